@@ -2,6 +2,8 @@
 // by Desmond Germans, 2019
 
 use crate::Image;
+use crate::Image16;
+use std::collections::HashMap;
 
 const TYPE_C1: u16 = 0x0001;
 const TYPE_C2: u16 = 0x0002;
@@ -14,6 +16,45 @@ const TYPE_B16: u16 = 0x0310;
 const TYPE_RGB8: u16 = 0x0018;
 const TYPE_ARGB8: u16 = 0x0020;
 const TYPE_B32: u16 = 0x0320;
+const TYPE_ARGB16: u16 = 0x0040;  // s2.13 fixed point, linear
+const TYPE_JPEG: u16 = 0x0400;
+const TYPE_PNG: u16 = 0x0500;
+const TYPE_C1_HUFFMAN: u16 = 0x0301;  // OS/2 2.x
+const TYPE_RGB8_RLE: u16 = 0x0418;  // OS/2 2.x
+
+// T.4 white run codes: code length, code, run length (terminating, makeup and extended makeup codes)
+const WHITE_CODES: [(u8,u16,u16); 104] = [
+    (4,0x007,2),(4,0x008,3),(4,0x00B,4),(4,0x00C,5),(4,0x00E,6),(4,0x00F,7),(5,0x007,10),(5,0x008,11),
+    (5,0x012,128),(5,0x013,8),(5,0x014,9),(5,0x01B,64),(6,0x003,13),(6,0x007,1),(6,0x008,12),(6,0x017,192),
+    (6,0x018,1664),(6,0x02A,16),(6,0x02B,17),(6,0x034,14),(6,0x035,15),(7,0x003,22),(7,0x004,23),(7,0x008,20),
+    (7,0x00C,19),(7,0x013,26),(7,0x017,21),(7,0x018,28),(7,0x024,27),(7,0x027,18),(7,0x028,24),(7,0x02B,25),
+    (7,0x037,256),(8,0x002,29),(8,0x003,30),(8,0x004,45),(8,0x005,46),(8,0x00A,47),(8,0x00B,48),(8,0x012,33),
+    (8,0x013,34),(8,0x014,35),(8,0x015,36),(8,0x016,37),(8,0x017,38),(8,0x01A,31),(8,0x01B,32),(8,0x024,53),
+    (8,0x025,54),(8,0x028,39),(8,0x029,40),(8,0x02A,41),(8,0x02B,42),(8,0x02C,43),(8,0x02D,44),(8,0x032,61),
+    (8,0x033,62),(8,0x034,63),(8,0x035,0),(8,0x036,320),(8,0x037,384),(8,0x04A,59),(8,0x04B,60),(8,0x052,49),
+    (8,0x053,50),(8,0x054,51),(8,0x055,52),(8,0x058,55),(8,0x059,56),(8,0x05A,57),(8,0x05B,58),(8,0x064,448),
+    (8,0x065,512),(8,0x067,640),(8,0x068,576),(9,0x098,1472),(9,0x099,1536),(9,0x09A,1600),(9,0x09B,1728),(9,0x0CC,704),
+    (9,0x0CD,768),(9,0x0D2,832),(9,0x0D3,896),(9,0x0D4,960),(9,0x0D5,1024),(9,0x0D6,1088),(9,0x0D7,1152),(9,0x0D8,1216),
+    (9,0x0D9,1280),(9,0x0DA,1344),(9,0x0DB,1408),(11,0x008,1792),(11,0x00C,1856),(11,0x00D,1920),(12,0x012,1984),(12,0x013,2048),
+    (12,0x014,2112),(12,0x015,2176),(12,0x016,2240),(12,0x017,2304),(12,0x01C,2368),(12,0x01D,2432),(12,0x01E,2496),(12,0x01F,2560),
+];
+
+// T.4 black run codes
+const BLACK_CODES: [(u8,u16,u16); 104] = [
+    (2,0x002,3),(2,0x003,2),(3,0x002,1),(3,0x003,4),(4,0x002,6),(4,0x003,5),(5,0x003,7),(6,0x004,9),
+    (6,0x005,8),(7,0x004,10),(7,0x005,11),(7,0x007,12),(8,0x004,13),(8,0x007,14),(9,0x018,15),(10,0x008,18),
+    (10,0x00F,64),(10,0x017,16),(10,0x018,17),(10,0x037,0),(11,0x008,1792),(11,0x00C,1856),(11,0x00D,1920),(11,0x017,24),
+    (11,0x018,25),(11,0x028,23),(11,0x037,22),(11,0x067,19),(11,0x068,20),(11,0x06C,21),(12,0x012,1984),(12,0x013,2048),
+    (12,0x014,2112),(12,0x015,2176),(12,0x016,2240),(12,0x017,2304),(12,0x01C,2368),(12,0x01D,2432),(12,0x01E,2496),(12,0x01F,2560),
+    (12,0x024,52),(12,0x027,55),(12,0x028,56),(12,0x02B,59),(12,0x02C,60),(12,0x033,320),(12,0x034,384),(12,0x035,448),
+    (12,0x037,53),(12,0x038,54),(12,0x052,50),(12,0x053,51),(12,0x054,44),(12,0x055,45),(12,0x056,46),(12,0x057,47),
+    (12,0x058,57),(12,0x059,58),(12,0x05A,61),(12,0x05B,256),(12,0x064,48),(12,0x065,49),(12,0x066,62),(12,0x067,63),
+    (12,0x068,30),(12,0x069,31),(12,0x06A,32),(12,0x06B,33),(12,0x06C,40),(12,0x06D,41),(12,0x0C8,128),(12,0x0C9,192),
+    (12,0x0CA,26),(12,0x0CB,27),(12,0x0CC,28),(12,0x0CD,29),(12,0x0D2,34),(12,0x0D3,35),(12,0x0D4,36),(12,0x0D5,37),
+    (12,0x0D6,38),(12,0x0D7,39),(12,0x0DA,42),(12,0x0DB,43),(13,0x04A,640),(13,0x04B,704),(13,0x04C,768),(13,0x04D,832),
+    (13,0x052,1280),(13,0x053,1344),(13,0x054,1408),(13,0x055,1472),(13,0x05A,1536),(13,0x05B,1600),(13,0x064,1664),(13,0x065,1728),
+    (13,0x06C,512),(13,0x06D,576),(13,0x072,896),(13,0x073,960),(13,0x074,1024),(13,0x075,1088),(13,0x076,1152),(13,0x077,1216),
+];
 
 fn from_le16(src: &[u8]) -> u16 {
     ((src[1] as u16) << 8) | (src[0] as u16)
@@ -78,6 +119,154 @@ impl Component {
             _ => (d >> (self.size - 8)) as u8,
         }
     }
+
+    // same as get, but scaled to 16 bits, so up to 16 bits per component survive
+    pub fn get16(&self,c: u32,def: u16) -> u16 {
+        if self.size == 0 {
+            return def;
+        }
+        let d = ((c & self.mask) >> self.shift) as u64;
+        if self.size >= 16 {
+            return (d >> (self.size - 16)) as u16;
+        }
+        // repeat the bits to fill 16 bits
+        let mut v = 0u64;
+        let mut bits = 0;
+        while bits < 16 {
+            v = (v << self.size) | d;
+            bits += self.size;
+        }
+        (v >> (bits - 16)) as u16
+    }
+}
+
+// lookup table for one component of 16-bit pixels, with the results already in their ARGB position
+struct Table16 {
+    mask: u32,
+    shift: u32,
+    values: Vec<u32>,
+}
+
+impl Table16 {
+    pub fn new(component: &Component,def: u8,position: u32) -> Table16 {
+        // an empty mask gives the default
+        if component.mask == 0 {
+            return Table16 {
+                mask: 0,
+                shift: 0,
+                values: vec![(def as u32) << position],
+            };
+        }
+        let max = (component.mask & 0xFFFF) >> component.shift;
+        Table16 {
+            mask: component.mask,
+            shift: component.shift,
+            values: (0..=max).map(|d| (component.get(d << component.shift,def) as u32) << position).collect(),
+        }
+    }
+}
+
+// row converters; src holds exactly the pixels of the row, without the padding
+
+fn row_a1rgb5(dst: &mut [u32],src: &[u8],opaque: bool) {
+    let a = if opaque { 0xFF000000 } else { 0 };
+    for (d,s) in dst.iter_mut().zip(src.chunks_exact(2)) {
+        let c = ((s[1] as u32) << 8) | (s[0] as u32);
+        // 5 bits to 8 for all three at once
+        let rgb = ((c & 0x7C00) << 9) | ((c & 0x03E0) << 6) | ((c & 0x001F) << 3);
+        *d = a | ((c >> 15) * 0xFF000000) | rgb | ((rgb >> 5) & 0x00070707);
+    }
+}
+
+fn row_b16(dst: &mut [u32],src: &[u8],tables: &[Table16; 4]) {
+    let [red,green,blue,alpha] = tables;
+    for (d,s) in dst.iter_mut().zip(src.chunks_exact(2)) {
+        let c = ((s[1] as u32) << 8) | (s[0] as u32);
+        *d = red.values[((c & red.mask) >> red.shift) as usize] |
+            green.values[((c & green.mask) >> green.shift) as usize] |
+            blue.values[((c & blue.mask) >> blue.shift) as usize] |
+            alpha.values[((c & alpha.mask) >> alpha.shift) as usize];
+    }
+}
+
+fn row_rgb8(dst: &mut [u32],src: &[u8]) {
+    // 4 pixels from 3 words at a time
+    let mut dst4 = dst.chunks_exact_mut(4);
+    let mut src12 = src.chunks_exact(12);
+    for (d,s) in (&mut dst4).zip(&mut src12) {
+        let w0 = from_le32(&s[0..4]);
+        let w1 = from_le32(&s[4..8]);
+        let w2 = from_le32(&s[8..12]);
+        d[0] = 0xFF000000 | (w0 & 0x00FFFFFF);
+        d[1] = 0xFF000000 | (w0 >> 24) | ((w1 & 0x0000FFFF) << 8);
+        d[2] = 0xFF000000 | (w1 >> 16) | ((w2 & 0x000000FF) << 16);
+        d[3] = 0xFF000000 | (w2 >> 8);
+    }
+    for (d,s) in dst4.into_remainder().iter_mut().zip(src12.remainder().chunks_exact(3)) {
+        *d = 0xFF000000 | ((s[2] as u32) << 16) | ((s[1] as u32) << 8) | (s[0] as u32);
+    }
+}
+
+fn row_argb8(dst: &mut [u32],src: &[u8],opaque: bool) {
+    let a = if opaque { 0xFF000000 } else { 0 };
+    for (d,s) in dst.iter_mut().zip(src.chunks_exact(4)) {
+        *d = a | from_le32(s);
+    }
+}
+
+fn row_c8(dst: &mut [u32],src: &[u8],palette: &[u32; 256]) {
+    for (d,s) in dst.iter_mut().zip(src.iter()) {
+        *d = palette[*s as usize];
+    }
+}
+
+// 16-bit channel from linear s2.13 fixed point, with the sRGB curve for color
+fn from_s2_13(v: u16,color: bool) -> u64 {
+    let f = ((v as i16) as f32 / 8192.0).clamp(0.0,1.0);
+    let f = if !color { f } else if f <= 0.0031308 { f * 12.92 } else { 1.055 * f.powf(1.0 / 2.4) - 0.055 };
+    (f * 65535.0 + 0.5) as u64
+}
+
+// 16 bits per channel counterpart of decode_pixels, for the types that can have more than 8 bits per channel
+#[allow(clippy::too_many_arguments)]
+fn decode_pixels16(dst: &mut [u64],src: &[u8],width: usize,height: usize,bottom_up: bool,itype: u16,redmask: u32,greenmask: u32,bluemask: u32,alphamask: u32) {
+    let red = Component::new(redmask);
+    let green = Component::new(greenmask);
+    let blue = Component::new(bluemask);
+    let alpha = Component::new(alphamask);
+    let mut sp = 0usize;
+    let mut line = if bottom_up { width * (height - 1) } else { 0 };
+    let dline = if bottom_up { -(width as isize) } else { width as isize };
+    let bpp = match itype {
+        TYPE_B16 => 2,
+        TYPE_B32 => 4,
+        TYPE_ARGB16 => 8,
+        _ => { return; },
+    };
+    for _l in 0..height {
+        for d in dst[line..line + width].iter_mut() {
+            *d = if itype == TYPE_ARGB16 {
+                // B,G,R,A
+                let b = from_s2_13(from_le16(&src[sp..sp + 2]),true);
+                let g = from_s2_13(from_le16(&src[sp + 2..sp + 4]),true);
+                let r = from_s2_13(from_le16(&src[sp + 4..sp + 6]),true);
+                let a = from_s2_13(from_le16(&src[sp + 6..sp + 8]),false);
+                (a << 48) | (r << 32) | (g << 16) | b
+            }
+            else {
+                let d = if bpp == 2 { from_le16(&src[sp..sp + 2]) as u32 } else { from_le32(&src[sp..sp + 4]) };
+                let r = red.get16(d,0) as u64;
+                let g = green.get16(d,0) as u64;
+                let b = blue.get16(d,0) as u64;
+                let a = if alphamask == 0 { 0xFFFF } else { alpha.get16(d,0xFFFF) as u64 };
+                (a << 48) | (r << 32) | (g << 16) | b
+            };
+            sp += bpp;
+        }
+        // lines are padded to 32 bits
+        sp += (4 - ((width * bpp) & 3)) & 3;
+        line = ((line as isize) + dline) as usize;
+    }
 }
 
 pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,bottom_up: bool,itype: u16,palette: &[u32; 256],redmask: u32,greenmask: u32,bluemask: u32,alphamask: u32) {
@@ -161,7 +350,7 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                 if (width & 1) != 0 {
                     let d = src[sp];
                     sp += 1;
-                    dst[dp] = palette[(d & 15) as usize];
+                    dst[dp] = palette[(d >> 4) as usize];
                 }
                 let rest = ((width + 1) / 2) & 3;
                 if rest > 0 {
@@ -172,7 +361,8 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
         },
         TYPE_C4_RLE => {
             let mut x = 0usize;
-            while sp < src.len() {
+            // stop where the data or the bitmap ends
+            while sp + 2 <= src.len() {
                 let code: u16 = from_le16(&src[sp..sp+2]);
                 sp += 2;
                 match code {
@@ -184,6 +374,9 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                         break;
                     },
                     0x0200 => {
+                        if sp + 2 > src.len() {
+                            break;
+                        }
                         x += src[sp] as usize;
                         y = ((y as isize) + (src[sp + 1] as isize) * dy) as usize;
                         sp += 2;
@@ -191,7 +384,7 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                     _ => {
                         if (code & 255) != 0 {
                             let count = code & 255;
-                            if x + (count as usize) > width {
+                            if (x + (count as usize) > width) || (y >= height) {
                                 break;
                             }
                             let c0 = palette[(code >> 12) as usize];
@@ -208,7 +401,8 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                         }
                         else {
                             let count = code >> 8;
-                            if x + (count as usize) > width {
+                            // 4 pixels per 16 bits
+                            if (sp + (count as usize).div_ceil(4) * 2 > src.len()) || (x + (count as usize) > width) || (y >= height) {
                                 break;
                             }
                             for _i in 0..count / 4 {
@@ -242,23 +436,17 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
             }
         },
         TYPE_C8 => {
+            let stride = (width + 3) & !3;
             for _l in 0..height {
-                let mut dp = line;
-                for _x in 0..width {
-                    dst[dp] = palette[src[sp] as usize];
-                    sp += 1;
-                    dp += 1;
-                }
-                let rest = width & 3;
-                if rest > 0 {
-                    sp += (4 - rest) as usize;
-                }
+                row_c8(&mut dst[line..line + width],&src[sp..sp + width],palette);
+                sp += stride;
                 line = (line as isize + dline) as usize;
             }
         },
         TYPE_C8_RLE => {
             let mut x = 0usize;
-            while sp < src.len() {
+            // stop where the data or the bitmap ends
+            while sp + 2 <= src.len() {
                 let code: u16 = from_le16(&src[sp..sp+2]);
                 sp += 2;
                 match code {
@@ -270,6 +458,9 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                         break;
                     },
                     0x0200 => {
+                        if sp + 2 > src.len() {
+                            break;
+                        }
                         x += src[sp] as usize;
                         y = ((y as isize) + (src[sp + 1] as isize) * dy) as usize;
                         sp += 2;
@@ -277,7 +468,7 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                     _ => {
                         if (code & 255) != 0 {
                             let count = code & 255;
-                            if x + count as usize > width {
+                            if (x + count as usize > width) || (y >= height) {
                                 break;
                             }
                             let c = palette[(code >> 8) as usize];
@@ -288,7 +479,8 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                         }
                         else {
                             let count = code >> 8;
-                            if x + count as usize > width {
+                            // 2 pixels per 16 bits
+                            if (sp + (count as usize).div_ceil(2) * 2 > src.len()) || (x + count as usize > width) || (y >= height) {
                                 break;
                             }
                             for _i in 0..count / 2 {
@@ -310,80 +502,34 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
 			}
         },
         TYPE_A1RGB5 => {
+            let stride = (width * 2 + 3) & !3;
             for _l in 0..height {
-                let mut dp = line;
-                for _x in 0..width {
-                    let d = from_le16(&src[sp..sp+2]);
-                    sp += 2;
-                    let mut r = (d >> 10) & 31;
-                    let mut g = (d >> 5) & 31;
-                    let mut b = d & 31;
-                    let a = if alphamask == 0 { 255 } else if (d & 0x8000) != 0 { 255 } else { 0 };
-                    r = (r << 3) | (r >> 2);
-                    g = (g << 3) | (g >> 2);
-                    b = (b << 3) | (b >> 2);
-                    //println!("{},{}: {:04X} - a{} r{} g{} b{}",x,line,d,a,r,g,b);
-                    dst[dp] = ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-                    dp += 1;
-                }
-                let rest = (width * 2) & 3;
-                if rest > 0 {
-                    sp += 4 - rest;
-                }
+                row_a1rgb5(&mut dst[line..line + width],&src[sp..sp + width * 2],alphamask == 0);
+                sp += stride;
                 line = (line as isize + dline) as usize;
             }
         },
         TYPE_B16 => {
+            let tables = [Table16::new(&red,0,16),Table16::new(&green,0,8),Table16::new(&blue,0,0),Table16::new(&alpha,255,24)];
+            let stride = (width * 2 + 3) & !3;
             for _l in 0..height {
-                let mut dp = line;
-                for _x in 0..width {
-                    let d = from_le16(&src[sp..sp + 2]) as u32;
-                    sp += 2;
-                    let r = red.get(d,0);
-                    let g = green.get(d,0);
-                    let b = blue.get(d,0);
-                    let a = if alphamask == 0 { 255 } else { alpha.get(d,255) };
-                    dst[dp] = ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-                    dp += 1;
-                }
-                let rest = (width * 2) & 3;
-                if rest > 0 {
-                    sp += (4 - rest) as usize;
-                }
+                row_b16(&mut dst[line..line + width],&src[sp..sp + width * 2],&tables);
+                sp += stride;
                 line = (line as isize + dline) as usize;
             }
         },
         TYPE_RGB8 => {
+            let stride = (width * 3 + 3) & !3;
             for _l in 0..height {
-                let mut dp = line;
-                for _x in 0..width {
-                    let b = src[sp];
-                    let g = src[sp + 1];
-                    let r = src[sp + 2];
-                    sp += 3;
-                    dst[dp] = 0xFF000000 | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-                    dp += 1;
-                }
-                let rest = (width * 3) & 3;
-                if rest > 0 {
-                    sp += (4 - rest) as usize;
-                }
+                row_rgb8(&mut dst[line..line + width],&src[sp..sp + width * 3]);
+                sp += stride;
                 line = (line as isize + dline) as usize;
             }
         },
         TYPE_ARGB8 => {
             for _l in 0..height {
-                let mut dp = line as usize;
-                for _x in 0..width {
-                    let d = from_le32(&src[sp..sp+4]);
-                    sp += 4;
-                    let r = (d >> 16) & 255;
-                    let g = (d >> 8) & 255;
-                    let b = d & 255;
-                    let a = if alphamask == 0 { 255 } else { d >> 24 };
-                    dst[dp] = ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-                    dp += 1;
-                }
+                row_argb8(&mut dst[line..line + width],&src[sp..sp + width * 4],alphamask == 0);
+                sp += width * 4;
                 line = (line as isize + dline) as usize;
             }
         },
@@ -403,122 +549,485 @@ pub fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,botto
                 line = (line as isize + dline) as usize;
             }
         },
+        TYPE_C1_HUFFMAN => {
+            let bits = src.len() * 8;
+            let mut bp = 0usize;
+            for _l in 0..height {
+                // EOL: 11 or more zeros and a one
+                let mut zeros = 0usize;
+                while (bp + zeros < bits) && (((src[(bp + zeros) >> 3] >> (7 - ((bp + zeros) & 7))) & 1) == 0) {
+                    zeros += 1;
+                }
+                if zeros >= 11 {
+                    bp += zeros + 1;
+                }
+                let mut dp = line;
+                let mut x = 0usize;
+                let mut black = false;
+                while x < width {
+                    let run = match huffman_run(src,&mut bp,black) {
+                        Some(run) => run.min(width - x),
+                        None => { return; },
+                    };
+                    let c = palette[black as usize];
+                    for _i in 0..run {
+                        dst[dp] = c;
+                        dp += 1;
+                    }
+                    x += run;
+                    black = !black;
+                }
+                line = ((line as isize) + dline) as usize;
+            }
+        },
+        TYPE_RGB8_RLE => {
+            let mut x = 0usize;
+            while sp < src.len() {
+                let count = src[sp] as usize;
+                sp += 1;
+                if count != 0 {
+                    if (sp + 3 > src.len()) || (x + count > width) || (y >= height) {
+                        break;
+                    }
+                    let c = 0xFF000000 | ((src[sp + 2] as u32) << 16) | ((src[sp + 1] as u32) << 8) | (src[sp] as u32);
+                    sp += 3;
+                    for _i in 0..count {
+                        dst[y * width + x] = c;
+                        x += 1;
+                    }
+                    continue;
+                }
+                if sp >= src.len() {
+                    break;
+                }
+                let code = src[sp] as usize;
+                sp += 1;
+                match code {
+                    0 => {
+                        x = 0;
+                        y = ((y as isize) + dy) as usize;
+                    },
+                    1 => {
+                        break;
+                    },
+                    2 => {
+                        if sp + 2 > src.len() {
+                            break;
+                        }
+                        x += src[sp] as usize;
+                        y = ((y as isize) + (src[sp + 1] as isize) * dy) as usize;
+                        sp += 2;
+                    },
+                    _ => {
+                        // absolute mode: B,G,R per pixel, padded to 16 bits
+                        if (sp + code * 3 > src.len()) || (x + code > width) || (y >= height) {
+                            break;
+                        }
+                        for _i in 0..code {
+                            dst[y * width + x] = 0xFF000000 | ((src[sp + 2] as u32) << 16) | ((src[sp + 1] as u32) << 8) | (src[sp] as u32);
+                            sp += 3;
+                            x += 1;
+                        }
+                        sp += code & 1;
+                    },
+                }
+            }
+        },
         _ => { },
     }
 }
 
-pub fn test(src: &[u8]) -> Option<(usize,usize)> {
-    let tag = from_le16(&src[0..2]);
-    if (tag == 0x4D42) ||   // BM (Windows BMP)
-        (tag == 0x4142) ||  // BA (OS/2 bitmap)
-        (tag == 0x4943) ||  // CI (OS/2 color icon)
-        (tag == 0x5043) ||  // CP (OS/2 color pointer) 
-        (tag == 0x4349) ||  // IC (OS/2 icon)
-        (tag == 0x5450) {    // PT (OS/2 pointer)
-        let filesize = from_le32(&src[2..6]);
-        let offset = from_le32(&src[10..14]);
-        let headersize = from_le32(&src[14..18]);
-        if (headersize > filesize) || (offset > filesize) || (headersize > offset) || (filesize != src.len() as u32) {
-            return None;
+// whether RLE4, RLE8 or RLE24 data stays inside the bitmap, without runs past the end of a line or below the last one
+fn rle_fits(src: &[u8],width: usize,height: usize,itype: u16) -> bool {
+    let mut sp = 0usize;
+    let mut x = 0usize;
+    let mut y = 0usize;
+    while sp < src.len() {
+        let count = src[sp] as usize;
+        sp += 1;
+        if count != 0 {
+            let size = if itype == TYPE_RGB8_RLE { 3 } else { 1 };
+            if (sp + size > src.len()) || (x + count > width) || (y >= height) {
+                return false;
+            }
+            sp += size;
+            x += count;
+            continue;
         }
-        if (headersize != 12) &&
-           (headersize != 40) &&
-           (headersize != 52) &&
-           (headersize != 56) &&
-           (headersize != 108) &&
-           (headersize != 124) {
-            return None;
+        if sp >= src.len() {
+            return false;
         }
-        if headersize == 12 {
-            let width = from_le16(&src[18..20]) as usize;
-            let mut height = from_le16(&src[20..22]) as usize;
-            if (height as i16) < 0 {
-                height = -(height as i16) as usize;
-            }
-            if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
-                return None;
-            }
-            let planes = from_le16(&src[22..24]);
-            let itype = from_le16(&src[24..26]);
-            if planes != 1 {
+        let code = src[sp] as usize;
+        sp += 1;
+        match code {
+            0 => {
+                x = 0;
+                y += 1;
+            },
+            1 => {
+                return true;
+            },
+            2 => {
+                if sp + 2 > src.len() {
+                    return false;
+                }
+                x += src[sp] as usize;
+                y += src[sp + 1] as usize;
+                sp += 2;
+                if (x > width) || (y >= height) {
+                    return false;
+                }
+            },
+            _ => {
+                // absolute mode, padded to 16 bits
+                let size = match itype {
+                    TYPE_C4_RLE => code.div_ceil(2),
+                    TYPE_C8_RLE => code,
+                    _ => code * 3,
+                };
+                if (sp + size > src.len()) || (x + code > width) || (y >= height) {
+                    return false;
+                }
+                sp += size + (size & 1);
+                x += code;
+            },
+        }
+    }
+    true
+}
+
+// one run length of T.4 coded data: any makeup codes and the terminating code, None on invalid or missing data
+fn huffman_run(src: &[u8],bp: &mut usize,black: bool) -> Option<usize> {
+    let codes: &[(u8,u16,u16)] = if black { &BLACK_CODES } else { &WHITE_CODES };
+    let mut run = 0usize;
+    loop {
+        let mut code = 0u16;
+        let mut length = 0u8;
+        let value = loop {
+            if ((*bp >> 3) >= src.len()) || (length == 13) {
                 return None;
             }
-            let mut line = match itype {
-                TYPE_C1 => (width + 7) / 8,
-                TYPE_C4 => (width + 1) / 2,
-                TYPE_C8 => width,
-                TYPE_RGB8 => width * 3,
-                _ => { return None; },
-            };
-            let rest = line & 3;
-            if rest > 0 {
-                line += 4 - rest;
+            code = (code << 1) | (((src[*bp >> 3] >> (7 - (*bp & 7))) & 1) as u16);
+            *bp += 1;
+            length += 1;
+            if let Some(entry) = codes.iter().find(|entry| (entry.0 == length) && (entry.1 == code)) {
+                break entry.2;
             }
-            if offset as usize + height * line > src.len() {
-                return None;
-            }
-            return Some((width,height));
+        };
+        run += value as usize;
+        if value < 64 {
+            return Some(run);
         }
-        else {
-            let width = from_le32(&src[18..22]) as usize;
-            let mut height = from_le32(&src[22..26]) as usize;
-            if (height as i32) < 0 {
-                height = -(height as i32) as usize;
-            }
-            if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
+    }
+}
+
+const TAG_BM: u16 = 0x4D42;  // BM (Windows BMP)
+const TAG_BA: u16 = 0x4142;  // BA (OS/2 bitmap array)
+const TAG_CI: u16 = 0x4943;  // CI (OS/2 color icon)
+const TAG_CP: u16 = 0x5043;  // CP (OS/2 color pointer)
+const TAG_IC: u16 = 0x4349;  // IC (OS/2 icon)
+const TAG_PT: u16 = 0x5450;  // PT (OS/2 pointer)
+
+// file and info header at fh, padded so that fields an OS/2 2.x header leaves out read as 0
+fn header(src: &[u8],fh: usize) -> Option<(u32,Vec<u8>)> {
+    if fh + 18 > src.len() {
+        return None;
+    }
+    let headersize = from_le32(&src[fh + 14..fh + 18]);
+    if (headersize != 12) &&
+        !(16..=64).contains(&headersize) &&
+        (headersize != 108) &&
+        (headersize != 124) {
+        return None;
+    }
+    if fh + 14 + headersize as usize > src.len() {
+        return None;
+    }
+    let mut h = src[fh..src.len().min(fh + 138)].to_vec();
+    h.resize(138,0);
+    if headersize < 40 {
+        h[14 + headersize as usize..54].fill(0);
+    }
+    // BI_ALPHABITFIELDS (Windows CE) is BI_BITFIELDS with the alpha mask always present
+    if (headersize != 12) && (from_le32(&h[30..34]) == 6) && ((from_le16(&h[28..30]) == 16) || (from_le16(&h[28..30]) == 32)) {
+        h[30] = 3;
+    }
+    Some((headersize,h))
+}
+
+// width, height and type (compression << 8 | bits per pixel) of the bitmap at fh
+fn bitmap_size(src: &[u8],fh: usize) -> Option<(usize,usize,u16)> {
+    let (headersize,h) = header(src,fh)?;
+    let offset = from_le32(&h[10..14]) as usize;
+    if (offset < fh + 14 + headersize as usize) || (offset > src.len()) {
+        return None;
+    }
+    let (width,height,itype) = if headersize == 12 {
+        if from_le16(&h[22..24]) != 1 {
+            return None;
+        }
+        (from_le16(&h[18..20]) as usize,(from_le16(&h[20..22]) as i16).unsigned_abs() as usize,from_le16(&h[24..26]))
+    }
+    else {
+        let compression = from_le32(&h[30..34]) as u16;
+        (from_le32(&h[18..22]) as usize,(from_le32(&h[22..26]) as i32).unsigned_abs() as usize,(compression << 8) | from_le16(&h[28..30]))
+    };
+    if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
+        return None;
+    }
+    let mut line = match itype {
+        TYPE_C1 => width.div_ceil(8),
+        TYPE_C2 if headersize != 12 => width.div_ceil(4),
+        TYPE_C4 => width.div_ceil(2),
+        TYPE_C8 => width,
+        TYPE_RGB8 => width * 3,
+        TYPE_A1RGB5 | TYPE_B16 if headersize != 12 => width * 2,
+        TYPE_ARGB8 | TYPE_B32 if headersize != 12 => width * 4,
+        TYPE_ARGB16 if headersize != 12 => width * 8,
+        TYPE_C4_RLE | TYPE_C8_RLE | TYPE_C1_HUFFMAN | TYPE_RGB8_RLE | TYPE_JPEG | TYPE_PNG if headersize != 12 => 0,
+        _ => { return None; },
+    };
+    let rest = line & 3;
+    if rest > 0 {
+        line += 4 - rest;
+    }
+    if offset + height * line > src.len() {
+        return None;
+    }
+    Some((width,height,itype))
+}
+
+// file header of the color bitmap of an OS/2 color icon or pointer, which follows the mask bitmap and its 2 color palette
+fn color_header(src: &[u8],fh: usize) -> Option<usize> {
+    let (headersize,_) = header(src,fh)?;
+    Some(fh + 14 + headersize as usize + if headersize == 12 { 6 } else { 8 })
+}
+
+// width, height and bits per pixel of the image stored at fh
+fn entry_size(src: &[u8],fh: usize) -> Option<(usize,usize,u16)> {
+    match from_le16(&src[fh..fh + 2]) {
+        TAG_BM => {
+            let (width,height,itype) = bitmap_size(src,fh)?;
+            Some((width,height,itype & 255))
+        },
+        TAG_IC | TAG_PT => {
+            // AND and XOR masks stacked in an uncompressed 1 bit bitmap of double height
+            let (width,height,itype) = bitmap_size(src,fh)?;
+            if (itype != TYPE_C1) || ((height & 1) != 0) {
                 return None;
             }
-            //let planes = from_le16(&src[26..28]);
-            let bpp = from_le16(&src[28..30]);
-            let compression = from_le32(&src[30..34]) as u16;
-            let itype = (compression << 8) | bpp;
-            let mut line = match itype {
-                TYPE_C1 => (width + 7) / 8,
-                TYPE_C2 => (width + 3) / 4,
-                TYPE_C4 => (width + 1) / 2,
-                TYPE_C4_RLE => 0,
-                TYPE_C8 => width,
-                TYPE_C8_RLE => 0,
-                TYPE_A1RGB5 | TYPE_B16 => width * 2,
-                TYPE_RGB8 => width * 3,
-                TYPE_ARGB8 | TYPE_B32 => width * 4,
-                _ => { return None; },
-            };
-            let rest = line & 3;
-            if rest > 0 {
-                line += 4 - rest;
-            }
-            if (line != 0) && (offset as usize + height * line > src.len()) {
+            Some((width,height / 2,1))
+        },
+        TAG_CI | TAG_CP => {
+            let (width,height,itype) = bitmap_size(src,fh)?;
+            let (cwidth,cheight,citype) = bitmap_size(src,color_header(src,fh)?)?;
+            if (itype != TYPE_C1) || (cwidth != width) || (cheight * 2 != height) {
                 return None;
             }
-            return Some((width,height));
+            Some((cwidth,cheight,citype & 255))
+        },
+        _ => None,
+    }
+}
+
+// file header of the image to use: the only one, or the largest (and then deepest) one in an OS/2 bitmap array
+fn select(src: &[u8]) -> Option<usize> {
+    if src.len() < 14 {
+        return None;
+    }
+    if from_le16(&src[0..2]) != TAG_BA {
+        return Some(0);
+    }
+    let mut best = None;
+    let mut best_key = (0usize,0u16);
+    let mut p = 0usize;
+    // 'BA', size of the entry, offset of the next entry (0 for the last one), display size, and the file header of the image
+    while (p + 30 <= src.len()) && (from_le16(&src[p..p + 2]) == TAG_BA) {
+        if let Some((width,height,bpp)) = entry_size(src,p + 14) {
+            if best.is_none() || ((width * height,bpp) > best_key) {
+                best = Some(p + 14);
+                best_key = (width * height,bpp);
+            }
         }
+        let next = from_le32(&src[p + 6..p + 10]) as usize;
+        if next <= p {
+            break;
+        }
+        p = next;
+    }
+    best
+}
+
+pub fn test(src: &[u8]) -> Option<(usize,usize)> {
+    let fh = select(src)?;
+    let (width,height,_) = entry_size(src,fh)?;
+    Some((width,height))
+}
+
+const LCS_WINDOWS_COLOR_SPACE: u32 = 0x57696E20;  // 'Win '
+const PROFILE_LINKED: u32 = 0x4C494E4B;  // 'LINK'
+const PROFILE_EMBEDDED: u32 = 0x4D424544;  // 'MBED'
+
+// profile data (offset from the info header) and size of a BITMAPV5HEADER with the given color space type
+fn profile_location(src: &[u8],cstype: u32) -> Option<(usize,usize)> {
+    if (src.len() < 138) || (from_le16(&src[0..2]) != 0x4D42) || (from_le32(&src[14..18]) != 124) || (from_le32(&src[70..74]) != cstype) {
+        return None;
+    }
+    let start = 14 + from_le32(&src[126..130]) as usize;
+    let size = from_le32(&src[130..134]) as usize;
+    if (size == 0) || (start + size > src.len()) {
+        return None;
+    }
+    Some((start,size))
+}
+
+// embedded ICC profile of a V5 BMP (PROFILE_EMBEDDED)
+pub fn icc_profile(src: &[u8]) -> Option<Vec<u8>> {
+    let (start,size) = profile_location(src,PROFILE_EMBEDDED)?;
+    Some(src[start..start + size].to_vec())
+}
+
+// file name of the ICC profile of a V5 BMP that links to one (PROFILE_LINKED)
+pub fn linked_profile(src: &[u8]) -> Option<String> {
+    let (start,size) = profile_location(src,PROFILE_LINKED)?;
+    // zero terminated, bytes are taken as Latin-1
+    Some(src[start..start + size].iter().take_while(|b| **b != 0).map(|b| *b as char).collect())
+}
+
+// resolution fields of a header with a pixel aspect ratio past 100:1; BITMAPINFOHEADER defines them as the resolution of the
+// target device, and no device has pixels that far from square
+fn garbage_resolution(h: &[u8]) -> bool {
+    let xppm = from_le32(&h[38..42]) as i32;
+    let yppm = from_le32(&h[42..46]) as i32;
+    (xppm > 0) && (yppm > 0) && ((xppm / 100 > yppm) || (yppm / 100 > xppm))
+}
+
+// physical resolution in dots per inch from the header, or else from the embedded JPEG or PNG
+pub fn dpi(src: &[u8]) -> Option<(f32,f32)> {
+    dpi_with_options(src,&DecodeOptions::default())
+}
+
+// same, but in strict mode a garbage resolution counts as no resolution
+pub fn dpi_with_options(src: &[u8],options: &DecodeOptions) -> Option<(f32,f32)> {
+    let fh = select(src)?;
+    if from_le16(&src[fh..fh + 2]) != TAG_BM {
+        return None;
+    }
+    let (_,h) = header(src,fh)?;  // zero resolution in headers too short for it
+    if options.strict && garbage_resolution(&h) {
+        return None;
+    }
+    let xppm = from_le32(&h[38..42]) as i32;
+    let yppm = from_le32(&h[42..46]) as i32;
+    if (xppm > 0) && (yppm > 0) {
+        return Some(((xppm as f32) * 0.0254,(yppm as f32) * 0.0254));
+    }
+    let payload = src.get(from_le32(&h[10..14]) as usize..)?;
+    match (from_le32(&h[30..34]),from_le16(&h[28..30])) {
+        (4,0) => crate::jpeg::dpi(payload),  // BI_JPEG
+        (5,0) => crate::png::dpi(payload),  // BI_PNG
+        _ => None,
     }
-    None
+}
+
+// what pixels that RLE data skips over become
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum SkippedPixels {
+    #[default]
+    Transparent,  // transparent black
+    Index0,  // palette color 0
+    Background(u32),  // this ARGB color
+}
+
+// decoder settings
+#[derive(Clone,Default)]
+pub struct DecodeOptions {
+    pub skipped_pixels: SkippedPixels,  // for RLE4/RLE8 delta escapes and early end of line or bitmap
+    pub embedded_bottom_up: bool,  // flip the JPEG or PNG of a bitmap with a positive height, as bottom-up; off keeps it as stored, like the bmpsuite reference
+    pub strict: bool,  // reject files that break the format documentation instead of repairing them, see decode_bitmap
 }
 
 pub fn decode(src: &[u8]) -> Result<Image,String> {
-    let tag = from_le16(&src[0..2]);
-    if (tag != 0x4D42) &&
-        (tag != 0x4142) &&
-        (tag != 0x4943) &&
-        (tag != 0x5043) && 
-        (tag != 0x4349) &&
-        (tag != 0x5450) {
-        return Err("Invalid BMP".to_string());
+    decode_with_options(src,&DecodeOptions::default())
+}
+
+pub fn decode_with_options(src: &[u8],options: &DecodeOptions) -> Result<Image,String> {
+    let fh = match select(src) {
+        Some(fh) => fh,
+        None => { return Err("Invalid BMP".to_string()); },
+    };
+    match from_le16(&src[fh..fh + 2]) {
+        TAG_BM => Ok(decode_bitmap(src,fh,options,false)?.into_image()),
+        TAG_IC | TAG_PT => decode_icon(src,fh,false,options),
+        TAG_CI | TAG_CP => decode_icon(src,fh,true,options),
+        _ => Err("Invalid BMP".to_string()),
     }
-    let filesize = from_le32(&src[2..6]);
-    let offset = from_le32(&src[10..14]);
-    let headersize = from_le32(&src[14..18]);
-    if (headersize > filesize) || (offset > filesize) || (headersize > offset) || (filesize != src.len() as u32) {
+}
+
+pub fn decode16(src: &[u8]) -> Result<Image16,String> {
+    decode16_with_options(src,&DecodeOptions::default())
+}
+
+// decode to 16 bits per channel, keeping all bits of 64-bit BMPs and of bitfields with more than 8 bits per channel
+pub fn decode16_with_options(src: &[u8],options: &DecodeOptions) -> Result<Image16,String> {
+    let fh = match select(src) {
+        Some(fh) => fh,
+        None => { return Err("Invalid BMP".to_string()); },
+    };
+    match from_le16(&src[fh..fh + 2]) {
+        TAG_BM => Ok(decode_bitmap(src,fh,options,true)?.into_image16()),
+        TAG_IC | TAG_PT => Ok(Image16::from_image(&decode_icon(src,fh,false,options)?)),
+        TAG_CI | TAG_CP => Ok(Image16::from_image(&decode_icon(src,fh,true,options)?)),
+        _ => Err("Invalid BMP".to_string()),
+    }
+}
+
+// decoded pixels, 64-bit BMPs and (when asked for) bitfield BMPs produce an Image16
+enum Decoded {
+    Image(Image),
+    Image16(Image16),
+}
+
+impl Decoded {
+    fn into_image(self) -> Image {
+        match self {
+            Decoded::Image(image) => image,
+            Decoded::Image16(image) => image.to_image(),
+        }
+    }
+
+    fn into_image16(self) -> Image16 {
+        match self {
+            Decoded::Image(image) => Image16::from_image(&image),
+            Decoded::Image16(image) => image,
+        }
+    }
+}
+
+// bitmap with its file header at fh; offsets in the file header count from the start of the file
+//
+// strict mode rejects, citing the BITMAPFILEHEADER, BITMAPINFOHEADER and Bitmap Compression documentation at each check:
+// - a file size field that isn't the size of the file
+// - a planes count other than 1
+// - a garbage resolution (see garbage_resolution)
+// - missing or overlapping bitfield masks
+// - top-down RLE, JPEG or PNG bitmaps
+// - RLE runs past the end of a line or below the last line
+// - more colors than the bits per pixel allow, or more than fit before the pixels
+// - pixels past the palette
+fn decode_bitmap(src: &[u8],fh: usize,options: &DecodeOptions,wide: bool) -> Result<Decoded,String> {
+    let (headersize,h) = match header(src,fh) {
+        Some(header) => header,
+        None => { return Err("Invalid BMP".to_string()); },
+    };
+    // the file size field is unreliable (OS/2 writers put other things there), so check against the actual size
+    let filesize = src.len() as u32;
+    let offset = from_le32(&h[10..14]);
+    if ((offset as usize) < fh + 14 + headersize as usize) || (offset > filesize) {
         return Err("Invalid BMP".to_string());
     }
-    if (headersize != 12) &&
-        (headersize != 40) &&
-        (headersize != 52) &&
-        (headersize != 56) &&
-        (headersize != 108) &&
-        (headersize != 124) {
+    // BITMAPFILEHEADER: bfSize is the size of the file in bytes
+    if options.strict && (fh == 0) && (from_le32(&h[2..6]) != filesize) {
         return Err("Invalid BMP".to_string());
     }
     #[allow(unused_assignments)]
@@ -528,20 +1037,22 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
     let mut bottom_up = true;
     #[allow(unused_assignments)]
     let mut itype = 0u16;
-    let mut palette = [0u32; 256];
+    // pixels past the palette come out black
+    let mut palette = [0xFF000000u32; 256];
+    let mut colors = 0usize;
     let mut redmask = 0u32;
     let mut greenmask = 0u32;
     let mut bluemask = 0u32;
     let mut alphamask = 0u32;
     if headersize == 12 {
-        width = from_le16(&src[18..20]) as usize;
-        let pheight = from_le16(&src[20..22]) as i16;
+        width = from_le16(&h[18..20]) as usize;
+        let pheight = from_le16(&h[20..22]) as i16;
         height = if pheight < 0 { bottom_up = false; -pheight as usize } else { pheight as usize };
         if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
             return Err("Invalid BMP".to_string());
         }
-        let planes = from_le16(&src[22..24]);
-        itype = from_le16(&src[24..26]);
+        let planes = from_le16(&h[22..24]);
+        itype = from_le16(&h[24..26]);
         if planes != 1 {
             return Err("Invalid BMP".to_string());
         }
@@ -559,17 +1070,29 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
         if offset as usize + (height * line) as usize > src.len() {
             return Err("Invalid BMP".to_string());
         }
+        if itype != TYPE_RGB8 {
+            // 3-byte entries up to the pixels
+            colors = (1usize << itype).min((offset as usize - fh - 14 - headersize as usize) / 3);
+            for (i,entry) in palette.iter_mut().take(colors).enumerate() {
+                let sp = fh + 14 + headersize as usize + i * 3;
+                *entry = 0xFF000000 | ((src[sp + 2] as u32) << 16) | ((src[sp + 1] as u32) << 8) | (src[sp] as u32);
+            }
+        }
     }
     else {
-        width = from_le32(&src[18..22]) as usize;
-        let pheight = from_le32(&src[22..26]) as i32;
+        width = from_le32(&h[18..22]) as usize;
+        let pheight = from_le32(&h[22..26]) as i32;
         height = if pheight < 0 { bottom_up = false; -pheight as usize } else { pheight as usize };
         if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
             return Err("Invalid BMP".to_string());
         }
-        //let planes = from_le16(&src[26..28]);
-        let bpp = from_le16(&src[28..30]);
-        let compression = from_le32(&src[30..34]) as u16;
+        let planes = from_le16(&h[26..28]);
+        // BITMAPINFOHEADER: biPlanes must be 1
+        if options.strict && (planes != 1) {
+            return Err("Invalid BMP".to_string());
+        }
+        let bpp = from_le16(&h[28..30]);
+        let compression = from_le32(&h[30..34]) as u16;
         itype = (compression << 8) | bpp;
         let mut line = match itype {
             TYPE_C1 => (width + 7) / 8,
@@ -581,6 +1104,8 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
             TYPE_A1RGB5 | TYPE_B16 => width * 2,
             TYPE_RGB8 => width * 3,
             TYPE_ARGB8 | TYPE_B32 => width * 4,
+            TYPE_ARGB16 => width * 8,
+            TYPE_C1_HUFFMAN | TYPE_RGB8_RLE | TYPE_JPEG | TYPE_PNG => 0,
             _ => { return Err("Invalid BMP".to_string()); },
         };
         let rest = line & 3;
@@ -590,47 +1115,154 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
         if (line != 0) && (offset as usize + (height * line) as usize > src.len()) {
             return Err("Invalid BMP".to_string());
         }
-        let imagesize = from_le32(&src[34..38]);
+        let imagesize = from_le32(&h[34..38]);
         if (compression == 0) && (imagesize > filesize - offset) {
             return Err("Invalid BMP".to_string());
         }
-        // 38..46: resolution
-        let mut colors = from_le32(&src[46..50]);
+        // 38..46: resolution of the target device in pixels per meter, see dpi() and garbage_resolution()
+        if options.strict && garbage_resolution(&h) {
+            return Err("Invalid BMP".to_string());
+        }
+        if (itype == TYPE_JPEG) || (itype == TYPE_PNG) {
+            let end = if imagesize == 0 { src.len() } else { offset as usize + imagesize as usize };
+            if end > src.len() {
+                return Err("Invalid BMP".to_string());
+            }
+            let payload = &src[offset as usize..end];
+            // BITMAPINFOHEADER: top-down DIBs (negative biHeight) can't be compressed, only BI_RGB and BI_BITFIELDS
+            if options.strict && !bottom_up {
+                return Err("Invalid BMP".to_string());
+            }
+            let image = if itype == TYPE_JPEG { crate::jpeg::decode(payload)? } else { crate::png::decode(payload)? };
+            if (image.width != width) || (image.height != height) {
+                return Err("Invalid BMP".to_string());
+            }
+            if bottom_up && options.embedded_bottom_up {
+                return Ok(Decoded::Image(image.oriented(4)));
+            }
+            return Ok(Decoded::Image(image));
+        }
+        colors = from_le32(&h[46..50]) as usize;
         // 50..54: important colors
         match itype {
-            TYPE_C1 | TYPE_C2 | TYPE_C4 | TYPE_C4_RLE | TYPE_C8 | TYPE_C8_RLE => {
+            TYPE_C1 | TYPE_C2 | TYPE_C4 | TYPE_C4_RLE | TYPE_C8 | TYPE_C8_RLE | TYPE_C1_HUFFMAN => {
+                let max = 1usize << bpp;
                 if colors == 0 {
-                    colors = 1 << bpp;
-                } else if colors > 256 {
-                    return Err("Invalid BMP".to_string());
+                    colors = max;
                 }
-                for i in 0..colors {
-                    let sp = (14 + headersize + i * 4) as usize;
-                    let b = src[sp];
-                    let g = src[sp + 1];
-                    let r = src[sp + 2];
-                    palette[i as usize] = 0xFF000000 | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+                else if colors > max {
+                    // BITMAPINFOHEADER: biClrUsed colors, of at most 2^biBitCount
+                    if options.strict {
+                        return Err("Invalid BMP".to_string());
+                    }
+                    colors = max;
+                }
+                // the palette ends where the pixels start; BITMAPFILEHEADER: bfOffBits is the offset of the pixels, which follow
+                // the color table
+                let room = (offset as usize - fh - 14 - headersize as usize) / 4;
+                if colors > room {
+                    if options.strict {
+                        return Err("Invalid BMP".to_string());
+                    }
+                    colors = room;
+                }
+                for (i,entry) in palette.iter_mut().take(colors).enumerate() {
+                    let sp = fh + 14 + headersize as usize + i * 4;
+                    *entry = 0xFF000000 | ((src[sp + 2] as u32) << 16) | ((src[sp + 1] as u32) << 8) | (src[sp] as u32);
                 }
             },
             TYPE_B16 | TYPE_B32 => {
-                redmask = from_le32(&src[54..58]);
-                greenmask = from_le32(&src[58..62]);
-                bluemask = from_le32(&src[62..66]);
-                if (headersize >= 56) || ((offset - headersize - 14) >= 16) {
-                    alphamask = from_le32(&src[66..70]);
+                redmask = from_le32(&h[54..58]);
+                greenmask = from_le32(&h[58..62]);
+                bluemask = from_le32(&h[62..66]);
+                if (headersize >= 56) || ((offset as usize - fh - 14 - headersize as usize) >= 16) {
+                    alphamask = from_le32(&h[66..70]);
+                }
+                // every color needs bits of its own; BITMAPINFOHEADER: with BI_BITFIELDS the masks give the red, green and blue
+                // components, and should not overlap the bits of another mask
+                if options.strict && ((redmask == 0) || (greenmask == 0) || (bluemask == 0) || ((redmask & greenmask) | (redmask & bluemask) | (greenmask & bluemask) | ((redmask | greenmask | bluemask) & alphamask) != 0)) {
+                    return Err("Invalid BMP".to_string());
                 }
             },
             TYPE_A1RGB5 => {
-                alphamask = if headersize < 56 { 0 } else { 0x8000 };
+                alphamask = if (headersize < 56) || (headersize == 64) { 0 } else { 0x8000 };
             },
             TYPE_ARGB8 => {
-                alphamask = if headersize < 56 { 0 } else { 0xFF000000 };
+                alphamask = if (headersize < 56) || (headersize == 64) { 0 } else { 0xFF000000 };
             }
             _ => { },
         }
     }
+    if (itype == TYPE_ARGB16) || (wide && ((itype == TYPE_B16) || (itype == TYPE_B32))) {
+        let mut image = Image16::new(width,height);
+        decode_pixels16(&mut image.data,&src[offset as usize..],width,height,bottom_up,itype,redmask,greenmask,bluemask,alphamask);
+        return Ok(Decoded::Image16(image));
+    }
     let mut image = Image::new(width,height);
+    // BITMAPINFOHEADER: top-down DIBs can't be compressed; Bitmap Compression: RLE data ends each line with an end of line
+    // escape and the bitmap with an end of bitmap escape, so runs stay within their line and the bitmap
+    if options.strict && ((itype == TYPE_C4_RLE) || (itype == TYPE_C8_RLE) || (itype == TYPE_RGB8_RLE)) && (!bottom_up || !rle_fits(&src[offset as usize..],width,height,itype)) {
+        return Err("Invalid BMP".to_string());
+    }
+    if (itype == TYPE_C4_RLE) || (itype == TYPE_C8_RLE) || (itype == TYPE_RGB8_RLE) {
+        let fill = match options.skipped_pixels {
+            SkippedPixels::Transparent => 0,
+            SkippedPixels::Index0 => palette[0],
+            SkippedPixels::Background(color) => color,
+        };
+        image.data.fill(fill);
+    }
+    if options.strict && matches!(itype,TYPE_C1 | TYPE_C2 | TYPE_C4 | TYPE_C4_RLE | TYPE_C8 | TYPE_C8_RLE | TYPE_C1_HUFFMAN) {
+        // BITMAPINFOHEADER: pixels are indices into the color table of biClrUsed entries
+        // decode the indices first to find the ones past the palette; u32::MAX marks skipped pixels
+        let mut identity = [0u32; 256];
+        for (i,entry) in identity.iter_mut().enumerate() {
+            *entry = i as u32;
+        }
+        let mut indices = vec![u32::MAX; width * height];
+        decode_pixels(&mut indices,&src[offset as usize..],width,height,bottom_up,itype,&identity,0,0,0,0);
+        for (d,i) in image.data.iter_mut().zip(indices.iter()) {
+            if *i != u32::MAX {
+                if *i as usize >= colors {
+                    return Err("Invalid BMP".to_string());
+                }
+                *d = palette[*i as usize];
+            }
+        }
+        return Ok(Decoded::Image(image));
+    }
     decode_pixels(&mut image.data,&src[offset as usize..],width,height,bottom_up,itype,&palette,redmask,greenmask,bluemask,alphamask);
+    Ok(Decoded::Image(image))
+}
+
+// OS/2 icon or pointer: a 1 bit mask bitmap of double height, with the AND mask in the first (bottom) half and the XOR mask
+// in the second; for color icons and pointers a color bitmap follows that replaces the XOR mask
+fn decode_icon(src: &[u8],fh: usize,color: bool,options: &DecodeOptions) -> Result<Image,String> {
+    let (width,height,_) = match entry_size(src,fh) {
+        Some(size) => size,
+        None => { return Err("Invalid BMP".to_string()); },
+    };
+    let mut image = if color {
+        decode_bitmap(src,color_header(src,fh).unwrap(),options,false)?.into_image()
+    }
+    else {
+        // bottom-up, so the XOR mask ends up in the top half
+        let mut image = decode_bitmap(src,fh,options,false)?.into_image();
+        image.data.truncate(width * height);
+        image.height = height;
+        image
+    };
+    let offset = from_le32(&src[fh + 10..fh + 14]) as usize;
+    let stride = width.div_ceil(32) * 4;
+    for y in 0..height {
+        let row = &src[offset + (height - 1 - y) * stride..];
+        for x in 0..width {
+            // AND set: screen shows through (or is inverted, which an image can't express)
+            if ((row[x >> 3] >> (7 - (x & 7))) & 1) != 0 {
+                image.data[y * width + x] = 0x00000000;
+            }
+        }
+    }
     Ok(image)
 }
 
@@ -664,57 +1296,583 @@ impl WriteTypes for Vec<u8> {
     }
 }
 
+// pixel format for the encoder
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Format {
+    Palette1,
+    Palette4,
+    Palette8,
+    Rgb555,  // 16-bit, top bit is alpha with V4 and V5 headers
+    Rgb565,  // 16-bit
+    Rgb24,
+    #[default]
+    Argb32,  // alpha is dropped with an INFO header
+}
+
+// info header version for the encoder
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Header {
+    Core,  // BITMAPCOREHEADER, 12 bytes, paletted and 24-bit only, always bottom-up
+    Info,  // BITMAPINFOHEADER, 40 bytes
+    #[default]
+    V4,  // BITMAPV4HEADER, 108 bytes
+    V5,  // BITMAPV5HEADER, 124 bytes
+}
+
+// encoder settings
+#[derive(Clone,Default)]
+pub struct EncodeOptions {
+    pub format: Format,
+    pub rle: bool,  // RLE4/RLE8 compression for Palette4 and Palette8, always bottom-up
+    pub skip_transparent: bool,  // leave out fully transparent pixels when compressing with RLE, they decode as skipped pixels
+    pub bottom_up: bool,  // write the last line first, like most BMPs; RLE and core headers are always bottom-up
+    pub header: Header,
+    pub palette: Option<Vec<u32>>,  // palette for the paletted formats, colors are mapped to the nearest entry; None collects the colors of the image
+    pub icc_profile: Option<Vec<u8>>,  // ICC profile to embed, needs a V4 or V5 header and is always written with V5
+    pub dpi: Option<(f32,f32)>,  // resolution to write, None writes 72 DPI (2835 pixels per meter)
+}
+
+// palette and palette indices of the image
+fn palettize(image: &Image,bits: u16,palette: &Option<Vec<u32>>) -> Result<(Vec<u32>,Vec<u8>),String> {
+    let max = 1usize << bits;
+    let mut lookup: HashMap<u32,u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(image.data.len());
+    match palette {
+        Some(palette) => {
+            if palette.is_empty() || (palette.len() > max) {
+                return Err("Invalid palette".to_string());
+            }
+            for p in image.data.iter() {
+                let c = p & 0x00FFFFFF;
+                let index = *lookup.entry(c).or_insert_with(|| {
+                    let distance = |e: &u32| -> i32 {
+                        let dr = ((e >> 16) & 255) as i32 - ((c >> 16) & 255) as i32;
+                        let dg = ((e >> 8) & 255) as i32 - ((c >> 8) & 255) as i32;
+                        let db = (e & 255) as i32 - (c & 255) as i32;
+                        dr * dr + dg * dg + db * db
+                    };
+                    (0..palette.len()).min_by_key(|i| distance(&palette[*i])).unwrap() as u8
+                });
+                indices.push(index);
+            }
+            Ok((palette.iter().map(|e| e & 0x00FFFFFF).collect(),indices))
+        },
+        None => {
+            let mut colors = Vec::new();
+            for p in image.data.iter() {
+                let c = p & 0x00FFFFFF;
+                let index = match lookup.get(&c) {
+                    Some(index) => *index,
+                    None => {
+                        if colors.len() == max {
+                            return Err("Too many colors for palette".to_string());
+                        }
+                        colors.push(c);
+                        lookup.insert(c,(colors.len() - 1) as u8);
+                        (colors.len() - 1) as u8
+                    },
+                };
+                indices.push(index);
+            }
+            if colors.is_empty() {
+                colors.push(0);
+            }
+            Ok((colors,indices))
+        },
+    }
+}
+
+// one step of an RLE line
+#[derive(Copy,Clone)]
+enum RleCode {
+    Run(usize),  // encoded run of pixels, alternating between two colors for RLE4
+    Absolute(usize),  // literal pixels, at least 3
+    Skip(usize),  // delta escape over skipped pixels
+}
+
+// codes of the shortest RLE encoding of one line, skipped pixels at the end of the line cost nothing
+fn rle_line(line: &[u8],skipped: &[bool],bits: u16) -> Vec<RleCode> {
+    let width = line.len();
+    // cost[x] is the size of the best encoding of line[x..]
+    let mut cost = vec![0usize; width + 1];
+    let mut code = vec![RleCode::Skip(0); width + 1];
+    let mut tail_skipped = true;
+    for x in (0..width).rev() {
+        tail_skipped = tail_skipped && skipped[x];
+        if tail_skipped {
+            continue;
+        }
+        if skipped[x] {
+            let mut count = 1;
+            while (x + count < width) && (count < 255) && skipped[x + count] {
+                count += 1;
+            }
+            cost[x] = 4 + cost[x + count];
+            code[x] = RleCode::Skip(count);
+            continue;
+        }
+        let mut best = usize::MAX;
+        // encoded runs
+        let mut count = 1;
+        while (x + count <= width) && (count <= 255) {
+            let i = x + count - 1;
+            let expected = if (bits == 4) && (count > 1) { line[x + ((count - 1) & 1)] } else { line[x] };
+            if skipped[i] || (line[i] != expected) {
+                break;
+            }
+            if 2 + cost[x + count] < best {
+                best = 2 + cost[x + count];
+                code[x] = RleCode::Run(count);
+            }
+            count += 1;
+        }
+        // absolute mode, padded to 16 bits
+        let mut count = 1;
+        while (x + count <= width) && (count <= 255) && !skipped[x + count - 1] {
+            if count >= 3 {
+                let size = if bits == 4 { 2 + count.div_ceil(4) * 2 } else { 2 + count.div_ceil(2) * 2 };
+                if size + cost[x + count] < best {
+                    best = size + cost[x + count];
+                    code[x] = RleCode::Absolute(count);
+                }
+            }
+            count += 1;
+        }
+        cost[x] = best;
+    }
+    let end = skipped.iter().rposition(|s| !*s).map_or(0,|x| x + 1);
+    let mut result = Vec::new();
+    let mut x = 0;
+    while x < end {
+        result.push(code[x]);
+        x += match code[x] {
+            RleCode::Run(count) | RleCode::Absolute(count) | RleCode::Skip(count) => count,
+        };
+    }
+    result
+}
+
+// RLE4 or RLE8 data of the indices, bottom-up; skipped pixels are left out with delta escapes and early end of line
+fn encode_rle(indices: &[u8],skipped: &[bool],width: usize,height: usize,bits: u16) -> Vec<u8> {
+    let mut dst = Vec::new();
+    let row_skipped = |y: usize| skipped[y * width..(y + 1) * width].iter().all(|s| *s);
+    let mut l = 0;
+    while l < height {
+        let y = height - 1 - l;
+        // nothing left to draw
+        if (0..=y).all(row_skipped) {
+            break;
+        }
+        if row_skipped(y) {
+            let mut count = 1;
+            while (count < 255) && (count < y) && row_skipped(y - count) {
+                count += 1;
+            }
+            if count > 1 {
+                dst.push16(0x0200);  // delta
+                dst.push(0);
+                dst.push(count as u8);
+            }
+            else {
+                dst.push16(0x0000);  // end of line
+            }
+            l += count;
+            continue;
+        }
+        let line = &indices[y * width..(y + 1) * width];
+        let mut x = 0;
+        for code in rle_line(line,&skipped[y * width..(y + 1) * width],bits) {
+            match code {
+                RleCode::Run(count) => {
+                    dst.push(count as u8);
+                    dst.push(if bits == 4 { (line[x] << 4) | line[if count > 1 { x + 1 } else { x }] } else { line[x] });
+                    x += count;
+                },
+                RleCode::Absolute(count) => {
+                    dst.push(0);
+                    dst.push(count as u8);
+                    let start = dst.len();
+                    if bits == 4 {
+                        for pair in line[x..x + count].chunks(2) {
+                            dst.push((pair[0] << 4) | if pair.len() > 1 { pair[1] } else { 0 });
+                        }
+                    }
+                    else {
+                        dst.extend_from_slice(&line[x..x + count]);
+                    }
+                    if ((dst.len() - start) & 1) != 0 {
+                        dst.push(0);
+                    }
+                    x += count;
+                },
+                RleCode::Skip(count) => {
+                    dst.push16(0x0200);  // delta
+                    dst.push(count as u8);
+                    dst.push(0);
+                    x += count;
+                },
+            }
+        }
+        l += 1;
+        if l < height {
+            dst.push16(0x0000);  // end of line
+        }
+    }
+    dst.push16(0x0100);  // end of bitmap
+    dst
+}
+
 pub fn encode(image: &Image) -> Result<Vec<u8>,String> {
-    let headersize = 108;
-    let stride = image.width * 4;
-    let palettesize = 0;
-    let bpp = 32;
-    let compression = 3;
-    let colors = 0;
-    let redmask: u32 = 0x00FF0000;
-    let greenmask: u32 = 0x0000FF00;
-    let bluemask: u32 = 0x000000FF;
-    let alphamask: u32 = 0xFF000000;
-    let imagesize = stride * image.height;
-    let offset = 14 + headersize + palettesize;
-    let filesize = offset + imagesize;
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options(image: &Image,options: &EncodeOptions) -> Result<Vec<u8>,String> {
+    let header = if options.icc_profile.is_some() {
+        match options.header {
+            Header::V4 | Header::V5 => Header::V5,
+            _ => { return Err("ICC profile needs a V5 header".to_string()); },
+        }
+    }
+    else {
+        options.header
+    };
+    let headersize = match header {
+        Header::Core => 12,
+        Header::Info => 40,
+        Header::V4 => 108,
+        Header::V5 => 124,
+    };
+    let bpp: u16 = match options.format {
+        Format::Palette1 => 1,
+        Format::Palette4 => 4,
+        Format::Palette8 => 8,
+        Format::Rgb555 | Format::Rgb565 => 16,
+        Format::Rgb24 => 24,
+        Format::Argb32 => 32,
+    };
+    if options.rle && (bpp != 4) && (bpp != 8) {
+        return Err("RLE needs a 4 or 8-bit palette".to_string());
+    }
+    if (header == Header::Core) && (options.rle || (bpp == 16) || (bpp == 32) || (image.width > 32767) || (image.height > 32767)) {
+        return Err("Format not supported by BITMAPCOREHEADER".to_string());
+    }
+    let (palette,indices) = if bpp <= 8 { palettize(image,bpp,&options.palette)? } else { (Vec::new(),Vec::new()) };
+    // BI_RGB, BI_RLE8, BI_RLE4 or BI_BITFIELDS, with the masks
+    let (compression,redmask,greenmask,bluemask,alphamask) = match options.format {
+        Format::Rgb555 if headersize >= 108 => (3,0x7C00,0x03E0,0x001F,0x8000),
+        Format::Rgb565 => (3,0xF800,0x07E0,0x001F,0),
+        Format::Argb32 if headersize >= 108 => (3,0x00FF0000,0x0000FF00,0x000000FF,0xFF000000),
+        Format::Palette4 if options.rle => (2,0,0,0,0),
+        Format::Palette8 if options.rle => (1,0,0,0,0),
+        _ => (0,0,0,0,0),
+    };
+    let bottom_up = options.bottom_up || options.rle || (options.header == Header::Core);  // the core header height is unsigned
+
+    // pixel data
+    let mut data: Vec<u8> = Vec::new();
+    if options.rle {
+        let skipped: Vec<bool> = image.data.iter().map(|p| options.skip_transparent && ((p >> 24) == 0)).collect();
+        data = encode_rle(&indices,&skipped,image.width,image.height,bpp);
+    }
+    else {
+        for l in 0..image.height {
+            let y = if bottom_up { image.height - 1 - l } else { l };
+            let start = data.len();
+            match options.format {
+                Format::Palette1 | Format::Palette4 | Format::Palette8 => {
+                    let per_byte = (8 / bpp) as usize;
+                    for chunk in indices[y * image.width..(y + 1) * image.width].chunks(per_byte) {
+                        let mut b = 0u8;
+                        for (i,index) in chunk.iter().enumerate() {
+                            b |= index << (8 - bpp as usize * (i + 1));
+                        }
+                        data.push(b);
+                    }
+                },
+                Format::Rgb555 | Format::Rgb565 => {
+                    for p in image.data[y * image.width..(y + 1) * image.width].iter() {
+                        let r = (p >> 16) & 255;
+                        let g = (p >> 8) & 255;
+                        let b = p & 255;
+                        let d = if options.format == Format::Rgb565 {
+                            ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3)
+                        }
+                        else {
+                            let a = if alphamask != 0 { (p >> 31) << 15 } else { 0 };
+                            a | ((r >> 3) << 10) | ((g >> 3) << 5) | (b >> 3)
+                        };
+                        data.push16(d as u16);
+                    }
+                },
+                Format::Rgb24 => {
+                    for p in image.data[y * image.width..(y + 1) * image.width].iter() {
+                        data.push((p & 255) as u8);
+                        data.push(((p >> 8) & 255) as u8);
+                        data.push(((p >> 16) & 255) as u8);
+                    }
+                },
+                Format::Argb32 => {
+                    for p in image.data[y * image.width..(y + 1) * image.width].iter() {
+                        data.push32(if alphamask != 0 { *p } else { p & 0x00FFFFFF });
+                    }
+                },
+            }
+            while ((data.len() - start) & 3) != 0 {
+                data.push(0);
+            }
+        }
+    }
+
+    // the INFO header needs the masks after it
+    let maskssize = if (headersize == 40) && (compression == 3) { 12 } else { 0 };
+    let entrysize = if header == Header::Core { 3 } else { 4 };
+    let colors = if header == Header::Core { if bpp <= 8 { 1 << bpp } else { 0 } } else { palette.len() };
+    let palettesize = colors * entrysize;
+    let imagesize = data.len();
+    let offset = 14 + headersize + maskssize + palettesize;
+    let profilesize = options.icc_profile.as_ref().map_or(0,|p| p.len());
+    let filesize = offset + imagesize + profilesize;
+    // resolution in pixels per meter, 72 DPI when unknown
+    let (xppm,yppm) = options.dpi.map_or((2835,2835),|(x,y)| ((x / 0.0254).round() as u32,(y / 0.0254).round() as u32));
     let mut dst: Vec<u8> = Vec::new();
     dst.push16b(0x424D);  // 0
     dst.push32(filesize as u32);  // 2
     dst.push32(0);  // 6
     dst.push32(offset as u32);  // 10
     dst.push32(headersize as u32);  // 14
-    dst.push32(image.width as u32);  // 18
-    dst.push32(-(image.height as i32) as u32);  // 22
-    dst.push16(1);  // 26
-    dst.push16(bpp);  // 28
-    dst.push32(compression);  // 30
-    dst.push32(imagesize as u32);  // 34
-    dst.push32(1);  // 38
-    dst.push32(1);  // 42
-    dst.push32(colors);  // 46
-    dst.push32(colors);  // 50
-    dst.push32(redmask);  // 54
-    dst.push32(greenmask);  // 58
-    dst.push32(bluemask);  // 62
-    dst.push32(alphamask);  // 66
-    dst.push32(0x57696E20);  // 70
-    dst.push32(0);  // 74
-    dst.push32(0);  // 78
-    dst.push32(0);  // 82
-    dst.push32(0);  // 86
-    dst.push32(0);  // 90
-    dst.push32(0);  // 94
-    dst.push32(0);  // 98
-    dst.push32(0);  // 102
-    dst.push32(0);  // 106
-    dst.push32(0);  // 110
-    dst.push32(0);  // 114
-    dst.push32(0);  // 118
-    for y in 0..image.height {
-        for x in 0..image.width {
-            dst.push32(image.data[y * image.width + x]);  // 122..
+    if header == Header::Core {
+        dst.push16(image.width as u16);  // 18
+        dst.push16(image.height as u16);  // 20
+        dst.push16(1);  // 22
+        dst.push16(bpp);  // 24
+    }
+    else {
+        dst.push32(image.width as u32);  // 18
+        dst.push32(if bottom_up { image.height as u32 } else { -(image.height as i32) as u32 });  // 22
+        dst.push16(1);  // 26
+        dst.push16(bpp);  // 28
+        dst.push32(compression);  // 30
+        dst.push32(imagesize as u32);  // 34
+        dst.push32(xppm);  // 38
+        dst.push32(yppm);  // 42
+        dst.push32(colors as u32);  // 46
+        dst.push32(colors as u32);  // 50
+    }
+    if (headersize >= 108) || (maskssize > 0) {
+        dst.push32(redmask);  // 54
+        dst.push32(greenmask);  // 58
+        dst.push32(bluemask);  // 62
+    }
+    if headersize >= 108 {
+        dst.push32(alphamask);  // 66
+        dst.push32(if profilesize > 0 { PROFILE_EMBEDDED } else { LCS_WINDOWS_COLOR_SPACE });  // 70
+        dst.push32(0);  // 74
+        dst.push32(0);  // 78
+        dst.push32(0);  // 82
+        dst.push32(0);  // 86
+        dst.push32(0);  // 90
+        dst.push32(0);  // 94
+        dst.push32(0);  // 98
+        dst.push32(0);  // 102
+        dst.push32(0);  // 106
+        dst.push32(0);  // 110
+        dst.push32(0);  // 114
+        dst.push32(0);  // 118
+    }
+    if headersize == 124 {
+        dst.push32(4);  // 122, LCS_GM_IMAGES (perceptual)
+        dst.push32(if profilesize > 0 { (offset + imagesize - 14) as u32 } else { 0 });  // 126, profile after the pixels
+        dst.push32(profilesize as u32);  // 130
+        dst.push32(0);  // 134
+    }
+    for i in 0..colors {
+        let c = if i < palette.len() { palette[i] } else { 0 };
+        dst.push((c & 255) as u8);
+        dst.push(((c >> 8) & 255) as u8);
+        dst.push(((c >> 16) & 255) as u8);
+        if entrysize == 4 {
+            dst.push(0);
         }
     }
+    dst.extend_from_slice(&data);
+    if let Some(profile) = &options.icc_profile {
+        dst.extend_from_slice(profile);
+    }
     Ok(dst)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, so the generated images are the same on every run
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self,n: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 % n
+        }
+    }
+
+    // bitfield component scaled to 8 bits by repeating its bits, def for an empty mask
+    fn field(c: u32,mask: u32,def: u32) -> u32 {
+        if mask == 0 {
+            return def;
+        }
+        let shift = mask.trailing_zeros();
+        let size = (mask >> shift).trailing_ones();
+        let d = ((c as u64) >> shift) & ((1u64 << size) - 1);
+        if size >= 8 {
+            return (d >> (size - 8)) as u32;
+        }
+        let mut v = d;
+        let mut bits = size;
+        while bits < 8 {
+            v = (v << size) | d;
+            bits += size;
+        }
+        (v >> (bits - 8)) as u32
+    }
+
+    // one pixel at a time, straight from the definition of each uncompressed type
+    #[allow(clippy::too_many_arguments)]
+    fn reference_pixels(src: &[u8],width: usize,height: usize,bottom_up: bool,itype: u16,palette: &[u32; 256],redmask: u32,greenmask: u32,bluemask: u32,alphamask: u32) -> Vec<u32> {
+        let bpp = (itype & 255) as usize;
+        let stride = (width * bpp).div_ceil(32) * 4;
+        let mut dst = vec![0u32; width * height];
+        for y in 0..height {
+            let line = &src[if bottom_up { height - 1 - y } else { y } * stride..];
+            for x in 0..width {
+                let p = &line[x * bpp / 8..];
+                dst[y * width + x] = match itype {
+                    TYPE_C1 | TYPE_C2 | TYPE_C4 | TYPE_C8 => {
+                        let index = (p[0] >> (8 - bpp - (x * bpp) % 8)) as usize & ((1 << bpp) - 1);
+                        palette[index]
+                    },
+                    TYPE_A1RGB5 => {
+                        let c = (p[0] as u32) | ((p[1] as u32) << 8);
+                        let a = if (alphamask == 0) || ((c & 0x8000) != 0) { 255 } else { 0 };
+                        (a << 24) | (field(c,0x7C00,0) << 16) | (field(c,0x03E0,0) << 8) | field(c,0x001F,0)
+                    },
+                    TYPE_RGB8 => 0xFF000000 | ((p[2] as u32) << 16) | ((p[1] as u32) << 8) | (p[0] as u32),
+                    TYPE_ARGB8 => {
+                        let a = if alphamask == 0 { 255 } else { p[3] as u32 };
+                        (a << 24) | ((p[2] as u32) << 16) | ((p[1] as u32) << 8) | (p[0] as u32)
+                    },
+                    TYPE_B16 | TYPE_B32 => {
+                        let c = if itype == TYPE_B16 { (p[0] as u32) | ((p[1] as u32) << 8) } else { from_le32(p) };
+                        (field(c,alphamask,255) << 24) | (field(c,redmask,0) << 16) | (field(c,greenmask,0) << 8) | field(c,bluemask,0)
+                    },
+                    _ => { panic!("no reference for type {:04X}",itype); },
+                };
+            }
+        }
+        dst
+    }
+
+    // decode_pixels against the reference, both ways up
+    #[allow(clippy::too_many_arguments)]
+    fn check(what: &str,src: &[u8],width: usize,height: usize,itype: u16,palette: &[u32; 256],redmask: u32,greenmask: u32,bluemask: u32,alphamask: u32) {
+        for bottom_up in [false,true].iter() {
+            let mut dst = vec![0x12345678u32; width * height];
+            decode_pixels(&mut dst,src,width,height,*bottom_up,itype,palette,redmask,greenmask,bluemask,alphamask);
+            let expected = reference_pixels(src,width,height,*bottom_up,itype,palette,redmask,greenmask,bluemask,alphamask);
+            if let Some(i) = (0..dst.len()).find(|i| dst[*i] != expected[*i]) {
+                panic!("{} {}x{} type {:04X} masks {:08X} {:08X} {:08X} {:08X} bottom_up {}: pixel ({},{}) is {:08X} instead of {:08X}",
+                    what,width,height,itype,redmask,greenmask,bluemask,alphamask,bottom_up,i % width,i / width,dst[i],expected[i]);
+            }
+        }
+    }
+
+    fn random_palette(random: &mut Random) -> [u32; 256] {
+        let mut palette = [0u32; 256];
+        for entry in palette.iter_mut() {
+            *entry = (random.next(0x10000) << 16) | random.next(0x10000);
+        }
+        palette
+    }
+
+    // every uncompressed bitmap in bmpsuite g/ and q/, with the masks the decoder would use
+    #[test]
+    fn row_converters_bmpsuite() {
+        let mut random = Random(0x2545F491);
+        let mut seen = Vec::new();
+        for dir in ["g","q"].iter() {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/bmp/bmpsuite").join(dir);
+            let mut names: Vec<_> = std::fs::read_dir(path).unwrap().map(|entry| entry.unwrap().path()).collect();
+            names.sort();
+            for name in names.iter() {
+                let what = name.display().to_string();
+                let src = std::fs::read(name).unwrap();
+                let fh = select(&src).unwrap();
+                let (headersize,h) = header(&src,fh).unwrap();
+                let (width,height,itype) = bitmap_size(&src,fh).unwrap();
+                if matches!(itype,TYPE_C4_RLE | TYPE_C8_RLE | TYPE_C1_HUFFMAN | TYPE_RGB8_RLE | TYPE_JPEG | TYPE_PNG) {
+                    continue;
+                }
+                let offset = from_le32(&h[10..14]) as usize;
+                let palette = random_palette(&mut random);
+                if (itype == TYPE_B16) || (itype == TYPE_B32) {
+                    let alphamask = if (headersize >= 56) || (offset - fh - 14 - headersize as usize >= 16) { from_le32(&h[66..70]) } else { 0 };
+                    check(&what,&src[offset..],width,height,itype,&palette,from_le32(&h[54..58]),from_le32(&h[58..62]),from_le32(&h[62..66]),alphamask);
+                }
+                else {
+                    // with and without alpha
+                    check(&what,&src[offset..],width,height,itype,&palette,0,0,0,0);
+                    check(&what,&src[offset..],width,height,itype,&palette,0,0,0,if itype == TYPE_A1RGB5 { 0x8000 } else { 0xFF000000 });
+                }
+                seen.push(itype);
+            }
+        }
+        for itype in [TYPE_C1,TYPE_C2,TYPE_C4,TYPE_C8,TYPE_A1RGB5,TYPE_B16,TYPE_RGB8,TYPE_ARGB8,TYPE_B32].iter() {
+            assert!(seen.contains(itype),"no bmpsuite image of type {:04X}",itype);
+        }
+    }
+
+    // contiguous random masks, in random order, that fit in bits; some of them empty
+    fn random_masks(random: &mut Random,bits: u32) -> [u32; 4] {
+        let mut masks = [0u32; 4];
+        let mut order = [0usize,1,2,3];
+        for i in (1..4).rev() {
+            order.swap(i,random.next(i as u32 + 1) as usize);
+        }
+        let mut shift = random.next(3);
+        for i in order.iter() {
+            if (shift >= bits) || (random.next(8) == 0) {
+                continue;
+            }
+            let size = 1 + random.next(bits - shift);
+            masks[*i] = (((1u64 << size) - 1) as u32) << shift;
+            shift += size + random.next(2);
+        }
+        masks
+    }
+
+    // random pixels of every width up to 33, to get through all the odd ends of the row converters
+    #[test]
+    fn row_converters_random() {
+        let mut random = Random(0x9E3779B9);
+        for width in 1..=33 {
+            for height in 1..=3 {
+                let palette = random_palette(&mut random);
+                let src: Vec<u8> = (0..height * (width * 4 + 4)).map(|_| random.next(256) as u8).collect();
+                for itype in [TYPE_C1,TYPE_C2,TYPE_C4,TYPE_C8,TYPE_RGB8].iter() {
+                    check("random",&src,width,height,*itype,&palette,0,0,0,0);
+                }
+                check("random",&src,width,height,TYPE_A1RGB5,&palette,0,0,0,0);
+                check("random",&src,width,height,TYPE_A1RGB5,&palette,0,0,0,0x8000);
+                check("random",&src,width,height,TYPE_ARGB8,&palette,0,0,0,0);
+                check("random",&src,width,height,TYPE_ARGB8,&palette,0,0,0,0xFF000000);
+                for _ in 0..20 {
+                    let [r,g,b,a] = random_masks(&mut random,16);
+                    check("random",&src,width,height,TYPE_B16,&palette,r,g,b,a);
+                    let [r,g,b,a] = random_masks(&mut random,32);
+                    check("random",&src,width,height,TYPE_B32,&palette,r,g,b,a);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,621 @@
+// image_formats::icc
+// by Desmond Germans, 2019
+
+use crate::Image;
+
+const SIG_ACSP: u32 = 0x61637370;  // 'acsp'
+const SIG_RGB: u32 = 0x52474220;  // 'RGB '
+const SIG_GRAY: u32 = 0x47524159;  // 'GRAY'
+const SIG_XYZ: u32 = 0x58595A20;  // 'XYZ '
+const SIG_LAB: u32 = 0x4C616220;  // 'Lab '
+
+const TAG_A2B0: u32 = 0x41324230;
+const TAG_A2B1: u32 = 0x41324231;
+const TAG_B2A0: u32 = 0x42324130;
+const TAG_B2A1: u32 = 0x42324131;
+const TAG_RXYZ: u32 = 0x7258595A;
+const TAG_GXYZ: u32 = 0x6758595A;
+const TAG_BXYZ: u32 = 0x6258595A;
+const TAG_RTRC: u32 = 0x72545243;
+const TAG_GTRC: u32 = 0x67545243;
+const TAG_BTRC: u32 = 0x62545243;
+const TAG_KTRC: u32 = 0x6B545243;
+
+const TYPE_CURV: u32 = 0x63757276;
+const TYPE_PARA: u32 = 0x70617261;
+const TYPE_XYZ: u32 = 0x58595A20;
+const TYPE_MFT1: u32 = 0x6D667431;
+const TYPE_MFT2: u32 = 0x6D667432;
+const TYPE_MAB: u32 = 0x6D414220;
+const TYPE_MBA: u32 = 0x6D424120;
+
+// PCS illuminant
+const D50: [f64; 3] = [0.9642,1.0,0.8249];
+
+fn from_be16(src: &[u8]) -> u16 {
+    ((src[0] as u16) << 8) | (src[1] as u16)
+}
+
+fn from_be32(src: &[u8]) -> u32 {
+    ((src[0] as u32) << 24) | ((src[1] as u32) << 16) | ((src[2] as u32) << 8) | (src[3] as u32)
+}
+
+fn s15fixed16(src: &[u8]) -> f64 {
+    (from_be32(src) as i32 as f64) / 65536.0
+}
+
+// tone curve, input and output normalized to 0..1
+#[derive(Clone)]
+enum Curve {
+    Gamma(f64),
+    Table(Vec<f64>),
+    Parametric(u16,[f64; 7]),  // function type, g a b c d e f
+}
+
+impl Curve {
+    fn eval(&self,x: f64) -> f64 {
+        let x = x.clamp(0.0,1.0);
+        let y = match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Table(table) => {
+                let p = x * ((table.len() - 1) as f64);
+                let i = (p as usize).min(table.len() - 2);
+                let f = p - (i as f64);
+                table[i] + (table[i + 1] - table[i]) * f
+            },
+            Curve::Parametric(function,p) => {
+                let [g,a,b,c,d,e,f] = *p;
+                let pow = |v: f64| if v > 0.0 { v.powf(g) } else { 0.0 };
+                match function {
+                    0 => pow(x),
+                    1 => if x >= -b / a { pow(a * x + b) } else { 0.0 },
+                    2 => if x >= -b / a { pow(a * x + b) + c } else { c },
+                    3 => if x >= d { pow(a * x + b) } else { c * x },
+                    _ => if x >= d { pow(a * x + b) + e } else { c * x + f },
+                }
+            },
+        };
+        y.clamp(0.0,1.0)
+    }
+
+    // input for output y, by bisection; curves may rise or fall
+    fn invert(&self,y: f64) -> f64 {
+        let rising = self.eval(1.0) >= self.eval(0.0);
+        let mut low = 0.0;
+        let mut high = 1.0;
+        for _i in 0..32 {
+            let mid = 0.5 * (low + high);
+            if (self.eval(mid) < y) == rising {
+                low = mid;
+            }
+            else {
+                high = mid;
+            }
+        }
+        0.5 * (low + high)
+    }
+}
+
+// multidimensional table, the first input varies slowest
+#[derive(Clone)]
+struct Clut {
+    grid: Vec<usize>,
+    outputs: usize,
+    data: Vec<f64>,
+}
+
+impl Clut {
+    // multilinear interpolation
+    fn eval(&self,input: &[f64]) -> Vec<f64> {
+        let n = self.grid.len();
+        let mut base = 0;
+        let mut fractions = Vec::with_capacity(n);
+        let mut strides = vec![self.outputs; n];
+        for i in (0..n - 1).rev() {
+            strides[i] = strides[i + 1] * self.grid[i + 1];
+        }
+        for i in 0..n {
+            let p = input[i].clamp(0.0,1.0) * ((self.grid[i] - 1) as f64);
+            let k = (p as usize).min(self.grid[i].max(2) - 2);
+            fractions.push(p - (k as f64));
+            base += k * strides[i];
+        }
+        let mut result = vec![0.0; self.outputs];
+        for corner in 0..(1 << n) {
+            let mut weight = 1.0;
+            let mut offset = base;
+            for i in 0..n {
+                if (corner & (1 << i)) != 0 {
+                    if self.grid[i] < 2 {
+                        weight = 0.0;
+                    }
+                    weight *= fractions[i];
+                    offset += strides[i];
+                }
+                else {
+                    weight *= 1.0 - fractions[i];
+                }
+            }
+            if weight != 0.0 {
+                for (r,d) in result.iter_mut().zip(self.data[offset..offset + self.outputs].iter()) {
+                    *r += weight * d;
+                }
+            }
+        }
+        result
+    }
+}
+
+#[derive(Clone)]
+enum Stage {
+    Curves(Vec<Curve>),
+    Matrix([f64; 12]),  // 3x3 followed by the offsets
+    Clut(Clut),
+}
+
+// how the PCS is encoded in the 0..1 values of a LUT
+#[derive(Copy,Clone,PartialEq)]
+enum Encoding {
+    Lut8,
+    Lut16,  // legacy 16-bit Lab
+    V4,
+}
+
+// lut8, lut16, lutAtoB or lutBtoA tag as a sequence of stages
+#[derive(Clone)]
+struct Lut {
+    encoding: Encoding,
+    stages: Vec<Stage>,
+}
+
+impl Lut {
+    fn eval(&self,input: &[f64]) -> Vec<f64> {
+        let mut values = input.to_vec();
+        for stage in self.stages.iter() {
+            values = match stage {
+                Stage::Curves(curves) => values.iter().zip(curves.iter()).map(|(v,c)| c.eval(*v)).collect(),
+                Stage::Matrix(m) => (0..3).map(|i| (m[i * 3] * values[0] + m[i * 3 + 1] * values[1] + m[i * 3 + 2] * values[2] + m[9 + i]).clamp(0.0,1.0)).collect(),
+                Stage::Clut(clut) => clut.eval(&values),
+            };
+        }
+        values
+    }
+}
+
+// parse a curv or para element, returns the curve and its size in bytes
+fn parse_curve(src: &[u8]) -> Option<(Curve,usize)> {
+    if src.len() < 12 {
+        return None;
+    }
+    match from_be32(&src[0..4]) {
+        TYPE_CURV => {
+            let count = from_be32(&src[8..12]) as usize;
+            if src.len() < 12 + count * 2 {
+                return None;
+            }
+            let curve = match count {
+                0 => Curve::Gamma(1.0),
+                1 => Curve::Gamma((from_be16(&src[12..14]) as f64) / 256.0),
+                _ => Curve::Table((0..count).map(|i| (from_be16(&src[12 + i * 2..14 + i * 2]) as f64) / 65535.0).collect()),
+            };
+            Some((curve,12 + count * 2))
+        },
+        TYPE_PARA => {
+            let function = from_be16(&src[8..10]);
+            let count = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => { return None; },
+            };
+            if src.len() < 12 + count * 4 {
+                return None;
+            }
+            let mut p = [1.0,1.0,0.0,0.0,0.0,0.0,0.0];
+            for (i,v) in p.iter_mut().take(count).enumerate() {
+                *v = s15fixed16(&src[12 + i * 4..16 + i * 4]);
+            }
+            Some((Curve::Parametric(function,p),12 + count * 4))
+        },
+        _ => None,
+    }
+}
+
+// a sequence of curves in a lutAtoB or lutBtoA tag, each padded to 4 bytes
+fn parse_curves(src: &[u8],count: usize) -> Option<Vec<Curve>> {
+    let mut curves = Vec::new();
+    let mut sp = 0;
+    for _i in 0..count {
+        let (curve,size) = parse_curve(src.get(sp..)?)?;
+        curves.push(curve);
+        sp += (size + 3) & !3;
+    }
+    Some(curves)
+}
+
+// a curve table of a lut8 or lut16 tag
+fn table_curve(src: &[u8],entries: usize,bytes: usize) -> Curve {
+    if bytes == 1 {
+        Curve::Table((0..entries).map(|i| (src[i] as f64) / 255.0).collect())
+    }
+    else {
+        Curve::Table((0..entries).map(|i| (from_be16(&src[i * 2..i * 2 + 2]) as f64) / 65535.0).collect())
+    }
+}
+
+fn parse_lut(src: &[u8],pcs_input: bool) -> Option<Lut> {
+    if src.len() < 12 {
+        return None;
+    }
+    let tagtype = from_be32(&src[0..4]);
+    let inputs = src[8] as usize;
+    let outputs = src[9] as usize;
+    if (inputs == 0) || (inputs > 8) || (outputs == 0) || (outputs > 8) {
+        return None;
+    }
+    match tagtype {
+        TYPE_MFT1 | TYPE_MFT2 => {
+            let grid = src[10] as usize;
+            let (bytes,input_entries,output_entries,mut sp) = if tagtype == TYPE_MFT1 {
+                (1,256,256,48)
+            }
+            else {
+                (2,from_be16(src.get(48..50)?) as usize,from_be16(src.get(50..52)?) as usize,52)
+            };
+            let points = grid.checked_pow(inputs as u32)?;
+            if (grid < 2) || (input_entries < 2) || (output_entries < 2) || (src.len() < sp + bytes * (inputs * input_entries + points * outputs + outputs * output_entries)) {
+                return None;
+            }
+            let mut stages = Vec::new();
+            if pcs_input && (inputs == 3) {
+                // the matrix is only used on XYZ input
+                let mut m = [0.0; 12];
+                for (i,v) in m.iter_mut().take(9).enumerate() {
+                    *v = s15fixed16(&src[12 + i * 4..16 + i * 4]);
+                }
+                stages.push(Stage::Matrix(m));
+            }
+            stages.push(Stage::Curves((0..inputs).map(|i| table_curve(&src[sp + i * input_entries * bytes..],input_entries,bytes)).collect()));
+            sp += inputs * input_entries * bytes;
+            let data = (0..points * outputs).map(|i| if bytes == 1 { (src[sp + i] as f64) / 255.0 } else { (from_be16(&src[sp + i * 2..sp + i * 2 + 2]) as f64) / 65535.0 }).collect();
+            stages.push(Stage::Clut(Clut { grid: vec![grid; inputs],outputs,data, }));
+            sp += points * outputs * bytes;
+            stages.push(Stage::Curves((0..outputs).map(|i| table_curve(&src[sp + i * output_entries * bytes..],output_entries,bytes)).collect()));
+            Some(Lut { encoding: if tagtype == TYPE_MFT1 { Encoding::Lut8 } else { Encoding::Lut16 },stages, })
+        },
+        TYPE_MAB | TYPE_MBA => {
+            if src.len() < 32 {
+                return None;
+            }
+            let a_to_b = tagtype == TYPE_MAB;
+            let offset = |i: usize| from_be32(&src[12 + i * 4..16 + i * 4]) as usize;
+            let (b,matrix,m,clut,a) = (offset(0),offset(1),offset(2),offset(3),offset(4));
+            // the curves at the PCS side have as many channels as the PCS, the A curves as many as the device
+            let pcs_channels = if a_to_b { outputs } else { inputs };
+            let device_channels = if a_to_b { inputs } else { outputs };
+            let b_curves = if b != 0 { Some(Stage::Curves(parse_curves(src.get(b..)?,pcs_channels)?)) } else { None };
+            let m_curves = if m != 0 { Some(Stage::Curves(parse_curves(src.get(m..)?,pcs_channels)?)) } else { None };
+            let a_curves = if a != 0 { Some(Stage::Curves(parse_curves(src.get(a..)?,device_channels)?)) } else { None };
+            let matrix = if (matrix != 0) && (pcs_channels == 3) {
+                let data = src.get(matrix..matrix + 48)?;
+                let mut mt = [0.0; 12];
+                for (i,v) in mt.iter_mut().enumerate() {
+                    *v = s15fixed16(&data[i * 4..i * 4 + 4]);
+                }
+                Some(Stage::Matrix(mt))
+            }
+            else {
+                None
+            };
+            let clut = if clut != 0 {
+                let header = src.get(clut..clut + 20)?;
+                let grid: Vec<usize> = header[0..inputs].iter().map(|g| *g as usize).collect();
+                let bytes = header[16] as usize;
+                let points = grid.iter().try_fold(1usize,|p,g| p.checked_mul(*g))?;
+                if grid.contains(&0) || ((bytes != 1) && (bytes != 2)) {
+                    return None;
+                }
+                let data = src.get(clut + 20..clut + 20 + points * outputs * bytes)?;
+                let data = (0..points * outputs).map(|i| if bytes == 1 { (data[i] as f64) / 255.0 } else { (from_be16(&data[i * 2..i * 2 + 2]) as f64) / 65535.0 }).collect();
+                Some(Stage::Clut(Clut { grid,outputs,data, }))
+            }
+            else {
+                None
+            };
+            let stages = if a_to_b { [a_curves,clut,m_curves,matrix,b_curves] } else { [b_curves,matrix,m_curves,clut,a_curves] };
+            Some(Lut { encoding: Encoding::V4,stages: stages.iter().flatten().cloned().collect(), })
+        },
+        _ => None,
+    }
+}
+
+// LUT values of a PCS to XYZ
+fn pcs_to_xyz(pcs: u32,encoding: Encoding,v: &[f64]) -> [f64; 3] {
+    if pcs == SIG_XYZ {
+        let scale = 65535.0 / 32768.0;
+        return [v[0] * scale,v[1] * scale,v[2] * scale];
+    }
+    let scale = if encoding == Encoding::Lut16 { 65535.0 / 65280.0 } else { 1.0 };
+    lab_to_xyz([v[0] * scale * 100.0,v[1] * scale * 255.0 - 128.0,v[2] * scale * 255.0 - 128.0])
+}
+
+// XYZ to LUT values of a PCS
+fn xyz_to_pcs(pcs: u32,encoding: Encoding,xyz: [f64; 3]) -> Vec<f64> {
+    if pcs == SIG_XYZ {
+        let scale = 32768.0 / 65535.0;
+        return xyz.iter().map(|c| c * scale).collect();
+    }
+    let scale = if encoding == Encoding::Lut16 { 65280.0 / 65535.0 } else { 1.0 };
+    let lab = xyz_to_lab(xyz);
+    vec![lab[0] / 100.0 * scale,(lab[1] + 128.0) / 255.0 * scale,(lab[2] + 128.0) / 255.0 * scale]
+}
+
+fn lab_to_xyz(lab: [f64; 3]) -> [f64; 3] {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let fx = fy + lab[1] / 500.0;
+    let fz = fy - lab[2] / 200.0;
+    let f = |t: f64| if t > 6.0 / 29.0 { t * t * t } else { 3.0 * (6.0 / 29.0) * (6.0 / 29.0) * (t - 4.0 / 29.0) };
+    [D50[0] * f(fx),D50[1] * f(fy),D50[2] * f(fz)]
+}
+
+fn xyz_to_lab(xyz: [f64; 3]) -> [f64; 3] {
+    let f = |t: f64| if t > (6.0 / 29.0) * (6.0 / 29.0) * (6.0 / 29.0) { t.cbrt() } else { t / (3.0 * (6.0 / 29.0) * (6.0 / 29.0)) + 4.0 / 29.0 };
+    let fx = f(xyz[0] / D50[0]);
+    let fy = f(xyz[1] / D50[1]);
+    let fz = f(xyz[2] / D50[2]);
+    [116.0 * fy - 16.0,500.0 * (fx - fy),200.0 * (fy - fz)]
+}
+
+fn invert_matrix(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut r = [[0.0; 3]; 3];
+    for (i,row) in r.iter_mut().enumerate() {
+        for (j,v) in row.iter_mut().enumerate() {
+            // cofactor of (j,i)
+            let a = [(j + 1) % 3,(j + 2) % 3];
+            let b = [(i + 1) % 3,(i + 2) % 3];
+            *v = (m[a[0]][b[0]] * m[a[1]][b[1]] - m[a[0]][b[1]] * m[a[1]][b[0]]) / det;
+        }
+    }
+    Some(r)
+}
+
+// rendering intent
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Intent {
+    #[default]
+    Perceptual,
+    RelativeColorimetric,
+}
+
+// RGB or gray ICC profile (v2 or v4)
+#[derive(Clone)]
+pub struct Profile {
+    pub version: (u8,u8),  // major, minor
+    pub gray: bool,
+    pcs: u32,
+    matrix: Option<[[f64; 3]; 3]>,  // colorant columns, RGB to XYZ
+    trc: Vec<Curve>,  // 3 for RGB, 1 for gray
+    a2b: [Option<Lut>; 2],  // device to PCS, perceptual and relative colorimetric
+    b2a: [Option<Lut>; 2],  // PCS to device
+}
+
+impl Profile {
+    pub fn parse(src: &[u8]) -> Result<Profile,String> {
+        if (src.len() < 132) || (from_be32(&src[36..40]) != SIG_ACSP) {
+            return Err("Invalid ICC profile".to_string());
+        }
+        let space = from_be32(&src[16..20]);
+        let pcs = from_be32(&src[20..24]);
+        if ((space != SIG_RGB) && (space != SIG_GRAY)) || ((pcs != SIG_XYZ) && (pcs != SIG_LAB)) {
+            return Err("Unsupported ICC profile".to_string());
+        }
+        let gray = space == SIG_GRAY;
+        let count = from_be32(&src[128..132]) as usize;
+        let tag = |signature: u32| -> Option<&[u8]> {
+            for i in 0..count {
+                let entry = src.get(132 + i * 12..144 + i * 12)?;
+                if from_be32(&entry[0..4]) == signature {
+                    let offset = from_be32(&entry[4..8]) as usize;
+                    let size = from_be32(&entry[8..12]) as usize;
+                    return src.get(offset..offset.checked_add(size)?);
+                }
+            }
+            None
+        };
+        let xyz = |signature: u32| -> Option<[f64; 3]> {
+            let data = tag(signature)?;
+            if (data.len() < 20) || (from_be32(&data[0..4]) != TYPE_XYZ) {
+                return None;
+            }
+            Some([s15fixed16(&data[8..12]),s15fixed16(&data[12..16]),s15fixed16(&data[16..20])])
+        };
+        let curve = |signature: u32| -> Option<Curve> { parse_curve(tag(signature)?).map(|c| c.0) };
+        let mut matrix = None;
+        let mut trc = Vec::new();
+        if gray {
+            if let Some(k) = curve(TAG_KTRC) {
+                trc.push(k);
+            }
+        }
+        else if let (Some(r),Some(g),Some(b),Some(rc),Some(gc),Some(bc)) = (xyz(TAG_RXYZ),xyz(TAG_GXYZ),xyz(TAG_BXYZ),curve(TAG_RTRC),curve(TAG_GTRC),curve(TAG_BTRC)) {
+            matrix = Some([[r[0],g[0],b[0]],[r[1],g[1],b[1]],[r[2],g[2],b[2]]]);
+            trc = vec![rc,gc,bc];
+        }
+        let channels = if gray { 1 } else { 3 };
+        let device_lut = |signature: u32,pcs_input: bool| -> Option<Lut> {
+            let lut = parse_lut(tag(signature)?,pcs_input && (pcs == SIG_XYZ))?;
+            Some(lut)
+        };
+        let a2b = [device_lut(TAG_A2B0,false),device_lut(TAG_A2B1,false)];
+        let b2a = [device_lut(TAG_B2A0,true),device_lut(TAG_B2A1,true)];
+        // drop LUTs with the wrong number of channels
+        let a2b = a2b.map(|l| l.filter(|l| lut_channels(l) == (channels,3)));
+        let b2a = b2a.map(|l| l.filter(|l| lut_channels(l) == (3,channels)));
+        if trc.is_empty() && a2b.iter().all(|l| l.is_none()) && b2a.iter().all(|l| l.is_none()) {
+            return Err("Unsupported ICC profile".to_string());
+        }
+        Ok(Profile {
+            version: (src[8],src[9] >> 4),
+            gray,
+            pcs,
+            matrix,
+            trc,
+            a2b,
+            b2a,
+        })
+    }
+
+    // built-in sRGB (IEC 61966-2.1) matrix/TRC profile
+    pub fn srgb() -> Profile {
+        let curve = Curve::Parametric(3,[2.4,1.0 / 1.055,0.055 / 1.055,1.0 / 12.92,0.04045,0.0,0.0]);
+        Profile {
+            version: (2,1),
+            gray: false,
+            pcs: SIG_XYZ,
+            matrix: Some([
+                [0.4360747,0.3850649,0.1430804],
+                [0.2225045,0.7168786,0.0606169],
+                [0.0139322,0.0971045,0.7141733],
+            ]),
+            trc: vec![curve.clone(),curve.clone(),curve],
+            a2b: [None,None],
+            b2a: [None,None],
+        }
+    }
+
+    // LUT for the intent, falling back to the other one
+    fn lut(luts: &[Option<Lut>; 2],intent: Intent) -> Option<&Lut> {
+        let (preferred,other) = if intent == Intent::RelativeColorimetric { (&luts[1],&luts[0]) } else { (&luts[0],&luts[1]) };
+        preferred.as_ref().or(other.as_ref())
+    }
+}
+
+// (inputs, outputs) of a LUT
+fn lut_channels(lut: &Lut) -> (usize,usize) {
+    let mut inputs = 0;
+    let mut outputs = 0;
+    for stage in lut.stages.iter() {
+        let (i,o) = match stage {
+            Stage::Curves(curves) => (curves.len(),curves.len()),
+            Stage::Matrix(_) => (3,3),
+            Stage::Clut(clut) => (clut.grid.len(),clut.outputs),
+        };
+        if inputs == 0 {
+            inputs = i;
+        }
+        else if i != outputs {
+            return (0,0);
+        }
+        outputs = o;
+    }
+    (inputs,outputs)
+}
+
+// device to XYZ half of a transform
+enum Source<'a> {
+    Matrix([Vec<f64>; 3],[[f64; 3]; 3]),  // linearization per 8-bit value, matrix
+    Gray(Vec<f64>),
+    Lut(&'a Lut,u32),
+}
+
+// XYZ to device half of a transform
+enum Destination<'a> {
+    Matrix([Vec<f64>; 3],[[f64; 3]; 3]),  // inverse curve tables, inverse matrix
+    Gray(Vec<f64>),
+    Lut(&'a Lut,u32),
+}
+
+const INVERSE_SIZE: usize = 4096;
+
+fn inverse_table(curve: &Curve) -> Vec<f64> {
+    (0..INVERSE_SIZE).map(|i| curve.invert((i as f64) / ((INVERSE_SIZE - 1) as f64))).collect()
+}
+
+fn lookup(table: &[f64],v: f64) -> f64 {
+    let p = v.clamp(0.0,1.0) * ((table.len() - 1) as f64);
+    let i = (p as usize).min(table.len() - 2);
+    table[i] + (table[i + 1] - table[i]) * (p - (i as f64))
+}
+
+// convert the pixels of image from the source profile to the destination profile; alpha is kept
+pub fn transform(image: &Image,source: &Profile,destination: &Profile,intent: Intent) -> Result<Image,String> {
+    let src = if let Some(lut) = Profile::lut(&source.a2b,intent) {
+        Source::Lut(lut,source.pcs)
+    }
+    else if let Some(matrix) = source.matrix {
+        let table = |c: &Curve| (0..256).map(|i| c.eval((i as f64) / 255.0)).collect::<Vec<f64>>();
+        Source::Matrix([table(&source.trc[0]),table(&source.trc[1]),table(&source.trc[2])],matrix)
+    }
+    else if source.gray && !source.trc.is_empty() {
+        Source::Gray((0..256).map(|i| source.trc[0].eval((i as f64) / 255.0)).collect())
+    }
+    else {
+        return Err("ICC profile can't be used as source".to_string());
+    };
+    let dst = if let Some(lut) = Profile::lut(&destination.b2a,intent) {
+        Destination::Lut(lut,destination.pcs)
+    }
+    else if let Some(matrix) = destination.matrix {
+        let inverse = invert_matrix(&matrix).ok_or("ICC profile can't be used as destination".to_string())?;
+        Destination::Matrix([inverse_table(&destination.trc[0]),inverse_table(&destination.trc[1]),inverse_table(&destination.trc[2])],inverse)
+    }
+    else if destination.gray && !destination.trc.is_empty() {
+        Destination::Gray(inverse_table(&destination.trc[0]))
+    }
+    else {
+        return Err("ICC profile can't be used as destination".to_string());
+    };
+
+    let mut result = Image::new(image.width,image.height);
+    let mut last = None;
+    for (d,s) in result.data.iter_mut().zip(image.data.iter()) {
+        // neighbouring pixels are often the same
+        if let Some((ls,ld)) = last {
+            if ls == *s {
+                *d = ld;
+                continue;
+            }
+        }
+        let r = ((s >> 16) & 255) as usize;
+        let g = ((s >> 8) & 255) as usize;
+        let b = (s & 255) as usize;
+        let xyz = match &src {
+            Source::Matrix(tables,m) => {
+                let rgb = [tables[0][r],tables[1][g],tables[2][b]];
+                [0,1,2].map(|i| m[i][0] * rgb[0] + m[i][1] * rgb[1] + m[i][2] * rgb[2])
+            },
+            Source::Gray(table) => {
+                let y = table[g];
+                [D50[0] * y,D50[1] * y,D50[2] * y]
+            },
+            Source::Lut(lut,pcs) => {
+                let input = if source.gray { vec![(g as f64) / 255.0] } else { vec![(r as f64) / 255.0,(g as f64) / 255.0,(b as f64) / 255.0] };
+                pcs_to_xyz(*pcs,lut.encoding,&lut.eval(&input))
+            },
+        };
+        let device = match &dst {
+            Destination::Matrix(tables,m) => {
+                let rgb = [0,1,2].map(|i| m[i][0] * xyz[0] + m[i][1] * xyz[1] + m[i][2] * xyz[2]);
+                vec![lookup(&tables[0],rgb[0]),lookup(&tables[1],rgb[1]),lookup(&tables[2],rgb[2])]
+            },
+            Destination::Gray(table) => vec![lookup(table,xyz[1])],
+            Destination::Lut(lut,pcs) => lut.eval(&xyz_to_pcs(*pcs,lut.encoding,xyz)),
+        };
+        let c = |v: f64| (v.clamp(0.0,1.0) * 255.0 + 0.5) as u32;
+        let (r,g,b) = if device.len() == 1 { (c(device[0]),c(device[0]),c(device[0])) } else { (c(device[0]),c(device[1]),c(device[2])) };
+        *d = (s & 0xFF000000) | (r << 16) | (g << 8) | b;
+        last = Some((*s,*d));
+    }
+    Ok(result)
+}
+
+// convert the pixels of image from the source profile to sRGB
+pub fn to_srgb(image: &Image,source: &Profile,intent: Intent) -> Result<Image,String> {
+    transform(image,source,&Profile::srgb(),intent)
+}
@@ -0,0 +1,293 @@
+// image_formats::ico
+// by Desmond Germans, 2019
+
+use crate::Image;
+use crate::bmp;
+
+fn from_le16(src: &[u8]) -> u16 {
+    ((src[1] as u16) << 8) | (src[0] as u16)
+}
+
+fn from_le32(src: &[u8]) -> u32 {
+    ((src[3] as u32) << 24) | ((src[2] as u32) << 16) | ((src[1] as u32) << 8) | (src[0] as u32)
+}
+
+fn from_be32(src: &[u8]) -> u32 {
+    ((src[0] as u32) << 24) | ((src[1] as u32) << 16) | ((src[2] as u32) << 8) | (src[3] as u32)
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A];
+
+// one image of an icon or cursor
+#[derive(Clone,Debug)]
+pub struct Entry {
+    pub width: usize,
+    pub height: usize,
+    pub bpp: u16,  // bits per pixel of the stored image
+    pub png: bool,  // stored as PNG instead of as a DIB
+    pub hotspot: Option<(u16,u16)>,  // cursors only
+    offset: usize,
+    size: usize,
+}
+
+// size and bits per pixel of the stored image, which the directory doesn't always get right
+fn stored_size(data: &[u8]) -> Option<(usize,usize,u16,bool)> {
+    if (data.len() >= 33) && (data[0..8] == PNG_SIGNATURE) {
+        // IHDR
+        let channels = match data[25] {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => { return None; },
+        };
+        return Some((from_be32(&data[16..20]) as usize,from_be32(&data[20..24]) as usize,(data[24] as u16) * channels,true));
+    }
+    if (data.len() < 40) || (from_le32(&data[0..4]) < 40) {
+        return None;
+    }
+    // the height covers both the XOR and the AND mask
+    let width = from_le32(&data[4..8]) as i32;
+    let height = from_le32(&data[8..12]) as i32;
+    if (width <= 0) || (height < 2) {
+        return None;
+    }
+    Some((width as usize,(height / 2) as usize,from_le16(&data[14..16]),false))
+}
+
+// all usable images in an icon (ICO) or cursor (CUR), an error when there are none
+pub fn entries(src: &[u8]) -> Result<Vec<Entry>,String> {
+    if (src.len() < 6) || (from_le16(&src[0..2]) != 0) {
+        return Err("Invalid ICO".to_string());
+    }
+    let cursor = match from_le16(&src[2..4]) {
+        1 => false,
+        2 => true,
+        _ => { return Err("Invalid ICO".to_string()); },
+    };
+    // directory records past the end of the file count as bad entries
+    let count = (from_le16(&src[4..6]) as usize).min((src.len() - 6) / 16);
+    let mut entries = Vec::new();
+    for i in 0..count {
+        let sp = 6 + i * 16;
+        // 0: width, 1: height, 2: colors, 3: reserved, 4: planes or hotspot X, 6: bits per pixel or hotspot Y
+        let size = from_le32(&src[sp + 8..sp + 12]) as usize;
+        let offset = from_le32(&src[sp + 12..sp + 16]) as usize;
+        // skip entries that point outside the file or at something that isn't a DIB or PNG
+        if (offset > src.len()) || (size > src.len() - offset) {
+            continue;
+        }
+        let (width,height,bpp,png) = match stored_size(&src[offset..offset + size]) {
+            Some(stored) => stored,
+            None => { continue; },
+        };
+        entries.push(Entry {
+            width,
+            height,
+            bpp,
+            png,
+            hotspot: if cursor { Some((from_le16(&src[sp + 4..sp + 6]),from_le16(&src[sp + 6..sp + 8]))) } else { None },
+            offset,
+            size,
+        });
+    }
+    if entries.is_empty() {
+        return Err("Invalid ICO".to_string());
+    }
+    Ok(entries)
+}
+
+// the largest, and then deepest, image
+fn best(entries: &[Entry]) -> &Entry {
+    let mut best = &entries[0];
+    for entry in entries.iter() {
+        if (entry.width * entry.height,entry.bpp) > (best.width * best.height,best.bpp) {
+            best = entry;
+        }
+    }
+    best
+}
+
+pub fn test(src: &[u8]) -> Option<(usize,usize)> {
+    let entries = entries(src).ok()?;
+    let entry = best(&entries);
+    Some((entry.width,entry.height))
+}
+
+// DIB of an icon or cursor image: XOR bitmap and AND mask
+fn decode_dib(src: &[u8]) -> Result<Image,String> {
+    let (width,height,bpp,_) = match stored_size(src) {
+        Some(stored) => stored,
+        None => { return Err("Invalid ICO".to_string()); },
+    };
+    let headersize = from_le32(&src[0..4]) as usize;
+    let compression = from_le32(&src[16..20]);
+    if (width > 32768) || (height > 32768) || (headersize > src.len()) || ((compression != 0) && (compression != 3)) {
+        return Err("Invalid ICO".to_string());
+    }
+    let itype = ((compression as u16) << 8) | bpp;
+    let mut sp = headersize;
+    let mut palette = [0u32; 256];
+    let mut redmask = 0u32;
+    let mut greenmask = 0u32;
+    let mut bluemask = 0u32;
+    let mut alphamask = 0u32;
+    match itype {
+        0x0001 | 0x0002 | 0x0004 | 0x0008 => {
+            let mut colors = from_le32(&src[32..36]) as usize;
+            if (colors == 0) || (colors > (1 << bpp)) {
+                colors = 1 << bpp;
+            }
+            if sp + colors * 4 > src.len() {
+                return Err("Invalid ICO".to_string());
+            }
+            for entry in palette.iter_mut().take(colors) {
+                *entry = 0xFF000000 | ((src[sp + 2] as u32) << 16) | ((src[sp + 1] as u32) << 8) | (src[sp] as u32);
+                sp += 4;
+            }
+        },
+        0x0010 | 0x0018 => { },
+        0x0020 => {
+            alphamask = 0xFF000000;
+        },
+        0x0310 | 0x0320 => {
+            // masks in the header (V2 and up) or after it
+            let mp = if headersize >= 52 { 40 } else { sp += 12; headersize };
+            if mp + 12 > src.len() {
+                return Err("Invalid ICO".to_string());
+            }
+            redmask = from_le32(&src[mp..mp + 4]);
+            greenmask = from_le32(&src[mp + 4..mp + 8]);
+            bluemask = from_le32(&src[mp + 8..mp + 12]);
+            if headersize >= 56 {
+                alphamask = from_le32(&src[52..56]);
+            }
+        },
+        _ => { return Err("Invalid ICO".to_string()); },
+    }
+    let stride = (width * bpp as usize).div_ceil(32) * 4;
+    if sp + height * stride > src.len() {
+        return Err("Invalid ICO".to_string());
+    }
+    let mut image = Image::new(width,height);
+    bmp::decode_pixels(&mut image.data,&src[sp..],width,height,true,itype,&palette,redmask,greenmask,bluemask,alphamask);
+    // images with an alpha channel that is all 0 (as older writers produce) use the AND mask instead
+    if (alphamask != 0) && image.data.iter().any(|p| (p >> 24) != 0) {
+        return Ok(image);
+    }
+    for p in image.data.iter_mut() {
+        *p |= 0xFF000000;
+    }
+    // AND mask: bottom-up 1 bit lines, set where the screen shows through; some writers leave it out
+    let mp = sp + height * stride;
+    let mstride = width.div_ceil(32) * 4;
+    if mp + height * mstride <= src.len() {
+        for y in 0..height {
+            let line = &src[mp + (height - 1 - y) * mstride..];
+            for x in 0..width {
+                if ((line[x >> 3] >> (7 - (x & 7))) & 1) != 0 {
+                    image.data[y * width + x] = 0x00000000;
+                }
+            }
+        }
+    }
+    Ok(image)
+}
+
+pub fn decode_entry(src: &[u8],entry: &Entry) -> Result<Image,String> {
+    let data = match src.get(entry.offset..entry.offset + entry.size) {
+        Some(data) => data,
+        None => { return Err("Invalid ICO".to_string()); },
+    };
+    if entry.png {
+        crate::png::decode(data)
+    }
+    else {
+        decode_dib(data)
+    }
+}
+
+pub fn decode(src: &[u8]) -> Result<Image,String> {
+    let entries = entries(src)?;
+    decode_entry(src,best(&entries))
+}
+
+// encoder settings
+#[derive(Clone,Default)]
+pub struct EncodeOptions {
+    pub hotspots: Option<Vec<(u16,u16)>>,  // write a cursor (CUR) with these hotspots, one per image, instead of an icon
+}
+
+pub fn encode(images: &[Image]) -> Result<Vec<u8>,String> {
+    encode_with_options(images,&EncodeOptions::default())
+}
+
+// each image is stored as a 32 bit DIB, with an AND mask for the fully transparent pixels
+pub fn encode_with_options(images: &[Image],options: &EncodeOptions) -> Result<Vec<u8>,String> {
+    if images.is_empty() || (images.len() > 65535) {
+        return Err("Need 1 to 65535 images".to_string());
+    }
+    if let Some(hotspots) = &options.hotspots {
+        if hotspots.len() != images.len() {
+            return Err("Need one hotspot per image".to_string());
+        }
+    }
+    let mut dibs = Vec::new();
+    for image in images.iter() {
+        if (image.width == 0) || (image.height == 0) || (image.width > 256) || (image.height > 256) {
+            return Err("Icon images can be at most 256x256 pixels".to_string());
+        }
+        // BITMAPINFOHEADER, with the height of XOR and AND mask together
+        let mut dib = Vec::new();
+        dib.extend_from_slice(&40u32.to_le_bytes());
+        dib.extend_from_slice(&(image.width as u32).to_le_bytes());
+        dib.extend_from_slice(&((image.height * 2) as u32).to_le_bytes());
+        dib.extend_from_slice(&1u16.to_le_bytes());  // planes
+        dib.extend_from_slice(&32u16.to_le_bytes());  // bits per pixel
+        dib.extend_from_slice(&0u32.to_le_bytes());  // BI_RGB
+        dib.extend_from_slice(&((image.width * image.height * 4) as u32).to_le_bytes());
+        dib.extend_from_slice(&[0u8; 16]);  // resolution and colors
+        // XOR bitmap: bottom-up BGRA
+        for y in (0..image.height).rev() {
+            for p in image.data[y * image.width..(y + 1) * image.width].iter() {
+                dib.extend_from_slice(&p.to_le_bytes());
+            }
+        }
+        let mstride = image.width.div_ceil(32) * 4;
+        for y in (0..image.height).rev() {
+            let mut line = vec![0u8; mstride];
+            for x in 0..image.width {
+                if (image.data[y * image.width + x] >> 24) == 0 {
+                    line[x >> 3] |= 0x80 >> (x & 7);
+                }
+            }
+            dib.extend_from_slice(&line);
+        }
+        dibs.push(dib);
+    }
+    let mut dst = Vec::new();
+    dst.extend_from_slice(&0u16.to_le_bytes());
+    dst.extend_from_slice(&(if options.hotspots.is_some() { 2u16 } else { 1u16 }).to_le_bytes());
+    dst.extend_from_slice(&(images.len() as u16).to_le_bytes());
+    let mut offset = 6 + images.len() * 16;
+    for (i,(image,dib)) in images.iter().zip(dibs.iter()).enumerate() {
+        // 256 is written as 0
+        dst.push(image.width as u8);
+        dst.push(image.height as u8);
+        dst.push(0);  // colors, 0 for more than 256
+        dst.push(0);
+        let (x,y) = match &options.hotspots {
+            Some(hotspots) => hotspots[i],
+            None => (1,32),  // planes and bits per pixel
+        };
+        dst.extend_from_slice(&x.to_le_bytes());
+        dst.extend_from_slice(&y.to_le_bytes());
+        dst.extend_from_slice(&(dib.len() as u32).to_le_bytes());
+        dst.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += dib.len();
+    }
+    for dib in dibs.iter() {
+        dst.extend_from_slice(dib);
+    }
+    Ok(dst)
+}
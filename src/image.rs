@@ -7,6 +7,20 @@ pub struct Image {
     pub data: Vec<u32>,
 }
 
+// source coordinates for pixel (x,y) of the output of an EXIF/TIFF orientation transform
+fn orientation_source(orientation: u16,w: usize,h: usize,x: usize,y: usize) -> (usize,usize) {
+    match orientation {
+        2 => (w - 1 - x,y),  // mirror horizontal
+        3 => (w - 1 - x,h - 1 - y),  // rotate 180
+        4 => (x,h - 1 - y),  // mirror vertical
+        5 => (y,x),  // transpose
+        6 => (y,h - 1 - x),  // rotate 90 CW
+        7 => (w - 1 - y,h - 1 - x),  // transverse
+        8 => (w - 1 - y,x),  // rotate 90 CCW
+        _ => (x,y),
+    }
+}
+
 impl Image {
     pub fn new(width: usize,height: usize) -> Image {
         Image {
@@ -15,4 +29,75 @@ impl Image {
             data: vec![0; width * height],
         }
     }
+
+    // returns a copy of the image transformed to upright according to an EXIF/TIFF orientation value (1..=8); unknown values return an unmodified copy
+    pub fn oriented(&self,orientation: u16) -> Image {
+        let w = self.width;
+        let h = self.height;
+        let mut result = if (5..=8).contains(&orientation) { Image::new(h,w) } else { Image::new(w,h) };
+        for y in 0..result.height {
+            for x in 0..result.width {
+                let (sx,sy) = orientation_source(orientation,w,h,x,y);
+                result.data[y * result.width + x] = self.data[sy * w + sx];
+            }
+        }
+        result
+    }
+}
+
+// image with 16 bits per channel, ARGB in the 64-bit pixels
+pub struct Image16 {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u64>,
+}
+
+impl Image16 {
+    pub fn new(width: usize,height: usize) -> Image16 {
+        Image16 {
+            width,
+            height,
+            data: vec![0; width * height],
+        }
+    }
+
+    // widen an 8-bit image, 0xFF becomes 0xFFFF
+    pub fn from_image(image: &Image) -> Image16 {
+        let mut result = Image16::new(image.width,image.height);
+        for (d,s) in result.data.iter_mut().zip(image.data.iter()) {
+            let mut p = 0u64;
+            for shift in [0,8,16,24].iter() {
+                p |= (((s >> shift) & 255) as u64 * 257) << (shift * 2);
+            }
+            *d = p;
+        }
+        result
+    }
+
+    // reduce to an 8-bit image by keeping the high byte of each channel
+    pub fn to_image(&self) -> Image {
+        let mut result = Image::new(self.width,self.height);
+        for (d,s) in result.data.iter_mut().zip(self.data.iter()) {
+            let mut p = 0u32;
+            for shift in [0,8,16,24].iter() {
+                p |= (((s >> (shift * 2 + 8)) & 255) as u32) << shift;
+            }
+            *d = p;
+        }
+        result
+    }
+
+    // same as Image::oriented
+    pub fn oriented(&self,orientation: u16) -> Image16 {
+        let w = self.width;
+        let h = self.height;
+        let mut result = if (5..=8).contains(&orientation) { Image16::new(h,w) } else { Image16::new(w,h) };
+        for y in 0..result.height {
+            for x in 0..result.width {
+                let (sx,sy) = orientation_source(orientation,w,h,x,y);
+                result.data[y * result.width + x] = self.data[sy * w + sx];
+            }
+        }
+        result
+    }
 }
@@ -2,6 +2,7 @@
 // by Desmond Germans, 2019
 
 use crate::Image;
+use crate::Image16;
 
 const TYPE_Y: u16 = 0x000C;
 const TYPE_YUV420: u16 = 0x3900;
@@ -21,6 +22,102 @@ const FOLDING: [u8; 64] = [
 	37,28,29,38,23,39,30,31,
 ];
 
+// row at which the coefficients of each vertical frequency are stored, the columns are in natural order
+const FOLDED_ROW: [usize; 8] = [7,1,5,0,6,2,4,3];
+
+// float IDCT bases (reference and scaled decoding): C(u) / 2 * cos((2x + 1) * u * pi / 2n), indexed [x * n + u]
+const IDCT2: [f32; 4] = [
+	0.35355339,0.35355339,
+	0.35355339,-0.35355339,
+];
+
+const IDCT8: [f32; 64] = [
+	0.35355339,0.49039264,0.46193977,0.4157348,0.35355339,0.27778512,0.19134172,0.09754516,
+	0.35355339,0.4157348,0.19134172,-0.09754516,-0.35355339,-0.49039264,-0.46193977,-0.27778512,
+	0.35355339,0.27778512,-0.19134172,-0.49039264,-0.35355339,0.09754516,0.46193977,0.4157348,
+	0.35355339,0.09754516,-0.46193977,-0.27778512,0.35355339,0.4157348,-0.19134172,-0.49039264,
+	0.35355339,-0.09754516,-0.46193977,0.27778512,0.35355339,-0.4157348,-0.19134172,0.49039264,
+	0.35355339,-0.27778512,-0.19134172,0.49039264,-0.35355339,-0.09754516,0.46193977,-0.4157348,
+	0.35355339,-0.4157348,0.19134172,0.09754516,-0.35355339,0.49039264,-0.46193977,0.27778512,
+	0.35355339,-0.49039264,0.46193977,-0.4157348,0.35355339,-0.27778512,0.19134172,-0.09754516,
+];
+
+const IDCT4: [f32; 16] = [
+	0.35355339,0.46193977,0.35355339,0.19134172,
+	0.35355339,0.19134172,-0.35355339,-0.46193977,
+	0.35355339,-0.19134172,-0.35355339,0.46193977,
+	0.35355339,-0.46193977,0.35355339,-0.19134172,
+];
+
+// zigzag order to natural order
+const ZIGZAG: [u8; 64] = [
+	0,1,8,16,9,2,3,10,
+	17,24,32,25,18,11,4,5,
+	12,19,26,33,40,48,41,34,
+	27,20,13,6,7,14,21,28,
+	35,42,49,56,57,50,43,36,
+	29,22,15,23,30,37,44,51,
+	58,59,52,45,38,31,39,46,
+	53,60,61,54,47,55,62,63,
+];
+
+// Annex K quantization tables (natural order)
+const STD_LUMINANCE_QT: [u8; 64] = [
+	16,11,10,16,24,40,51,61,
+	12,12,14,19,26,58,60,55,
+	14,13,16,24,40,57,69,56,
+	14,17,22,29,51,87,80,62,
+	18,22,37,56,68,109,103,77,
+	24,35,55,64,81,104,113,92,
+	49,64,78,87,103,121,120,101,
+	72,92,95,98,112,100,103,99,
+];
+
+const STD_CHROMINANCE_QT: [u8; 64] = [
+	17,18,24,47,99,99,99,99,
+	18,21,26,66,99,99,99,99,
+	24,26,56,99,99,99,99,99,
+	47,66,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+];
+
+// Annex K huffman tables
+const STD_DC_LUMINANCE_BITS: [u8; 16] = [0,1,5,1,1,1,1,1,1,0,0,0,0,0,0,0];
+const STD_DC_LUMINANCE_VALUES: [u8; 12] = [0,1,2,3,4,5,6,7,8,9,10,11];
+const STD_DC_CHROMINANCE_BITS: [u8; 16] = [0,3,1,1,1,1,1,1,1,1,1,0,0,0,0,0];
+const STD_DC_CHROMINANCE_VALUES: [u8; 12] = [0,1,2,3,4,5,6,7,8,9,10,11];
+const STD_AC_LUMINANCE_BITS: [u8; 16] = [0,2,1,3,3,2,4,3,5,5,4,4,0,0,1,0x7D];
+const STD_AC_LUMINANCE_VALUES: [u8; 162] = [
+	0x01,0x02,0x03,0x00,0x04,0x11,0x05,0x12,0x21,0x31,0x41,0x06,0x13,0x51,0x61,0x07,
+	0x22,0x71,0x14,0x32,0x81,0x91,0xA1,0x08,0x23,0x42,0xB1,0xC1,0x15,0x52,0xD1,0xF0,
+	0x24,0x33,0x62,0x72,0x82,0x09,0x0A,0x16,0x17,0x18,0x19,0x1A,0x25,0x26,0x27,0x28,
+	0x29,0x2A,0x34,0x35,0x36,0x37,0x38,0x39,0x3A,0x43,0x44,0x45,0x46,0x47,0x48,0x49,
+	0x4A,0x53,0x54,0x55,0x56,0x57,0x58,0x59,0x5A,0x63,0x64,0x65,0x66,0x67,0x68,0x69,
+	0x6A,0x73,0x74,0x75,0x76,0x77,0x78,0x79,0x7A,0x83,0x84,0x85,0x86,0x87,0x88,0x89,
+	0x8A,0x92,0x93,0x94,0x95,0x96,0x97,0x98,0x99,0x9A,0xA2,0xA3,0xA4,0xA5,0xA6,0xA7,
+	0xA8,0xA9,0xAA,0xB2,0xB3,0xB4,0xB5,0xB6,0xB7,0xB8,0xB9,0xBA,0xC2,0xC3,0xC4,0xC5,
+	0xC6,0xC7,0xC8,0xC9,0xCA,0xD2,0xD3,0xD4,0xD5,0xD6,0xD7,0xD8,0xD9,0xDA,0xE1,0xE2,
+	0xE3,0xE4,0xE5,0xE6,0xE7,0xE8,0xE9,0xEA,0xF1,0xF2,0xF3,0xF4,0xF5,0xF6,0xF7,0xF8,
+	0xF9,0xFA,
+];
+const STD_AC_CHROMINANCE_BITS: [u8; 16] = [0,2,1,2,4,4,3,4,7,5,4,4,0,1,2,0x77];
+const STD_AC_CHROMINANCE_VALUES: [u8; 162] = [
+	0x00,0x01,0x02,0x03,0x11,0x04,0x05,0x21,0x31,0x06,0x12,0x41,0x51,0x07,0x61,0x71,
+	0x13,0x22,0x32,0x81,0x08,0x14,0x42,0x91,0xA1,0xB1,0xC1,0x09,0x23,0x33,0x52,0xF0,
+	0x15,0x62,0x72,0xD1,0x0A,0x16,0x24,0x34,0xE1,0x25,0xF1,0x17,0x18,0x19,0x1A,0x26,
+	0x27,0x28,0x29,0x2A,0x35,0x36,0x37,0x38,0x39,0x3A,0x43,0x44,0x45,0x46,0x47,0x48,
+	0x49,0x4A,0x53,0x54,0x55,0x56,0x57,0x58,0x59,0x5A,0x63,0x64,0x65,0x66,0x67,0x68,
+	0x69,0x6A,0x73,0x74,0x75,0x76,0x77,0x78,0x79,0x7A,0x82,0x83,0x84,0x85,0x86,0x87,
+	0x88,0x89,0x8A,0x92,0x93,0x94,0x95,0x96,0x97,0x98,0x99,0x9A,0xA2,0xA3,0xA4,0xA5,
+	0xA6,0xA7,0xA8,0xA9,0xAA,0xB2,0xB3,0xB4,0xB5,0xB6,0xB7,0xB8,0xB9,0xBA,0xC2,0xC3,
+	0xC4,0xC5,0xC6,0xC7,0xC8,0xC9,0xCA,0xD2,0xD3,0xD4,0xD5,0xD6,0xD7,0xD8,0xD9,0xDA,
+	0xE2,0xE3,0xE4,0xE5,0xE6,0xE7,0xE8,0xE9,0xEA,0xF2,0xF3,0xF4,0xF5,0xF6,0xF7,0xF8,
+	0xF9,0xFA,
+];
+
 const FC0: f32 = 1.0;
 const FC1: f32 = 0.98078528;
 const FC2: f32 = 0.92387953;
@@ -41,6 +138,22 @@ const C5: i32 = (FC5 * ONE) as i32;
 const C6: i32 = (FC6 * ONE) as i32;
 const C7: i32 = (FC7 * ONE) as i32;
 
+// constants for the accurate integer IDCT, 13 bits
+const ACC_BITS: i32 = 13;
+const ACC_PASS1_BITS: i32 = 2;
+const ACC_0_298631336: i32 = 2446;
+const ACC_0_390180644: i32 = 3196;
+const ACC_0_541196100: i32 = 4433;
+const ACC_0_765366865: i32 = 6270;
+const ACC_0_899976223: i32 = 7373;
+const ACC_1_175875602: i32 = 9633;
+const ACC_1_501321110: i32 = 12299;
+const ACC_1_847759065: i32 = 15137;
+const ACC_1_961570560: i32 = 16069;
+const ACC_2_053119869: i32 = 16819;
+const ACC_2_562915447: i32 = 20995;
+const ACC_3_072711026: i32 = 25172;
+
 const C7PC1: i32 = C7 + C1;
 const C5PC3: i32 = C5 + C3;
 const C7MC1: i32 = C7 - C1;
@@ -65,6 +178,218 @@ fn from_be32(src: &[u8]) -> u32 {
     ((src[0] as u32) << 24) | ((src[1] as u32) << 16) | ((src[2] as u32) << 8) | (src[3] as u32)
 }
 
+// EXIF data as found in APP1, starting at the TIFF header right after "Exif\0\0"
+struct Exif<'a> {
+	block: &'a [u8],
+	le: bool,
+}
+
+// one IFD entry, offset points at the value inside the TIFF block
+struct ExifEntry {
+	tag: u16,
+	format: u16,
+	count: u32,
+	offset: usize,
+}
+
+impl<'a> Exif<'a> {
+	pub fn new(block: &'a [u8]) -> Option<Exif<'a>> {
+		if block.len() < 8 {
+			return None;
+		}
+		let le = match from_be16(&block[0..2]) {
+			0x4949 => true,  // II
+			0x4D4D => false,  // MM
+			_ => { return None; },
+		};
+		Some(Exif {
+			block,
+			le,
+		})
+	}
+
+	pub fn get16(&self,ofs: usize) -> Option<u16> {
+		if ofs + 2 > self.block.len() {
+			return None;
+		}
+		Some(if self.le { from_le16(&self.block[ofs..ofs + 2]) } else { from_be16(&self.block[ofs..ofs + 2]) })
+	}
+
+	pub fn get32(&self,ofs: usize) -> Option<u32> {
+		if ofs + 4 > self.block.len() {
+			return None;
+		}
+		Some(if self.le { from_le32(&self.block[ofs..ofs + 4]) } else { from_be32(&self.block[ofs..ofs + 4]) })
+	}
+
+	// offset of IFD0
+	pub fn first_ifd(&self) -> Option<usize> {
+		match self.get32(4) {
+			Some(0) | None => None,
+			Some(ofs) => Some(ofs as usize),
+		}
+	}
+
+	// offset of the IFD following this one
+	pub fn next_ifd(&self,ifd: usize) -> Option<usize> {
+		let count = self.get16(ifd)? as usize;
+		match self.get32(ifd + 2 + count * 12) {
+			Some(0) | None => None,
+			Some(ofs) => Some(ofs as usize),
+		}
+	}
+
+	pub fn entries(&self,ifd: usize) -> Vec<ExifEntry> {
+		let mut result = Vec::new();
+		let count = match self.get16(ifd) {
+			Some(count) => count as usize,
+			None => { return result; },
+		};
+		let elsize = [0usize,1,1,2,4,8,1,0,2,4,8,4,8];
+		for i in 0..count {
+			let esp = ifd + 2 + i * 12;
+			let (tag,format,count) = match (self.get16(esp),self.get16(esp + 2),self.get32(esp + 4)) {
+				(Some(tag),Some(format),Some(count)) => (tag,format,count),
+				_ => { break; },
+			};
+			if (format == 0) || (format > 12) {
+				continue;
+			}
+			let total = elsize[format as usize] * (count as usize);
+			let offset = if total <= 4 {
+				esp + 8
+			}
+			else {
+				match self.get32(esp + 8) {
+					Some(ofs) => ofs as usize,
+					None => { break; },
+				}
+			};
+			result.push(ExifEntry {
+				tag,
+				format,
+				count,
+				offset,
+			});
+		}
+		result
+	}
+
+	// first value of an integer entry
+	pub fn value(&self,entry: &ExifEntry) -> Option<u32> {
+		if entry.count == 0 {
+			return None;
+		}
+		self.value_at(entry,0)
+	}
+
+	// all values of an integer entry
+	pub fn values(&self,entry: &ExifEntry) -> Vec<u32> {
+		let mut result = Vec::new();
+		for i in 0..entry.count as usize {
+			match self.value_at(entry,i) {
+				Some(value) => { result.push(value); },
+				None => { break; },
+			}
+		}
+		result
+	}
+
+	// value of a RATIONAL entry
+	pub fn rational(&self,entry: &ExifEntry) -> Option<f32> {
+		if (entry.format != 5) || (entry.count == 0) {
+			return None;
+		}
+		let denominator = self.get32(entry.offset + 4)?;
+		if denominator == 0 {
+			return None;
+		}
+		Some((self.get32(entry.offset)? as f32) / (denominator as f32))
+	}
+
+	fn value_at(&self,entry: &ExifEntry,i: usize) -> Option<u32> {
+		match entry.format {
+			1 | 6 | 7 => self.block.get(entry.offset + i).map(|b| *b as u32),  // BYTE, SBYTE, UNDEFINED
+			3 | 8 => self.get16(entry.offset + i * 2).map(|d| d as u32),  // SHORT, SSHORT
+			4 | 9 => self.get32(entry.offset + i * 4),  // LONG, SLONG
+			_ => None,
+		}
+	}
+}
+
+// find the EXIF block in APP1, if any
+fn find_exif(src: &[u8]) -> Option<Exif<'_>> {
+	if (src.len() < 4) || (from_be16(&src[0..2]) != 0xFFD8) {
+		return None;
+	}
+	let mut sp = 2;
+	while sp + 4 <= src.len() {
+		let marker = from_be16(&src[sp..sp + 2]);
+		let length = from_be16(&src[sp + 2..sp + 4]) as usize;
+		match marker {
+			0xFFE1 if (sp + 10 <= src.len()) && (from_be32(&src[sp + 4..sp + 8]) == 0x45786966) => {  // Exif
+				let end = if sp + length + 2 > src.len() { src.len() } else { sp + length + 2 };
+				return Exif::new(&src[sp + 10..end]);
+			},
+			0xFFDA | 0xFFD9 => {  // reached the scan data, so no EXIF to be found
+				return None;
+			},
+			_ => { },
+		}
+		sp += length + 2;
+	}
+	None
+}
+
+const ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+const ICC_CHUNK: usize = 65519;  // maximum profile bytes per APP2 segment
+
+// embedded ICC profile, reassembled from the APP2 segments
+pub fn icc_profile(src: &[u8]) -> Option<Vec<u8>> {
+	if (src.len() < 4) || (from_be16(&src[0..2]) != 0xFFD8) {
+		return None;
+	}
+	let mut chunks: Vec<(u8,&[u8])> = Vec::new();
+	let mut count = 0;
+	let mut sp = 2;
+	while sp + 4 <= src.len() {
+		let marker = from_be16(&src[sp..sp + 2]);
+		let length = from_be16(&src[sp + 2..sp + 4]) as usize;
+		match marker {
+			0xFFE2 if (length >= 16) && (sp + length + 2 <= src.len()) && (&src[sp + 4..sp + 16] == ICC_SIGNATURE) => {
+				chunks.push((src[sp + 16],&src[sp + 18..sp + length + 2]));
+				count = src[sp + 17];
+			},
+			0xFFDA | 0xFFD9 => {
+				break;
+			},
+			_ => { },
+		}
+		sp += length + 2;
+	}
+	// all chunks 1..=count must be present exactly once
+	chunks.sort_by_key(|c| c.0);
+	if chunks.is_empty() || (chunks.len() != count as usize) || chunks.iter().enumerate().any(|(i,c)| c.0 as usize != i + 1) {
+		return None;
+	}
+	Some(chunks.iter().flat_map(|c| c.1.iter().cloned()).collect())
+}
+
+// APP2 segments holding an ICC profile
+fn icc_segments(profile: &[u8]) -> Vec<Vec<u8>> {
+	let count = profile.len().div_ceil(ICC_CHUNK);
+	profile.chunks(ICC_CHUNK).enumerate().map(|(i,chunk)| {
+		let mut dst: Vec<u8> = Vec::new();
+		dst.push16b(0xFFE2);
+		dst.push16b((chunk.len() + 16) as u16);
+		dst.extend_from_slice(ICC_SIGNATURE);
+		dst.push((i + 1) as u8);
+		dst.push(count as u8);
+		dst.extend_from_slice(chunk);
+		dst
+	}).collect()
+}
+
 fn make_coeff(cat: u8,code: isize) -> i32 {
 	let mcat = cat - 1;
 	let hmcat = 1 << mcat;
@@ -131,29 +456,32 @@ struct Reader<'a> {
 	rp: usize,
 	bit: u32,
 	cache: u32,
+	fill: u32,  // number of fill bytes at the end of the cache
 }
 
 impl<'a> Reader<'a> {
 	pub fn new(block: &'a [u8]) -> Reader<'a> {
-		let mut rp = 0usize;
-		let mut bit = 0u32;
-		let mut cache = 0u32;
-		while bit <= 24 {
-			let b = jpeg_get8(block,&mut rp);
-			cache |= (b as u32) << (24 - bit);
-			bit += 8;
-		}
-		Reader {
+		let mut reader = Reader {
 			block: block,
-			rp: rp,
-			bit: bit,
-			cache: cache,
-		}
+			rp: 0,
+			bit: 0,
+			cache: 0,
+			fill: 0,
+		};
+		reader.restock();
+		reader
 	}
 
 	fn restock(&mut self) {
 		while self.bit <= 24 {
-			let b = if self.rp >= self.block.len() { 0 } else { jpeg_get8(self.block,&mut self.rp) };  // fill with 0 if at end of block
+			// fill with 0 at the end of the block or at a marker, so the marker stays in place
+			let b = if (self.rp >= self.block.len()) || ((self.block[self.rp] == 0xFF) && ((self.rp + 1 >= self.block.len()) || (self.block[self.rp + 1] != 0x00))) {
+				self.fill += 1;
+				0
+			}
+			else {
+				jpeg_get8(self.block,&mut self.rp)
+			};
 			self.cache |= (b as u32) << (24 - self.bit);
 			self.bit += 8;
 		}
@@ -197,39 +525,19 @@ impl<'a> Reader<'a> {
 		self.rp = rp;
 		self.cache = 0;
 		self.bit = 0;
+		self.fill = 0;
 		self.restock();
 	}
 
+	// returns the position of the first byte that was not (partially) consumed
 	pub fn leave(&mut self) -> usize {
-		//println!("leave: bit = {}, rp = {}",self.bit,self.rp);
-		// superspecial case: no JPEG data read at all (only during initial programming)
-		if (self.bit == 32) && (self.rp == 4) {
-			return 0;
-		}
-		/*// first search FFD9 to elimiate stray FFs past end of buffer
-		if (self.block[self.rp - 5] == 0xFF) && (self.block[self.rp - 4] == 0xD9) {
-			return self.rp - 5;
-		}
-		if (self.block[self.rp - 4] == 0xFF) && (self.block[self.rp - 3] == 0xD9) {
-			return self.rp - 4;
-		}
-		if (self.block[self.rp - 3] == 0xFF) && (self.block[self.rp - 2] == 0xD9) {
-			return self.rp - 3;
-		}
-		if (self.block[self.rp - 2] == 0xFF) && (self.block[self.rp - 1] == 0xD9) {
-			return self.rp - 2;
-		}
-		if (self.block[self.rp - 1] == 0xFF) && (self.block[self.rp] == 0xD9) {
-			return self.rp - 1;
-		}*/
-		// anything else
-		for _i in 0..((self.bit + 7) / 8) - 2 {
+		let unused = (self.bit / 8).saturating_sub(self.fill);
+		for _i in 0..unused {
 			if (self.block[self.rp - 1] == 0x00) && (self.block[self.rp - 2] == 0xFF) {
 				self.rp -= 1;
 			}
 			self.rp -= 1;
 		}
-		//println!("and leaving with rp = {} ({:02X} {:02X})",self.rp,self.block[self.rp],self.block[self.rp + 1]);
 		self.rp
 	}
 }
@@ -323,7 +631,7 @@ fn update_nonzeros(reader: &mut Reader,coeffs: &mut[i32],start: u8,end: u8,shift
 	let mut k = count;
 	while i <= end {
 		if coeffs[FOLDING[i as usize] as usize] != 0 {
-			if reader.get1() {
+			if reader.get1() && ((coeffs[FOLDING[i as usize] as usize] & (1 << shift)) == 0) {
 				if coeffs[FOLDING[i as usize] as usize] > 0 {
 					coeffs[FOLDING[i as usize] as usize] += 1 << shift;
 				}
@@ -357,11 +665,8 @@ fn unpack_progressive_refine_ac(reader: &mut Reader,coeffs: &mut[i32],acht: &Tab
 			if cat != 0 {
 				let sb = reader.get1();
 				i = update_nonzeros(reader,&mut coeffs[0..64],i,end,shift,run);
-				if sb {
-					coeffs[FOLDING[i as usize] as usize] = 1 << shift;
-				}
-				else {
-					coeffs[FOLDING[i as usize] as usize] = 11 << shift;
+				if i <= end {
+					coeffs[FOLDING[i as usize] as usize] = if sb { 1 << shift } else { -1 << shift };
 				}
 			}
 			else {
@@ -378,6 +683,7 @@ fn unpack_progressive_refine_ac(reader: &mut Reader,coeffs: &mut[i32],acht: &Tab
 					break;
 				}
 			}
+			i += 1;
 		}
 	}
 }
@@ -406,68 +712,480 @@ fn unpack_block(reader: &mut Reader,coeffs: &mut [i32],dcht: &Table,acht: &Table
 	}
 }
 
-fn unpack_macroblock(reader: &mut Reader,coeffs: &mut [i32],dcht: &[Table],acht: &[Table],dt: &[usize],at: &[usize],dc: &mut [i32],start: u8,end: u8,shift: u8,refine: bool,eobrun: &mut usize,itype: u16,rescnt: &mut usize,resint: usize,mask: u8) {
-	match itype {
-		TYPE_Y => {
-			if (mask & 1) != 0 {
-				unpack_block(reader,&mut coeffs[0..64],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+// QM-coder probability estimation state machine (Table D.2): Qe, next index after LPS, next index after MPS, MPS switch
+const ARITH_STATES: [(u32,u8,u8,bool); 114] = [
+	(0x5A1D,1,1,true),(0x2586,14,2,false),(0x1114,16,3,false),(0x080B,18,4,false),
+	(0x03D8,20,5,false),(0x01DA,23,6,false),(0x00E5,25,7,false),(0x006F,28,8,false),
+	(0x0036,30,9,false),(0x001A,33,10,false),(0x000D,35,11,false),(0x0006,9,12,false),
+	(0x0003,10,13,false),(0x0001,12,13,false),(0x5A7F,15,15,true),(0x3F25,36,16,false),
+	(0x2CF2,38,17,false),(0x207C,39,18,false),(0x17B9,40,19,false),(0x1182,42,20,false),
+	(0x0CEF,43,21,false),(0x09A1,45,22,false),(0x072F,46,23,false),(0x055C,48,24,false),
+	(0x0406,49,25,false),(0x0303,51,26,false),(0x0240,52,27,false),(0x01B1,54,28,false),
+	(0x0144,56,29,false),(0x00F5,57,30,false),(0x00B7,59,31,false),(0x008A,60,32,false),
+	(0x0068,62,33,false),(0x004E,63,34,false),(0x003B,32,35,false),(0x002C,33,9,false),
+	(0x5AE1,37,37,true),(0x484C,64,38,false),(0x3A0D,65,39,false),(0x2EF1,67,40,false),
+	(0x261F,68,41,false),(0x1F33,69,42,false),(0x19A8,70,43,false),(0x1518,72,44,false),
+	(0x1177,73,45,false),(0x0E74,74,46,false),(0x0BFB,75,47,false),(0x09F8,77,48,false),
+	(0x0861,78,49,false),(0x0706,79,50,false),(0x05CD,48,51,false),(0x04DE,50,52,false),
+	(0x040F,50,53,false),(0x0363,51,54,false),(0x02D4,52,55,false),(0x025C,53,56,false),
+	(0x01F8,54,57,false),(0x01A4,55,58,false),(0x0160,56,59,false),(0x0125,57,60,false),
+	(0x00F6,58,61,false),(0x00CB,59,62,false),(0x00AB,61,63,false),(0x008F,61,32,false),
+	(0x5B12,65,65,true),(0x4D04,80,66,false),(0x412C,81,67,false),(0x37D8,82,68,false),
+	(0x2FE8,83,69,false),(0x293C,84,70,false),(0x2379,86,71,false),(0x1EDF,87,72,false),
+	(0x1AA9,87,73,false),(0x174E,72,74,false),(0x1424,72,75,false),(0x119C,74,76,false),
+	(0x0F6B,74,77,false),(0x0D51,75,78,false),(0x0BB6,77,79,false),(0x0A40,77,48,false),
+	(0x5832,80,81,true),(0x4D1C,88,82,false),(0x438E,89,83,false),(0x3BDD,90,84,false),
+	(0x34EE,91,85,false),(0x2EAE,92,86,false),(0x299A,93,87,false),(0x2516,86,71,false),
+	(0x5570,88,89,true),(0x4CA9,95,90,false),(0x44D9,96,91,false),(0x3E22,97,92,false),
+	(0x3824,99,93,false),(0x32B4,99,94,false),(0x2E17,93,86,false),(0x56A8,95,96,true),
+	(0x4F46,101,97,false),(0x47E5,102,98,false),(0x41CF,103,99,false),(0x3C3D,104,100,false),
+	(0x375E,99,93,false),(0x5231,105,102,false),(0x4C0F,106,103,false),(0x4639,107,104,false),
+	(0x415E,103,99,false),(0x5627,105,106,true),(0x50E7,108,107,false),(0x4B85,109,103,false),
+	(0x5597,110,109,false),(0x504F,111,107,false),(0x5A10,110,111,true),(0x5522,112,109,false),
+	(0x59EB,112,111,true),
+	(0x5A1D,113,113,false),  // fixed 0.5 probability, for sign and refinement bits
+];
+
+// fixed probability state
+const ARITH_FIXED: u8 = 113;
+
+// QM-coder arithmetic decoder (Annex D); a statistics bin is a state index with the MPS in bit 7
+struct ArithReader<'a> {
+	block: &'a [u8],
+	rp: usize,
+	c: u32,
+	a: u32,
+	ct: i32,
+	marker: bool,  // hit a marker, the rest of the data reads as 0
+}
+
+impl<'a> ArithReader<'a> {
+	pub fn new(block: &'a [u8]) -> ArithReader<'a> {
+		let mut reader = ArithReader {
+			block,
+			rp: 0,
+			c: 0,
+			a: 0,
+			ct: 0,
+			marker: false,
+		};
+		reader.enter(0);
+		reader
+	}
+
+	pub fn enter(&mut self,rp: usize) {
+		self.rp = rp;
+		self.c = 0;
+		self.a = 0;
+		self.ct = -16;  // read two bytes before the first decision
+		self.marker = false;
+	}
+
+	// returns the position of the next marker
+	pub fn leave(&mut self) -> usize {
+		while (self.rp + 1 < self.block.len()) && ((self.block[self.rp] != 0xFF) || (self.block[self.rp + 1] == 0x00) || (self.block[self.rp + 1] == 0xFF)) {
+			self.rp += 1;
+		}
+		if self.rp + 1 >= self.block.len() {
+			self.rp = self.block.len();
+		}
+		self.rp
+	}
+
+	fn get8(&mut self) -> u32 {
+		if self.marker || (self.rp >= self.block.len()) {
+			return 0;
+		}
+		if self.block[self.rp] == 0xFF {
+			let mut tp = self.rp + 1;
+			while (tp < self.block.len()) && (self.block[tp] == 0xFF) {
+				tp += 1;
+			}
+			if (tp < self.block.len()) && (self.block[tp] == 0x00) {
+				self.rp = tp + 1;
+				return 0xFF;
+			}
+			self.rp = tp - 1;  // stay in front of the marker
+			self.marker = true;
+			return 0;
+		}
+		self.rp += 1;
+		self.block[self.rp - 1] as u32
+	}
+
+	pub fn decode(&mut self,st: &mut u8) -> bool {
+		// renormalization
+		while self.a < 0x8000 {
+			self.ct -= 1;
+			if self.ct < 0 {
+				let b = self.get8();
+				self.c = (self.c << 8) | b;
+				self.ct += 8;
+				if self.ct < 0 {
+					self.ct += 1;
+					if self.ct == 0 {
+						self.a = 0x8000;  // two initial bytes read, becomes 0x10000 below
+					}
+				}
+			}
+			self.a <<= 1;
+		}
+		let sv = *st;
+		let (qe,nlps,nmps,switch) = ARITH_STATES[(sv & 0x7F) as usize];
+		let mut mps = sv >> 7;
+		let temp = self.a - qe;
+		self.a = temp;
+		let temp = temp << self.ct;
+		if self.c >= temp {
+			self.c -= temp;
+			if self.a < qe {
+				// conditional exchange, MPS after all
+				self.a = qe;
+				*st = (sv & 0x80) | nmps;
+			}
+			else {
+				self.a = qe;
+				*st = (if switch { (sv & 0x80) ^ 0x80 } else { sv & 0x80 }) | nlps;
+				mps ^= 1;
+			}
+		}
+		else if self.a < 0x8000 {
+			if self.a < qe {
+				// conditional exchange, LPS after all
+				*st = (if switch { (sv & 0x80) ^ 0x80 } else { sv & 0x80 }) | nlps;
+				mps ^= 1;
+			}
+			else {
+				*st = (sv & 0x80) | nmps;
+			}
+		}
+		mps != 0
+	}
+}
+
+// adaptive statistics of an arithmetic coded scan, per table
+struct ArithStats {
+	dc: [[u8; 64]; 4],
+	ac: [[u8; 256]; 4],
+	dc_context: [usize; 3],
+	fixed: u8,  // bin for sign and refinement bits
+	dc_l: [u8; 4],  // conditioning from the DAC marker
+	dc_u: [u8; 4],
+	ac_k: [u8; 4],
+}
+
+impl ArithStats {
+	pub fn new(dc_l: [u8; 4],dc_u: [u8; 4],ac_k: [u8; 4]) -> ArithStats {
+		ArithStats {
+			dc: [[0; 64]; 4],
+			ac: [[0; 256]; 4],
+			dc_context: [0; 3],
+			fixed: ARITH_FIXED,
+			dc_l,
+			dc_u,
+			ac_k,
+		}
+	}
+
+	pub fn reset(&mut self) {
+		self.dc = [[0; 64]; 4];
+		self.ac = [[0; 256]; 4];
+		self.dc_context = [0; 3];
+	}
+}
+
+// DC difference with conditioning (F.1.4.4.1, F.2.4.1)
+fn arith_dc_diff(reader: &mut ArithReader,stats: &mut ArithStats,t: usize,index: usize) -> i32 {
+	let mut st = stats.dc_context[index];
+	if !reader.decode(&mut stats.dc[t][st]) {
+		stats.dc_context[index] = 0;
+		return 0;
+	}
+	let sign = reader.decode(&mut stats.dc[t][st + 1]) as usize;
+	st += 2 + sign;
+	let mut m = 0i32;
+	if reader.decode(&mut stats.dc[t][st]) {
+		m = 1;
+		st = 20;  // X1
+		while reader.decode(&mut stats.dc[t][st]) {
+			m <<= 1;
+			if m == 0x8000 {
+				return 0;
 			}
-		},
-		TYPE_YUV420 => {
-			if (mask & 1) != 0 {
-				unpack_block(reader,&mut coeffs[0..64],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
-				unpack_block(reader,&mut coeffs[64..128],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
-				unpack_block(reader,&mut coeffs[128..192],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
-				unpack_block(reader,&mut coeffs[192..256],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+			st += 1;
+		}
+	}
+	stats.dc_context[index] = if m < ((1 << stats.dc_l[t]) >> 1) {
+		0
+	}
+	else if m > ((1 << stats.dc_u[t]) >> 1) {
+		12 + sign * 4
+	}
+	else {
+		4 + sign * 4
+	};
+	let mut v = m;
+	st += 14;
+	while m > 1 {
+		m >>= 1;
+		if reader.decode(&mut stats.dc[t][st]) {
+			v |= m;
+		}
+	}
+	v += 1;
+	if sign != 0 { -v } else { v }
+}
+
+// AC coefficient magnitude and sign, starting at the bins for coefficient k (F.2.4.2)
+fn arith_ac_value(reader: &mut ArithReader,stats: &mut ArithStats,t: usize,k: usize,mut st: usize) -> i32 {
+	let sign = reader.decode(&mut stats.fixed);
+	st += 2;
+	let mut m = 0i32;
+	if reader.decode(&mut stats.ac[t][st]) {
+		m = 1;
+		if reader.decode(&mut stats.ac[t][st]) {
+			m <<= 1;
+			st = if k <= stats.ac_k[t] as usize { 189 } else { 217 };
+			while reader.decode(&mut stats.ac[t][st]) {
+				m <<= 1;
+				if m == 0x8000 {
+					return 0;
+				}
+				st += 1;
 			}
-			if (mask & 2) != 0 {
-				unpack_block(reader,&mut coeffs[256..320],&dcht[dt[1]],&acht[at[1]],&mut dc[1],start,end,shift,refine,eobrun);
+		}
+	}
+	let mut v = m;
+	st += 14;
+	while m > 1 {
+		m >>= 1;
+		if reader.decode(&mut stats.ac[t][st]) {
+			v |= m;
+		}
+	}
+	v += 1;
+	if sign { -v } else { v }
+}
+
+fn arith_ac_first(reader: &mut ArithReader,stats: &mut ArithStats,coeffs: &mut [i32],t: usize,start: u8,end: u8,shift: u8) {
+	let mut k = start as usize;
+	while k <= end as usize {
+		let mut st = 3 * (k - 1);
+		if reader.decode(&mut stats.ac[t][st]) {  // EOB
+			break;
+		}
+		while !reader.decode(&mut stats.ac[t][st + 1]) {
+			st += 3;
+			k += 1;
+			if k > end as usize {
+				return;
 			}
-			if (mask & 4) != 0 {
-				unpack_block(reader,&mut coeffs[320..384],&dcht[dt[2]],&acht[at[2]],&mut dc[2],start,end,shift,refine,eobrun);
+		}
+		let v = arith_ac_value(reader,stats,t,k,st);
+		coeffs[FOLDING[k] as usize] = v << shift;
+		k += 1;
+	}
+}
+
+fn arith_ac_refine(reader: &mut ArithReader,stats: &mut ArithStats,coeffs: &mut [i32],t: usize,start: u8,end: u8,shift: u8) {
+	// end of block of the previous stage
+	let mut kex = end as usize;
+	while (kex > 0) && (coeffs[FOLDING[kex] as usize] == 0) {
+		kex -= 1;
+	}
+	let mut k = start as usize;
+	while k <= end as usize {
+		let mut st = 3 * (k - 1);
+		if (k > kex) && reader.decode(&mut stats.ac[t][st]) {  // EOB
+			break;
+		}
+		loop {
+			let coeff = &mut coeffs[FOLDING[k] as usize];
+			if *coeff != 0 {
+				// previously nonzero, correction bit
+				if reader.decode(&mut stats.ac[t][st + 2]) {
+					if *coeff < 0 {
+						*coeff -= 1 << shift;
+					}
+					else {
+						*coeff += 1 << shift;
+					}
+				}
+				break;
 			}
-		},
-		TYPE_YUV422 | TYPE_YUV440 => {
-			if (mask & 1) != 0 {
-				unpack_block(reader,&mut coeffs[0..64],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
-				unpack_block(reader,&mut coeffs[64..128],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+			if reader.decode(&mut stats.ac[t][st + 1]) {
+				// newly nonzero
+				*coeff = if reader.decode(&mut stats.fixed) { -1 << shift } else { 1 << shift };
+				break;
 			}
-			if (mask & 2) != 0 {
-				unpack_block(reader,&mut coeffs[128..192],&dcht[dt[1]],&acht[at[1]],&mut dc[1],start,end,shift,refine,eobrun);
+			st += 3;
+			k += 1;
+			if k > end as usize {
+				return;
 			}
-			if (mask & 4) != 0 {
-				unpack_block(reader,&mut coeffs[192..256],&dcht[dt[2]],&acht[at[2]],&mut dc[2],start,end,shift,refine,eobrun);
+		}
+		k += 1;
+	}
+}
+
+#[allow(clippy::too_many_arguments)]
+fn arith_block(reader: &mut ArithReader,stats: &mut ArithStats,coeffs: &mut [i32],index: usize,dt: usize,at: usize,dc: &mut i32,start: u8,end: u8,shift: u8,refine: bool) {
+	if start == 0 {
+		if refine {
+			if reader.decode(&mut stats.fixed) {
+				coeffs[FOLDING[0] as usize] |= 1 << shift;
 			}
-		},
-		TYPE_YUV444 | TYPE_RGB444 => {
-			if (mask & 1) != 0 {
-				unpack_block(reader,&mut coeffs[0..64],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+		}
+		else {
+			*dc += arith_dc_diff(reader,stats,dt,index);
+			coeffs[FOLDING[0] as usize] = *dc << shift;
+		}
+		if end != 0 {
+			// sequential
+			arith_ac_first(reader,stats,coeffs,at,1,end,0);
+		}
+	}
+	else if refine {
+		arith_ac_refine(reader,stats,coeffs,at,start,end,shift);
+	}
+	else {
+		arith_ac_first(reader,stats,coeffs,at,start,end,shift);
+	}
+}
+
+// count down the restart interval, skip over the restart marker and reset the statistics when it expires
+fn handle_arith_restart(reader: &mut ArithReader,stats: &mut ArithStats,dc: &mut [i32],rescnt: &mut usize,resint: usize) {
+	if resint != 0 {
+		*rescnt -= 1;
+		if *rescnt == 0 {
+			let mut tsp = reader.leave();
+			*rescnt = resint;
+			if (tsp + 1 < reader.block.len()) && ((reader.block[tsp + 1] >= 0xD0) && (reader.block[tsp + 1] < 0xD8)) {
+				tsp += 2;
 			}
-			if (mask & 2) != 0 {
-				unpack_block(reader,&mut coeffs[64..128],&dcht[dt[1]],&acht[at[1]],&mut dc[1],start,end,shift,refine,eobrun);
+			dc[0] = 0;
+			dc[1] = 0;
+			dc[2] = 0;
+			stats.reset();
+			reader.enter(tsp);
+		}
+	}
+}
+
+// coefficient offsets of the blocks in a scan, in stream order, together with the component index; also returns the number of blocks per MCU
+#[allow(clippy::too_many_arguments)]
+fn scan_blocks(itype: u16,mask: u8,width: usize,height: usize,mbwidth: usize,mbheight: usize,cpmb: usize,bsize: usize) -> (Vec<(usize,usize)>,usize) {
+	let mut blocks = Vec::new();
+	let (hf,vf) = match itype { TYPE_YUV420 => (2,2), TYPE_YUV422 => (2,1), TYPE_YUV440 => (1,2), _ => (1,1), };
+	if (mask.count_ones() == 1) && (hf * vf > 1) {
+		// non-interleaved scan of a subsampled image: the blocks of one component in raster order
+		let index = mask.trailing_zeros() as usize;
+		let (bwidth,bheight) = if index == 0 { (width.div_ceil(8),height.div_ceil(8)) } else { (mbwidth,mbheight) };
+		for by in 0..bheight {
+			for bx in 0..bwidth {
+				let offset = if index == 0 {
+					((by / vf) * mbwidth + bx / hf) * cpmb + ((by % vf) * hf + bx % hf) * bsize
+				}
+				else {
+					(by * mbwidth + bx) * cpmb + (hf * vf + index - 1) * bsize
+				};
+				blocks.push((index,offset));
 			}
-			if (mask & 4) != 0 {
-				unpack_block(reader,&mut coeffs[128..192],&dcht[dt[2]],&acht[at[2]],&mut dc[2],start,end,shift,refine,eobrun);
+		}
+		return (blocks,1);
+	}
+	let mut per_mcu = 0;
+	for i in 0..mbwidth * mbheight {
+		let mut offset = i * cpmb;
+		per_mcu = 0;
+		for index in 0..3 {
+			let n = if index == 0 { hf * vf } else if itype == TYPE_Y { 0 } else { 1 };
+			for _k in 0..n {
+				if (mask & (1 << index)) != 0 {
+					blocks.push((index,offset));
+					per_mcu += 1;
+				}
+				offset += bsize;
 			}
-		},
-		_ => { },
+		}
 	}
+	(blocks,per_mcu)
+}
+
+// count down the restart interval and skip over the restart marker when it expires
+fn handle_restart(reader: &mut Reader,dc: &mut [i32],eobrun: &mut usize,rescnt: &mut usize,resint: usize) {
 	if resint != 0 {
 		*rescnt -= 1;
 		if *rescnt == 0 {
 			let mut tsp = reader.leave();
-			if (reader.block[tsp] == 0xFF) && ((reader.block[tsp + 1] >= 0xD0) && (reader.block[tsp + 1] < 0xD8)) {
+			*rescnt = resint;
+			if (tsp + 1 < reader.block.len()) && (reader.block[tsp] == 0xFF) && ((reader.block[tsp + 1] >= 0xD0) && (reader.block[tsp + 1] < 0xD8)) {
 				tsp += 2;
-				*rescnt = resint;
 				dc[0] = 0;
 				dc[1] = 0;
 				dc[2] = 0;
+				*eobrun = 0;
 			}
 			reader.enter(tsp);
 		}
 	}
 }
 
+// decode one lossless scan of precision bits (after point transform); every MCU is one sample of each component in the scan (only 1x1 sampling is supported)
+#[allow(clippy::too_many_arguments)]
+fn unpack_lossless(reader: &mut Reader,planes: &mut [Vec<i32>],indices: &[usize],tables: &[Table],width: usize,height: usize,precision: u8,predictor: u8,resint: usize) {
+	let mut rescnt = resint;
+	let mut dummy_dc = [0i32; 3];
+	let mut dummy_eobrun = 0;
+	let mut initial = true;  // first sample of the scan or restart interval
+	let mut first_row = 0;  // first line of the scan or restart interval
+	for y in 0..height {
+		for x in 0..width {
+			for (index,table) in indices.iter().zip(tables.iter()) {
+				let plane = &mut planes[*index];
+				let p = y * width + x;
+				let prediction = if initial {
+					1 << (precision - 1)
+				}
+				else if y == first_row {
+					plane[p - 1]
+				}
+				else if x == 0 {
+					plane[p - width]
+				}
+				else {
+					let ra = plane[p - 1];
+					let rb = plane[p - width];
+					let rc = plane[p - width - 1];
+					match predictor {
+						1 => ra,
+						2 => rb,
+						3 => rc,
+						4 => ra + rb - rc,
+						5 => ra + ((rb - rc) >> 1),
+						6 => rb + ((ra - rc) >> 1),
+						7 => (ra + rb) >> 1,
+						_ => 0,
+					}
+				};
+				let cat = reader.get_code(table);
+				let diff = match cat {
+					0 => 0,
+					16 => 32768,
+					_ => make_coeff(cat,reader.getn(cat as usize) as isize),
+				};
+				plane[p] = (prediction + diff) & ((1 << precision) - 1);  // modulo 2^precision, so corrupt differences stay in range
+			}
+			initial = false;
+			handle_restart(reader,&mut dummy_dc,&mut dummy_eobrun,&mut rescnt,resint);
+			if (resint != 0) && (rescnt == resint) {
+				initial = true;
+				first_row = if x + 1 == width { y + 1 } else { y };
+			}
+		}
+	}
+}
+
 fn partial_idct(out: &mut [i32],inp: &[i32]) {
 	for i in 0..8 {
 		let x3 = inp[i];
@@ -674,112 +1392,425 @@ fn convert_block(block: &mut [i32],qtable: &[i32]) {
 	unswizzle_transpose(block,&temp3);
 }
 
-fn convert_blocks(coeffs: &mut [i32],count: usize,pattern: u16,qtable: &[[i32; 64]],qt: &[usize; 3]) {
-	let mut curp = pattern;
-	for i in 0..count {
-		if (curp & 3) == 3 {
-			curp = pattern;
-		}
-		convert_block(&mut coeffs[i * 64..i * 64 + 64],&qtable[qt[(curp & 3) as usize]]);
-		curp >>= 2;
-	}
+// one pass of the accurate integer IDCT over 8 values spaced step apart, results descaled by bits
+fn accurate_idct_1d(out: &mut [i32],inp: &[i32],step: usize,bits: i32) {
+	let round = 1 << (bits - 1);
+	let z2 = inp[2 * step];
+	let z3 = inp[6 * step];
+	let z1 = (z2 + z3) * ACC_0_541196100;
+	let tmp2 = z1 - z3 * ACC_1_847759065;
+	let tmp3 = z1 + z2 * ACC_0_765366865;
+	let tmp0 = (inp[0] + inp[4 * step]) << ACC_BITS;
+	let tmp1 = (inp[0] - inp[4 * step]) << ACC_BITS;
+	let tmp10 = tmp0 + tmp3;
+	let tmp13 = tmp0 - tmp3;
+	let tmp11 = tmp1 + tmp2;
+	let tmp12 = tmp1 - tmp2;
+
+	let tmp0 = inp[7 * step];
+	let tmp1 = inp[5 * step];
+	let tmp2 = inp[3 * step];
+	let tmp3 = inp[step];
+	let z5 = (tmp0 + tmp1 + tmp2 + tmp3) * ACC_1_175875602;
+	let z1 = -(tmp0 + tmp3) * ACC_0_899976223;
+	let z2 = -(tmp1 + tmp2) * ACC_2_562915447;
+	let z3 = z5 - (tmp0 + tmp2) * ACC_1_961570560;
+	let z4 = z5 - (tmp1 + tmp3) * ACC_0_390180644;
+	let tmp0 = tmp0 * ACC_0_298631336 + z1 + z3;
+	let tmp1 = tmp1 * ACC_2_053119869 + z2 + z4;
+	let tmp2 = tmp2 * ACC_3_072711026 + z2 + z3;
+	let tmp3 = tmp3 * ACC_1_501321110 + z1 + z4;
+
+	out[0] = (tmp10 + tmp3 + round) >> bits;
+	out[7 * step] = (tmp10 - tmp3 + round) >> bits;
+	out[step] = (tmp11 + tmp2 + round) >> bits;
+	out[6 * step] = (tmp11 - tmp2 + round) >> bits;
+	out[2 * step] = (tmp12 + tmp1 + round) >> bits;
+	out[5 * step] = (tmp12 - tmp1 + round) >> bits;
+	out[3 * step] = (tmp13 + tmp0 + round) >> bits;
+	out[4 * step] = (tmp13 - tmp0 + round) >> bits;
 }
 
-fn clamp<T : std::cmp::PartialOrd>(v: T,min: T,max: T) -> T {
-	if v < min {
-		return min;
+// accurate 13-bit integer IDCT (islow style)
+fn convert_block_accurate(block: &mut [i32],qtable: &[i32]) {
+	let mut temp0 = [0i32; 64];
+	for v in 0..8 {
+		for u in 0..8 {
+			let i = FOLDED_ROW[v] * 8 + u;
+			temp0[v * 8 + u] = block[i] * qtable[i];
+		}
 	}
-	else if v > max {
-		return max;
+	let mut temp1 = [0i32; 64];
+	for u in 0..8 {
+		accurate_idct_1d(&mut temp1[u..],&temp0[u..],8,ACC_BITS - ACC_PASS1_BITS);
+	}
+	for y in 0..8 {
+		accurate_idct_1d(&mut block[y * 8..y * 8 + 8],&temp1[y * 8..y * 8 + 8],1,ACC_BITS + ACC_PASS1_BITS + 3);
 	}
-	v
+}
+
+// float IDCT, n = 8 for the reference IDCT on a folded block, n = 4, 2 or 1 for scaled decoding on a reduced block; leaves
+// n x n samples in the block
+fn convert_block_float(block: &mut [i32],qtable: &[i32],n: usize) {
+	if n == 1 {  // DC only
+		let dc = block[0] * qtable[FOLDED_ROW[0] * 8];
+		block[0] = (dc + 4) >> 3;
+		return;
+	}
+	let table: &[f32] = match n {
+		8 => &IDCT8,
+		4 => &IDCT4,
+		_ => &IDCT2,
+	};
+	let mut temp0 = [0f32; 64];
+	for v in 0..n {
+		for u in 0..n {
+			let i = if n == 8 { FOLDED_ROW[v] * 8 + u } else { v * n + u };
+			temp0[v * n + u] = (block[i] * qtable[FOLDED_ROW[v] * 8 + u]) as f32;
+		}
+	}
+	let mut temp1 = [0f32; 64];
+	for v in 0..n {
+		for x in 0..n {
+			let mut sum = 0.0;
+			for u in 0..n {
+				sum += table[x * n + u] * temp0[v * n + u];
+			}
+			temp1[v * n + x] = sum;
+		}
+	}
+	for y in 0..n {
+		for x in 0..n {
+			let mut sum = 0.0;
+			for v in 0..n {
+				sum += table[y * n + v] * temp1[v * n + x];
+			}
+			block[y * n + x] = sum.round() as i32;
+		}
+	}
+}
+
+// keep the n x n lowest frequencies of a folded block, in natural order
+fn reduce_block(src: &[i32],dst: &mut [i32],n: usize) {
+	for v in 0..n {
+		for u in 0..n {
+			dst[v * n + u] = src[FOLDED_ROW[v] * 8 + u];
+		}
+	}
+}
+
+// IDCT of all blocks: 8x8 blocks at full size, n x n reduced blocks for scaled decoding
+fn convert_blocks(coeffs: &mut [i32],count: usize,pattern: u16,qtable: &[[i32; 64]],qt: &[usize; 3],bshift: usize,idct: Idct) {
+	let bsize = 64 >> (bshift * 2);
+	let mut curp = pattern;
+	for i in 0..count {
+		if (curp & 3) == 3 {
+			curp = pattern;
+		}
+		let block = &mut coeffs[i * bsize..(i + 1) * bsize];
+		let qtable = &qtable[qt[(curp & 3) as usize]];
+		if bshift != 0 {
+			convert_block_float(block,qtable,8 >> bshift);
+		}
+		else {
+			match idct {
+				Idct::Fast => { convert_block(block,qtable); },
+				Idct::Accurate => { convert_block_accurate(block,qtable); },
+				Idct::Float => { convert_block_float(block,qtable,8); },
+			}
+		}
+		curp >>= 2;
+	}
+}
+
+fn clamp<T : std::cmp::PartialOrd>(v: T,min: T,max: T) -> T {
+	if v < min {
+		return min;
+	}
+	else if v > max {
+		return max;
+	}
+	v
 }
 
 fn draw_rgb(image: &mut Image,px: usize,py: usize,r: i32,g: i32,b: i32) {
 	image.data[py * image.width + px] = 0xFF000000 | ((clamp(r,0,255) as u32) << 16) | ((clamp(g,0,255) as u32) << 8) | (clamp(b,0,255) as u32);
 }
 
+fn yuv_to_rgb(y: i32,u: i32,v: i32) -> (i32,i32,i32) {
+	let r = y + ((91881 * v + 32768) >> 16);  // 1.40200 * Cr
+	let g = y + ((-22554 * u - 46802 * v + 32768) >> 16);  // 0.34414 * Cb + 0.71414 * Cr
+	let b = y + ((116130 * u + 32768) >> 16);  // 1.77200 * Cb
+	(r,g,b)
+}
+
 fn draw_yuv(image: &mut Image,px: usize,py: usize,y: i32,u: i32,v: i32) {
-	let r = ((y << 8) + 359 * v) >> 8;
-	let g = ((y << 8) - 88 * u - 183 * v) >> 8;
-	let b = ((y << 8) + 454 * u) >> 8;
+	let (r,g,b) = yuv_to_rgb(y,u,v);
 	draw_rgb(image,px,py,r,g,b);
 }
 
-fn draw_macroblock_y(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32]) {
+// the draw functions take macroblocks of bs x bs sample blocks, bs = 8 at full size
+fn draw_macroblock_y(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,bs: usize,coeffs: &[i32]) {
 	for i in 0..height {
 		for k in 0..width {
-			draw_yuv(image,x0 + k,y0 + i,(coeffs[i * 8 + k] + 128) as i32,0,0);
+			draw_yuv(image,x0 + k,y0 + i,coeffs[i * bs + k] + 128,0,0);
 		}
 	}
 }
 
-fn draw_macroblock_yuv420(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32]) {
+fn draw_macroblock_yuv420(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,bs: usize,coeffs: &[i32]) {
+	let bits = bs.trailing_zeros();
+	let bsize = bs * bs;
 	for i in 0..height {
 		for k in 0..width {
-			let by = (i >> 3) * 2 + (k >> 3);
-			let si = i & 7;
-			let sk = k & 7;
-			let y = coeffs[by * 64 + si * 8 + sk] + 128;
+			let by = (i >> bits) * 2 + (k >> bits);
+			let si = i & (bs - 1);
+			let sk = k & (bs - 1);
+			let y = coeffs[by * bsize + si * bs + sk] + 128;
 			let hi = i >> 1;
 			let hk = k >> 1;
-			let u = coeffs[256 + hi * 8 + hk];
-			let v = coeffs[320 + hi * 8 + hk];
+			let u = coeffs[4 * bsize + hi * bs + hk];
+			let v = coeffs[5 * bsize + hi * bs + hk];
 			draw_yuv(image,x0 + k,y0 + i,y as i32,u as i32,v as i32);
 		}
 	}
 }
 
-fn draw_macroblock_yuv422(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32]) {
+fn draw_macroblock_yuv422(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,bs: usize,coeffs: &[i32]) {
+	let bits = bs.trailing_zeros();
+	let bsize = bs * bs;
 	for i in 0..height {
 		for k in 0..width {
-			let by = k >> 3;
-			let sk = k & 7;
-			let y = coeffs[by * 64 + i * 8 + sk] + 128;
+			let by = k >> bits;
+			let sk = k & (bs - 1);
+			let y = coeffs[by * bsize + i * bs + sk] + 128;
 			let hk = k >> 1;
-			let u = coeffs[128 + i * 8 + hk];
-			let v = coeffs[192 + i * 8 + hk];
+			let u = coeffs[2 * bsize + i * bs + hk];
+			let v = coeffs[3 * bsize + i * bs + hk];
 			draw_yuv(image,x0 + k,y0 + i,y as i32,u as i32,v as i32);
 		}
 	}
 }
 
-fn draw_macroblock_yuv440(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32]) {
+fn draw_macroblock_yuv440(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,bs: usize,coeffs: &[i32]) {
+	let bits = bs.trailing_zeros();
+	let bsize = bs * bs;
 	for i in 0..height {
 		for k in 0..width {
-			let by = i >> 3;
-			let si = k & 7;
-			let y = coeffs[by * 64 + si * 8 + k] + 128;
+			let by = i >> bits;
+			let si = i & (bs - 1);
+			let y = coeffs[by * bsize + si * bs + k] + 128;
 			let hi = i >> 1;
-			let u = coeffs[128 + hi * 8 + k];
-			let v = coeffs[192 + hi * 8 + k];
+			let u = coeffs[2 * bsize + hi * bs + k];
+			let v = coeffs[3 * bsize + hi * bs + k];
 			draw_yuv(image,x0 + k,y0 + i,y as i32,u as i32,v as i32);
 		}
 	}
 }
 
-fn draw_macroblock_yuv444(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32]) {
+fn draw_macroblock_yuv444(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,bs: usize,coeffs: &[i32]) {
+	let bsize = bs * bs;
 	for i in 0..height {
 		for k in 0..width {
-			let y = coeffs[i * 8 + k] + 128;
-			let u = coeffs[64 + i * 8 + k];
-			let v = coeffs[128 + i * 8 + k];
+			let y = coeffs[i * bs + k] + 128;
+			let u = coeffs[bsize + i * bs + k];
+			let v = coeffs[2 * bsize + i * bs + k];
 			draw_yuv(image,x0 + k,y0 + i,y as i32,u as i32,v as i32);
 		}
 	}
 }
 
-fn draw_macroblock_rgb444(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32]) {
+fn draw_macroblock_rgb444(image: &mut Image,x0: usize,y0: usize,width: usize,height: usize,bs: usize,coeffs: &[i32]) {
+	let bsize = bs * bs;
 	for i in 0..height {
 		for k in 0..width {
-			let r = coeffs[i * 8 + k] + 128;
-			let g = coeffs[64 + i * 8 + k] + 128;
-			let b = coeffs[128 + i * 8 + k] + 128;
+			let r = coeffs[i * bs + k] + 128;
+			let g = coeffs[bsize + i * bs + k] + 128;
+			let b = coeffs[2 * bsize + i * bs + k] + 128;
 			draw_rgb(image,x0 + k,y0 + i,r as i32,g as i32,b as i32);
 		}
 	}
 }
 
+// collect one block of each macroblock into a plane of mbwidth x mbheight blocks of bs x bs samples
+fn gather_plane(coeffs: &[i32],cpmb: usize,offset: usize,mbwidth: usize,mbheight: usize,bs: usize) -> Vec<i32> {
+	let stride = mbwidth * bs;
+	let mut plane = vec![0i32; stride * mbheight * bs];
+	for i in 0..mbheight {
+		for k in 0..mbwidth {
+			let block = &coeffs[(i * mbwidth + k) * cpmb + offset..];
+			for y in 0..bs {
+				for x in 0..bs {
+					plane[(i * bs + y) * stride + k * bs + x] = block[y * bs + x];
+				}
+			}
+		}
+	}
+	plane
+}
+
+// triangle filter upsampling by 2 horizontally, neighbours beyond the valid width are clamped
+fn upsample_h2(plane: &[i32],stride: usize,width: usize,height: usize) -> Vec<i32> {
+	let mut result = vec![0i32; stride * 2 * height];
+	for y in 0..height {
+		let row = &plane[y * stride..];
+		let out = &mut result[y * stride * 2..];
+		for x in 0..width {
+			let left = row[if x > 0 { x - 1 } else { x }];
+			let right = row[if x + 1 < width { x + 1 } else { x }];
+			out[x * 2] = (3 * row[x] + left + 1) >> 2;
+			out[x * 2 + 1] = (3 * row[x] + right + 2) >> 2;
+		}
+	}
+	result
+}
+
+// triangle filter upsampling by 2 vertically, neighbours beyond the valid height are clamped
+fn upsample_v2(plane: &[i32],stride: usize,width: usize,height: usize) -> Vec<i32> {
+	let mut result = vec![0i32; stride * height * 2];
+	for y in 0..height {
+		let above = if y > 0 { y - 1 } else { y };
+		let below = if y + 1 < height { y + 1 } else { y };
+		for x in 0..width {
+			let c = plane[y * stride + x];
+			result[y * 2 * stride + x] = (3 * c + plane[above * stride + x] + 1) >> 2;
+			result[(y * 2 + 1) * stride + x] = (3 * c + plane[below * stride + x] + 2) >> 2;
+		}
+	}
+	result
+}
+
+// triangle filter upsampling by 2 in both directions
+fn upsample_h2v2(plane: &[i32],stride: usize,width: usize,height: usize) -> Vec<i32> {
+	let mut result = vec![0i32; stride * 2 * height * 2];
+	let mut colsum = vec![0i32; width];
+	for y in 0..height {
+		let above = if y > 0 { y - 1 } else { y };
+		let below = if y + 1 < height { y + 1 } else { y };
+		for (v,near) in [above,below].iter().enumerate() {
+			for x in 0..width {
+				colsum[x] = 3 * plane[y * stride + x] + plane[near * stride + x];
+			}
+			let out = &mut result[(y * 2 + v) * stride * 2..];
+			for x in 0..width {
+				let left = colsum[if x > 0 { x - 1 } else { x }];
+				let right = colsum[if x + 1 < width { x + 1 } else { x }];
+				out[x * 2] = (3 * colsum[x] + left + 8) >> 4;
+				out[x * 2 + 1] = (3 * colsum[x] + right + 7) >> 4;
+			}
+		}
+	}
+	result
+}
+
+// draw subsampled images with fancy upsampling of the chroma planes, continuous across macroblocks
+#[allow(clippy::too_many_arguments)]
+fn draw_fancy(image: &mut Image,coeffs: &[i32],itype: u16,mbwidth: usize,mbheight: usize,mbw: usize,mbh: usize,bshift: usize) {
+	let bs = 8 >> bshift;
+	let bsize = bs * bs;
+	let bits = 3 - bshift;
+	let bmask = (1 << bits) - 1;
+	let (cpmb,uoffset,voffset) = match itype {
+		TYPE_YUV420 => (6 * bsize,4 * bsize,5 * bsize),
+		_ => (4 * bsize,2 * bsize,3 * bsize),
+	};
+	let stride = mbwidth * bs;
+	let height = mbheight * bs;
+	let uplane = gather_plane(coeffs,cpmb,uoffset,mbwidth,mbheight,bs);
+	let vplane = gather_plane(coeffs,cpmb,voffset,mbwidth,mbheight,bs);
+	let (uplane,vplane,ustride) = match itype {
+		TYPE_YUV420 => {
+			let width = image.width.div_ceil(2);
+			let height = image.height.div_ceil(2);
+			(upsample_h2v2(&uplane,stride,width,height),upsample_h2v2(&vplane,stride,width,height),stride * 2)
+		},
+		TYPE_YUV422 => {
+			let width = image.width.div_ceil(2);
+			(upsample_h2(&uplane,stride,width,height),upsample_h2(&vplane,stride,width,height),stride * 2)
+		},
+		_ => {
+			let height = image.height.div_ceil(2);
+			(upsample_v2(&uplane,stride,image.width,height),upsample_v2(&vplane,stride,image.width,height),stride)
+		},
+	};
+	for py in 0..image.height {
+		let i = py / mbh;
+		let ly = py % mbh;
+		for px in 0..image.width {
+			let k = px / mbw;
+			let lx = px % mbw;
+			let by = match itype {
+				TYPE_YUV420 => (ly >> bits) * 2 + (lx >> bits),
+				TYPE_YUV422 => lx >> bits,
+				_ => ly >> bits,
+			};
+			let y = coeffs[(i * mbwidth + k) * cpmb + by * bsize + (ly & bmask) * bs + (lx & bmask)] + 128;
+			let u = uplane[py * ustride + px];
+			let v = vplane[py * ustride + px];
+			draw_yuv(image,px,py,y,u,v);
+		}
+	}
+}
+
+// draw a 12-bit image of any type, with nearest or fancy chroma upsampling
+fn draw16(image: &mut Image16,coeffs: &[i32],itype: u16,mbwidth: usize,mbheight: usize,bshift: usize,fancy: bool) {
+	let bs = 8 >> bshift;
+	let bsize = bs * bs;
+	let (hf,vf,cpmb) = match itype {
+		TYPE_Y => (1,1,bsize),
+		TYPE_YUV420 => (2,2,6 * bsize),
+		TYPE_YUV422 => (2,1,4 * bsize),
+		TYPE_YUV440 => (1,2,4 * bsize),
+		_ => (1,1,3 * bsize),
+	};
+	let uoffset = hf * vf * bsize;
+	let mut planes = None;
+	if fancy && (hf * vf > 1) {
+		let stride = mbwidth * bs;
+		let uplane = gather_plane(coeffs,cpmb,uoffset,mbwidth,mbheight,bs);
+		let vplane = gather_plane(coeffs,cpmb,uoffset + bsize,mbwidth,mbheight,bs);
+		let width = image.width.div_ceil(hf);
+		let height = image.height.div_ceil(vf);
+		planes = Some(match itype {
+			TYPE_YUV420 => (upsample_h2v2(&uplane,stride,width,height),upsample_h2v2(&vplane,stride,width,height),stride * 2),
+			TYPE_YUV422 => (upsample_h2(&uplane,stride,width,mbheight * bs),upsample_h2(&vplane,stride,width,mbheight * bs),stride * 2),
+			_ => (upsample_v2(&uplane,stride,width,height),upsample_v2(&vplane,stride,width,height),stride),
+		});
+	}
+	for py in 0..image.height {
+		for px in 0..image.width {
+			let ly = py % (vf * bs);
+			let lx = px % (hf * bs);
+			let mb = ((py / (vf * bs)) * mbwidth + px / (hf * bs)) * cpmb;
+			let c0 = coeffs[mb + ((ly / bs) * hf + lx / bs) * bsize + (ly % bs) * bs + lx % bs];
+			let (r,g,b) = if itype == TYPE_Y {
+				(c0 + 2048,c0 + 2048,c0 + 2048)
+			}
+			else {
+				let (c1,c2) = if let Some((uplane,vplane,ustride)) = &planes {
+					(uplane[py * ustride + px],vplane[py * ustride + px])
+				}
+				else {
+					let cy = (py % (vf * bs)) / vf;
+					let cx = (px % (hf * bs)) / hf;
+					(coeffs[mb + uoffset + cy * bs + cx],coeffs[mb + uoffset + bsize + cy * bs + cx])
+				};
+				if itype == TYPE_RGB444 {
+					(c0 + 2048,c1 + 2048,c2 + 2048)
+				}
+				else {
+					yuv_to_rgb(c0 + 2048,c1,c2)
+				}
+			};
+			let widen = |c: i32| {
+				let c = clamp(c,0,4095) as u64;
+				(c << 4) | (c >> 8)
+			};
+			image.data[py * image.width + px] = 0xFFFF000000000000 | (widen(r) << 32) | (widen(g) << 16) | widen(b);
+		}
+	}
+}
+
 pub fn test(src: &[u8]) -> Option<(usize,usize)> {
 	let mut sp = 0;
 	if from_be16(&src[sp..sp + 2]) != 0xFFD8 {
@@ -790,7 +1821,7 @@ pub fn test(src: &[u8]) -> Option<(usize,usize)> {
 		let marker = from_be16(&src[sp..sp + 2]);
 		let length = from_be16(&src[sp + 2..sp + 4]) as usize;
 		match marker {
-			0xFFC0 | 0xFFC1 | 0xFFC2 => {
+			0xFFC0 | 0xFFC1 | 0xFFC2 | 0xFFC3 | 0xFFC9 | 0xFFCA => {
 				let width = from_be16(&src[sp + 5..sp + 7]) as usize;
 				let height = from_be16(&src[sp + 7..sp + 9]) as usize;
 				let components = src[sp + 9];
@@ -806,8 +1837,287 @@ pub fn test(src: &[u8]) -> Option<(usize,usize)> {
 	None
 }
 
+// output size relative to the full image
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Scale {
+	#[default]
+	Full = 0,
+	Half = 1,
+	Quarter = 2,
+	Eighth = 3,
+}
+
+// IDCT precision; 12-bit JPEGs always use Float, the fixed point IDCTs are tuned for 8-bit samples
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Idct {
+	#[default]
+	Fast,  // 8-bit fixed point
+	Accurate,  // 13-bit fixed point, islow style
+	Float,  // floating point reference
+}
+
+// chroma upsampling for 4:2:0, 4:2:2 and 4:4:0 images
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Upsampling {
+	#[default]
+	Fancy,  // triangle filter, continuous across macroblocks
+	Nearest,  // replicate chroma samples, faster
+}
+
+// decoder settings
+#[derive(Copy,Clone,Default)]
+pub struct DecodeOptions {
+	pub apply_orientation: bool,  // rotate/flip the result upright according to the EXIF orientation tag
+	pub scale: Scale,  // decode directly at 1/2, 1/4 or 1/8 size using reduced IDCTs
+	pub idct: Idct,  // IDCT precision for full size decoding of 8-bit JPEGs, ignored for 12-bit JPEGs
+	pub upsampling: Upsampling,  // chroma upsampling
+}
+
+// EXIF orientation tag (0x0112) as stored in the file, 1 = upright
+pub fn orientation(src: &[u8]) -> Option<u16> {
+	let exif = find_exif(src)?;
+	let ifd0 = exif.first_ifd()?;
+	for entry in exif.entries(ifd0) {
+		if entry.tag == 0x0112 {
+			return exif.value(&entry).map(|o| o as u16);
+		}
+	}
+	None
+}
+
+// physical resolution in dots per inch, from the JFIF density or else the EXIF resolution tags; this is the resolution of the
+// stored image, so divide it by the scale factor of a scaled decode and swap it when orientation 5..=8 is applied
+pub fn dpi(src: &[u8]) -> Option<(f32,f32)> {
+	if (src.len() < 4) || (from_be16(&src[0..2]) != 0xFFD8) {
+		return None;
+	}
+	let mut sp = 2;
+	while sp + 4 <= src.len() {
+		let marker = from_be16(&src[sp..sp + 2]);
+		let length = from_be16(&src[sp + 2..sp + 4]) as usize;
+		match marker {
+			0xFFE0 if (sp + 16 <= src.len()) && (&src[sp + 4..sp + 9] == b"JFIF\0") => {
+				let x = from_be16(&src[sp + 12..sp + 14]) as f32;
+				let y = from_be16(&src[sp + 14..sp + 16]) as f32;
+				if (x > 0.0) && (y > 0.0) {
+					match src[sp + 11] {
+						1 => { return Some((x,y)); },  // dots per inch
+						2 => { return Some((x * 2.54,y * 2.54)); },  // dots per cm
+						_ => { },  // aspect ratio only
+					}
+				}
+			},
+			0xFFDA | 0xFFD9 => {
+				break;
+			},
+			_ => { },
+		}
+		sp += length + 2;
+	}
+	let exif = find_exif(src)?;
+	let ifd0 = exif.first_ifd()?;
+	let mut x = None;
+	let mut y = None;
+	let mut unit = 2;
+	for entry in exif.entries(ifd0) {
+		match entry.tag {
+			0x011A => { x = exif.rational(&entry); },
+			0x011B => { y = exif.rational(&entry); },
+			0x0128 => { unit = exif.value(&entry).unwrap_or(2); },
+			_ => { },
+		}
+	}
+	let scale = match unit {
+		2 => 1.0,  // inch
+		3 => 2.54,  // cm
+		_ => { return None; },
+	};
+	match (x,y) {
+		(Some(x),Some(y)) if (x > 0.0) && (y > 0.0) => Some((x * scale,y * scale)),
+		_ => None,
+	}
+}
+
+// decode the thumbnail stored in EXIF IFD1 (JPEG or uncompressed RGB), without touching the main image
+pub fn thumbnail(src: &[u8]) -> Option<Image> {
+	let exif = find_exif(src)?;
+	let ifd1 = exif.next_ifd(exif.first_ifd()?)?;
+	let mut compression = 6;
+	let mut width = 0usize;
+	let mut height = 0usize;
+	let mut photometric = 2;
+	let mut samples = 3;
+	let mut bits = vec![8];
+	let mut jpeg_offset = 0usize;
+	let mut jpeg_length = 0usize;
+	let mut strip_offsets = Vec::new();
+	let mut strip_counts = Vec::new();
+	for entry in exif.entries(ifd1) {
+		match entry.tag {
+			0x0100 => { width = exif.value(&entry)? as usize; },  // image width
+			0x0101 => { height = exif.value(&entry)? as usize; },  // image height
+			0x0102 => { bits = exif.values(&entry); },  // bits per sample
+			0x0103 => { compression = exif.value(&entry)?; },  // compression
+			0x0106 => { photometric = exif.value(&entry)?; },  // photometric interpretation
+			0x0111 => { strip_offsets = exif.values(&entry); },  // strip offsets
+			0x0115 => { samples = exif.value(&entry)?; },  // samples per pixel
+			0x0117 => { strip_counts = exif.values(&entry); },  // strip byte counts
+			0x0201 => { jpeg_offset = exif.value(&entry)? as usize; },  // JPEG interchange format
+			0x0202 => { jpeg_length = exif.value(&entry)? as usize; },  // JPEG interchange format length
+			_ => { },
+		}
+	}
+	if (jpeg_offset != 0) && (jpeg_length != 0) {
+		if jpeg_offset + jpeg_length > exif.block.len() {
+			return None;
+		}
+		return decode(&exif.block[jpeg_offset..jpeg_offset + jpeg_length]).ok();
+	}
+	if (compression != 1) || (photometric != 2) || (samples != 3) || bits.iter().any(|b| *b != 8) || (width == 0) || (height == 0) || (strip_offsets.len() != strip_counts.len()) {
+		return None;
+	}
+	let mut data = Vec::new();
+	for (offset,count) in strip_offsets.iter().zip(strip_counts.iter()) {
+		let offset = *offset as usize;
+		let count = *count as usize;
+		if offset + count > exif.block.len() {
+			return None;
+		}
+		data.extend_from_slice(&exif.block[offset..offset + count]);
+	}
+	if data.len() < width * height * 3 {
+		return None;
+	}
+	let mut image = Image::new(width,height);
+	for i in 0..width * height {
+		image.data[i] = 0xFF000000 | ((data[i * 3] as u32) << 16) | ((data[i * 3 + 1] as u32) << 8) | (data[i * 3 + 2] as u32);
+	}
+	Some(image)
+}
+
 pub fn decode(src: &[u8]) -> Result<Image,String> {
-	if from_be16(&src[0..2]) != 0xFFD8 {
+	decode_with_options(src,&DecodeOptions::default())
+}
+
+pub fn decode_with_options(src: &[u8],options: &DecodeOptions) -> Result<Image,String> {
+	match decode_pixels(src,options,false)? {
+		Decoded::Image(image) => Ok(image),
+		Decoded::Image16(image) => Ok(image.to_image()),
+		Decoded::Coefficients(_) => Err("Invalid JPEG".to_string()),
+	}
+}
+
+// decode to 16 bits per channel, keeping the full precision of 12-bit JPEGs
+pub fn decode16(src: &[u8]) -> Result<Image16,String> {
+	decode16_with_options(src,&DecodeOptions::default())
+}
+
+pub fn decode16_with_options(src: &[u8],options: &DecodeOptions) -> Result<Image16,String> {
+	match decode_pixels(src,options,false)? {
+		Decoded::Image(image) => Ok(Image16::from_image(&image)),
+		Decoded::Image16(image) => Ok(image),
+		Decoded::Coefficients(_) => Err("Invalid JPEG".to_string()),
+	}
+}
+
+// decoded pixels, 8-bit JPEGs produce an Image and 12-bit JPEGs an Image16; raw decoding stops at the quantized coefficients
+enum Decoded {
+	Image(Image),
+	Image16(Image16),
+	Coefficients(JpegCoefficients),
+}
+
+// one component of a JpegCoefficients
+#[derive(Clone)]
+pub struct JpegComponent {
+	pub id: u8,
+	pub hsamp: usize,  // horizontal sampling factor
+	pub vsamp: usize,  // vertical sampling factor
+	pub qtable: [u16; 64],  // quantization table in natural order
+	pub bwidth: usize,  // number of blocks, padded to whole MCUs
+	pub bheight: usize,
+	pub blocks: Vec<[i32; 64]>,  // quantized coefficients in natural order, bwidth x bheight blocks
+}
+
+// quantized DCT coefficients of an 8-bit JPEG, with its APPn and COM segments
+#[derive(Clone)]
+pub struct JpegCoefficients {
+	pub width: usize,
+	pub height: usize,
+	pub components: Vec<JpegComponent>,  // 1 (grayscale) or 3 (YCbCr)
+	pub markers: Vec<Vec<u8>>,  // complete segments, including the marker and length
+}
+
+// read the quantized coefficients and quantization tables without IDCT
+pub fn read_coefficients(src: &[u8]) -> Result<JpegCoefficients,String> {
+	match decode_pixels(src,&DecodeOptions::default(),true)? {
+		Decoded::Coefficients(coefficients) => Ok(coefficients),
+		_ => Err("Unsupported JPEG".to_string()),
+	}
+}
+
+// write a JPEG from coefficients; quality, subsampling and dpi of the options are not used, the markers keep the resolution and an
+// ICC profile replaces the one in the markers
+pub fn write_coefficients(coefficients: &JpegCoefficients,options: &EncodeOptions) -> Result<Vec<u8>,String> {
+	let width = coefficients.width;
+	let height = coefficients.height;
+	if (width == 0) || (height == 0) || (width > 65535) || (height > 65535) {
+		return Err("Invalid image size".to_string());
+	}
+	if (coefficients.components.len() != 1) && (coefficients.components.len() != 3) {
+		return Err("Invalid coefficients".to_string());
+	}
+	let hmax = coefficients.components.iter().map(|c| c.hsamp).max().unwrap_or(1);
+	let vmax = coefficients.components.iter().map(|c| c.vsamp).max().unwrap_or(1);
+	let mut qtables: Vec<[u8; 64]> = Vec::new();
+	let mut components = Vec::new();
+	for c in coefficients.components.iter() {
+		if !(1..=4).contains(&c.hsamp) || !(1..=4).contains(&c.vsamp) || (c.bwidth != width.div_ceil(8 * hmax) * c.hsamp) || (c.bheight != height.div_ceil(8 * vmax) * c.vsamp) || (c.blocks.len() != c.bwidth * c.bheight) {
+			return Err("Invalid coefficients".to_string());
+		}
+		let mut qtable = [0u8; 64];
+		for (q,value) in qtable.iter_mut().zip(c.qtable.iter()) {
+			if (*value == 0) || (*value > 255) {
+				return Err("Invalid quantization table".to_string());
+			}
+			*q = *value as u8;
+		}
+		// 8-bit coefficient ranges, so DC differences and AC values fit the huffman categories
+		if c.blocks.iter().any(|block| !(-1024..=1023).contains(&block[0]) || block[1..].iter().any(|v| v.abs() > 1023)) {
+			return Err("Coefficient out of range".to_string());
+		}
+		let tq = match qtables.iter().position(|t| *t == qtable) {
+			Some(tq) => tq,
+			None => {
+				qtables.push(qtable);
+				qtables.len() - 1
+			},
+		};
+		components.push(EncodeComponent {
+			id: c.id,
+			samp: ((c.hsamp << 4) | c.vsamp) as u8,
+			tq,
+			bwidth: c.bwidth,
+			bheight: c.bheight,
+			cwidth: (width * c.hsamp).div_ceil(hmax).div_ceil(8),
+			cheight: (height * c.vsamp).div_ceil(vmax).div_ceil(8),
+			blocks: c.blocks.clone(),
+		});
+	}
+	let mut markers = coefficients.markers.clone();
+	if let Some(profile) = &options.icc_profile {
+		if profile.len() > 255 * ICC_CHUNK {
+			return Err("ICC profile too large".to_string());
+		}
+		// replace the profile that came with the coefficients
+		markers.retain(|m| (m.len() < 16) || (m[0..2] != [0xFF,0xE2]) || (&m[4..16] != ICC_SIGNATURE));
+		markers.extend(icc_segments(profile));
+	}
+	write_jpeg(width,height,&qtables,&components,&markers,options)
+}
+
+fn decode_pixels(src: &[u8],options: &DecodeOptions,raw: bool) -> Result<Decoded,String> {
+	if (src.len() < 4) || (from_be16(&src[0..2]) != 0xFFD8) {
 		return Err("Invalid JPEG".to_string());
 	}
 	let mut qtable = [[0i32; 64]; 4];
@@ -823,6 +2133,8 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 	#[allow(unused_assignments)]
 	let mut itype = 0;  // image type
 	#[allow(unused_assignments)]
+	let mut precision = 8;
+	#[allow(unused_assignments)]
 	let mut mbtotal = 0;  // total number of macroblocks
 	#[allow(unused_assignments)]
 	let mut mbwidth = 0;
@@ -830,19 +2142,39 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 	let mut mbheight = 0;
 	#[allow(unused_assignments)]
 	let mut cpmb = 0;
+	let bshift = options.scale as usize;  // log2 of the scale factor
+	let bs = 8 >> bshift;  // samples per block row in the output
+	let mut reduced = false;  // only the bs x bs lowest frequencies of each block are stored
 	let mut coeffs: Vec<i32> = Vec::new();  // the coefficients
 	#[allow(unused_assignments)]
 	let mut resint = 0;
+	let mut arithmetic = false;
+	let mut dc_l = [0u8; 4];  // arithmetic coding conditioning
+	let mut dc_u = [1u8; 4];
+	let mut ac_k = [5u8; 4];
+	let mut orientation = 1u16;
+	let mut lossless = false;
+	let mut ids = [0u8; 3];  // lossless component identifiers
+	let mut planes: Vec<Vec<i32>> = Vec::new();  // lossless samples, one plane per component
+	let mut markers: Vec<Vec<u8>> = Vec::new();  // APPn and COM segments for raw decoding
 	#[allow(unused_assignments)]
 	let mut sp = 2;
 	while sp < src.len() {
 		let marker = from_be16(&src[sp..sp + 2]);
 		let length = if marker != 0xFFD9 { from_be16(&src[sp + 2..sp + 4]) as usize } else { 0 };
+		if raw && ((0xFFE0..=0xFFEF).contains(&marker) || (marker == 0xFFFE)) {
+			markers.push(src[sp..sp + length + 2].to_vec());
+		}
 		//println!("marker {:04X}, length {}",marker,length);
 		match marker {
-			0xFFC0 | 0xFFC1 | 0xFFC2 => {  // baseline sequential, extended sequential, progressive
+			0xFFC0 | 0xFFC1 | 0xFFC2 | 0xFFC9 | 0xFFCA => {  // baseline sequential, extended sequential, progressive, arithmetic sequential, arithmetic progressive
+				arithmetic = (marker == 0xFFC9) || (marker == 0xFFCA);
+				// progressive refinement needs the history of all coefficients, sequential scans can be reduced right away
+				reduced = (bs < 8) && (marker != 0xFFC2) && (marker != 0xFFCA);
+				let bsize = if reduced { bs * bs } else { 64 };
 				//println!("precision {}",src[sp + 4]);
-				if src[sp + 4] != 8 {
+				precision = src[sp + 4];
+				if (precision != 8) && ((precision != 12) || (marker == 0xFFC0)) {  // 12-bit is not allowed in baseline
 					return Err("Invalid JPEG".to_string());
 				}
 				height = from_be16(&src[sp + 5..sp + 7]) as usize;
@@ -873,7 +2205,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 					mbwidth = (width + sw - 1) / sw;
 					mbheight = (height + sh - 1) / sh;
 					//println!("{}x{} macroblocks ({}x{} pixels)",mbwidth,mbheight,mbwidth * sw,mbheight * sh);
-					cpmb = 128 + 64 * ((samp[0] >> 4) as usize) * ((samp[0] & 15) as usize);
+					cpmb = (2 + ((samp[0] >> 4) as usize) * ((samp[0] & 15) as usize)) * bsize;
 					itype = match samp[0] {
 						0x11 => TYPE_YUV444,
 						0x12 => TYPE_YUV440,
@@ -887,7 +2219,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 				else {
 					mbwidth = (width + 7) / 8;
 					mbheight = (height + 7) / 8;
-					cpmb = 64;
+					cpmb = bsize;
 					itype = TYPE_Y;
 				}
 				mbtotal = mbwidth * mbheight;
@@ -895,6 +2227,28 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 				//println!("type {:04X}, {} macroblocks in total, {} coefficients per row",itype,mbtotal,mbstride);
 				//println!("size {}x{}, macroblocks {}",width,height,mbtotal);
 			},
+			0xFFC3 => {  // lossless
+				lossless = true;
+				precision = src[sp + 4];
+				if !(2..=16).contains(&precision) {
+					return Err("Invalid JPEG".to_string());
+				}
+				height = from_be16(&src[sp + 5..sp + 7]) as usize;
+				width = from_be16(&src[sp + 7..sp + 9]) as usize;
+				let components = src[sp + 9] as usize;
+				if (components != 1) && (components != 3) {
+					return Err("Invalid JPEG".to_string());
+				}
+				let mut tsp = sp + 10;
+				for id in ids.iter_mut().take(components) {
+					*id = src[tsp];
+					if src[tsp + 1] != 0x11 {
+						return Err("Unsupported JPEG".to_string());
+					}
+					tsp += 3;
+				}
+				planes = vec![vec![0; width * height]; components];
+			},
 			0xFFC4 => {  // huffman tables
 				let mut tsp = sp + 4;
 				while tsp < sp + length + 2 {
@@ -931,63 +2285,176 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 			},
 			0xFFD8 => {  // image start
 			},
+			0xFFD9 if lossless => {  // image end, lossless
+				// no color transform is applied, three components are RGB
+				let max = (1u64 << precision) - 1;
+				let sample = |c: usize,p: usize,bits: u32| (planes[c % planes.len()][p] as u64 * ((1 << bits) - 1) + max / 2) / max;
+				if precision <= 8 {
+					let mut image = Image::new(width,height);
+					for (p,d) in image.data.iter_mut().enumerate() {
+						*d = 0xFF000000 | ((sample(0,p,8) as u32) << 16) | ((sample(1,p,8) as u32) << 8) | (sample(2,p,8) as u32);
+					}
+					if options.apply_orientation && (orientation != 1) {
+						return Ok(Decoded::Image(image.oriented(orientation)));
+					}
+					return Ok(Decoded::Image(image));
+				}
+				let mut image = Image16::new(width,height);
+				for (p,d) in image.data.iter_mut().enumerate() {
+					*d = 0xFFFF000000000000 | (sample(0,p,16) << 32) | (sample(1,p,16) << 16) | sample(2,p,16);
+				}
+				if options.apply_orientation && (orientation != 1) {
+					return Ok(Decoded::Image16(image.oriented(orientation)));
+				}
+				return Ok(Decoded::Image16(image));
+			},
+			0xFFD9 if raw => {  // image end, raw coefficients
+				if lossless || (precision != 8) {
+					return Err("Unsupported JPEG".to_string());
+				}
+				let (hf,vf) = match itype {
+					TYPE_YUV420 => (2,2),
+					TYPE_YUV422 => (2,1),
+					TYPE_YUV440 => (1,2),
+					_ => (1,1),
+				};
+				let mut components = Vec::new();
+				let count = if itype == TYPE_Y { 1 } else { 3 };
+				for i in 0..count {
+					let mut table = [0u16; 64];
+					for (k,q) in table.iter_mut().enumerate() {
+						*q = qtable[qt[i]][FOLDED_ROW[k >> 3] * 8 + (k & 7)] as u16;
+					}
+					let (h,v) = if i == 0 { (hf,vf) } else { (1,1) };
+					let bwidth = mbwidth * h;
+					let bheight = mbheight * v;
+					let mut blocks = Vec::with_capacity(bwidth * bheight);
+					for by in 0..bheight {
+						for bx in 0..bwidth {
+							let offset = ((by / v) * mbwidth + bx / h) * cpmb + if i == 0 { ((by % v) * h + bx % h) * 64 } else { (hf * vf + i - 1) * 64 };
+							let mut block = [0i32; 64];
+							for (k,c) in block.iter_mut().enumerate() {
+								*c = coeffs[offset + FOLDED_ROW[k >> 3] * 8 + (k & 7)];
+							}
+							blocks.push(block);
+						}
+					}
+					components.push(JpegComponent {
+						id: (i + 1) as u8,
+						hsamp: h,
+						vsamp: v,
+						qtable: table,
+						bwidth,
+						bheight,
+						blocks,
+					});
+				}
+				return Ok(Decoded::Coefficients(JpegCoefficients {
+					width,
+					height,
+					components,
+					markers,
+				}));
+			},
 			0xFFD9 => {  // image end
 				//println!("end");
-				let mut image = Image::new(width,height);
+				if (bs < 8) && !reduced {
+					// progressive: drop the high frequencies now that all scans are in
+					let mut blocks = vec![0i32; coeffs.len() >> (bshift * 2)];
+					for (src,dst) in coeffs.chunks(64).zip(blocks.chunks_mut(bs * bs)) {
+						reduce_block(src,dst,bs);
+					}
+					coeffs = blocks;
+					cpmb >>= bshift * 2;
+				}
+				let swidth = (width + (1 << bshift) - 1) >> bshift;
+				let sheight = (height + (1 << bshift) - 1) >> bshift;
+				let (mbw,mbh) = match itype {
+					TYPE_YUV420 => (16 >> bshift,16 >> bshift),
+					TYPE_YUV422 => (16 >> bshift,8 >> bshift),
+					TYPE_YUV440 => (8 >> bshift,16 >> bshift),
+					_ => (8 >> bshift,8 >> bshift),
+				};
+				let idct = if precision == 12 { Idct::Float } else { options.idct };  // see Idct
 				match itype {
-					TYPE_Y => { convert_blocks(&mut coeffs,mbtotal,TYPE_Y,&qtable,&qt); },
-					TYPE_YUV420 => { convert_blocks(&mut coeffs,mbtotal * 6,TYPE_YUV420,&qtable,&qt); },
-					TYPE_YUV422 => { convert_blocks(&mut coeffs,mbtotal * 4,TYPE_YUV422,&qtable,&qt); },
-					TYPE_YUV440 => { convert_blocks(&mut coeffs,mbtotal * 4,TYPE_YUV440,&qtable,&qt); },
-					TYPE_YUV444 => { convert_blocks(&mut coeffs,mbtotal * 3,TYPE_YUV444,&qtable,&qt); },
-					TYPE_RGB444 => { convert_blocks(&mut coeffs,mbtotal * 3,TYPE_RGB444,&qtable,&qt); },
+					TYPE_Y => { convert_blocks(&mut coeffs,mbtotal,TYPE_Y,&qtable,&qt,bshift,idct); },
+					TYPE_YUV420 => { convert_blocks(&mut coeffs,mbtotal * 6,TYPE_YUV420,&qtable,&qt,bshift,idct); },
+					TYPE_YUV422 => { convert_blocks(&mut coeffs,mbtotal * 4,TYPE_YUV422,&qtable,&qt,bshift,idct); },
+					TYPE_YUV440 => { convert_blocks(&mut coeffs,mbtotal * 4,TYPE_YUV440,&qtable,&qt,bshift,idct); },
+					TYPE_YUV444 => { convert_blocks(&mut coeffs,mbtotal * 3,TYPE_YUV444,&qtable,&qt,bshift,idct); },
+					TYPE_RGB444 => { convert_blocks(&mut coeffs,mbtotal * 3,TYPE_RGB444,&qtable,&qt,bshift,idct); },
 					_ => { },
 				}
-				#[allow(unused_assignments)]
-				let mut mb = 0;
-				for i in 0..mbheight - 1 {
-					for k in 0..mbwidth - 1 {
-						match itype {
-							TYPE_Y => { draw_macroblock_y(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 64]); mb += 64; },
-							TYPE_YUV420 => { draw_macroblock_yuv420(&mut image,k * 16,i * 16,16,16,&coeffs[mb..mb + 384]); mb += 384; },
-							TYPE_YUV422 => { draw_macroblock_yuv422(&mut image,k * 16,i * 8,16,8,&coeffs[mb..mb + 256]); mb += 256; },
-							TYPE_YUV440 => { draw_macroblock_yuv440(&mut image,k * 8,i * 16,8,16,&coeffs[mb..mb + 256]); mb += 256; },
-							TYPE_YUV444 => { draw_macroblock_yuv444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 192]); mb += 192; },
-							TYPE_RGB444 => { draw_macroblock_rgb444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 192]); mb += 192; },
-							_ => { },
-						}
-					}
-					match itype {
-						TYPE_Y => { draw_macroblock_y(&mut image,mbwidth * 8 - 8,i * 8,width - (mbwidth - 1) * 8,8,&coeffs[mb..mb + 64]); mb += 64; },
-						TYPE_YUV420 => { draw_macroblock_yuv420(&mut image,mbwidth * 16 - 16,i * 16,width - (mbwidth - 1) * 16,16,&coeffs[mb..mb + 384]); mb += 384; },
-						TYPE_YUV422 => { draw_macroblock_yuv422(&mut image,mbwidth * 16 - 16,i * 8,width - (mbwidth - 1) * 16,8,&coeffs[mb..mb + 256]); mb += 256; },
-						TYPE_YUV440 => { draw_macroblock_yuv440(&mut image,mbwidth * 8 - 8,i * 16,width - (mbwidth - 1) * 8,16,&coeffs[mb..mb + 256]); mb += 256; },
-						TYPE_YUV444 => { draw_macroblock_yuv444(&mut image,mbwidth * 8 - 8,i * 8,width - (mbwidth - 1) * 8,8,&coeffs[mb..mb + 192]); mb += 192; },
-						TYPE_RGB444 => { draw_macroblock_rgb444(&mut image,mbwidth * 8 - 8,i * 8,width - (mbwidth - 1) * 8,8,&coeffs[mb..mb + 192]); mb += 192; },
-						_ => { },
+				let fancy = (options.upsampling == Upsampling::Fancy) && ((itype == TYPE_YUV420) || (itype == TYPE_YUV422) || (itype == TYPE_YUV440));
+				if precision == 12 {
+					let mut image = Image16::new(swidth,sheight);
+					draw16(&mut image,&coeffs,itype,mbwidth,mbheight,bshift,fancy);
+					if options.apply_orientation && (orientation != 1) {
+						return Ok(Decoded::Image16(image.oriented(orientation)));
 					}
+					return Ok(Decoded::Image16(image));
 				}
-				for k in 0..mbwidth - 1 {
-					match itype {
-						TYPE_Y => { draw_macroblock_y(&mut image,k * 8,mbheight * 8 - 8,8,mbheight * 8 - height,&coeffs[mb..mb + 64]); mb += 64; },
-						TYPE_YUV420 => { draw_macroblock_yuv420(&mut image,k * 16,mbheight * 16 - 16,16,height - (mbheight - 1) * 16,&coeffs[mb..mb + 384]); mb += 384; },
-						TYPE_YUV422 => { draw_macroblock_yuv422(&mut image,k * 16,mbheight * 8 - 8,16,height - (mbheight - 1) * 8,&coeffs[mb..mb + 256]); mb += 256; },
-						TYPE_YUV440 => { draw_macroblock_yuv440(&mut image,k * 8,mbheight * 16 - 16,8,height - (mbheight - 1) * 16,&coeffs[mb..mb + 256]); mb += 256; },
-						TYPE_YUV444 => { draw_macroblock_yuv444(&mut image,k * 8,mbheight * 8 - 8,8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); mb += 192; },
-						TYPE_RGB444 => { draw_macroblock_rgb444(&mut image,k * 8,mbheight * 8 - 8,8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); mb += 192; },
-						_ => { },
+				let mut image = Image::new(swidth,sheight);
+				if fancy {
+					draw_fancy(&mut image,&coeffs,itype,mbwidth,mbheight,mbw,mbh,bshift);
+				}
+				else {
+					let mut mb = 0;
+					for i in 0..mbheight {
+						for k in 0..mbwidth {
+							let x0 = k * mbw;
+							let y0 = i * mbh;
+							let w = if x0 + mbw > swidth { swidth - x0 } else { mbw };
+							let h = if y0 + mbh > sheight { sheight - y0 } else { mbh };
+							match itype {
+								TYPE_Y => { draw_macroblock_y(&mut image,x0,y0,w,h,bs,&coeffs[mb..mb + cpmb]); },
+								TYPE_YUV420 => { draw_macroblock_yuv420(&mut image,x0,y0,w,h,bs,&coeffs[mb..mb + cpmb]); },
+								TYPE_YUV422 => { draw_macroblock_yuv422(&mut image,x0,y0,w,h,bs,&coeffs[mb..mb + cpmb]); },
+								TYPE_YUV440 => { draw_macroblock_yuv440(&mut image,x0,y0,w,h,bs,&coeffs[mb..mb + cpmb]); },
+								TYPE_YUV444 => { draw_macroblock_yuv444(&mut image,x0,y0,w,h,bs,&coeffs[mb..mb + cpmb]); },
+								TYPE_RGB444 => { draw_macroblock_rgb444(&mut image,x0,y0,w,h,bs,&coeffs[mb..mb + cpmb]); },
+								_ => { },
+							}
+							mb += cpmb;
+						}
 					}
 				}
-				match itype {
-					TYPE_Y => { draw_macroblock_y(&mut image,mbwidth * 8 - 8,mbheight * 8 - 8,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 64]); },
-					TYPE_YUV420 => { draw_macroblock_yuv420(&mut image,mbwidth * 16 - 16,mbheight * 16 - 16,width - (mbwidth - 1) * 16,height - (mbheight - 1) * 16,&coeffs[mb..mb + 384]); },
-					TYPE_YUV422 => { draw_macroblock_yuv422(&mut image,mbwidth * 16 - 16,mbheight * 8 - 8,width - (mbwidth - 1) * 16,height - (mbheight - 1) * 8,&coeffs[mb..mb + 256]); },
-					TYPE_YUV440 => { draw_macroblock_yuv440(&mut image,mbwidth * 8 - 8,mbheight * 16 - 16,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 16,&coeffs[mb..mb + 256]); },
-					TYPE_YUV444 => { draw_macroblock_yuv444(&mut image,mbwidth * 8 - 8,mbheight * 8 - 8,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); },
-					TYPE_RGB444 => { draw_macroblock_rgb444(&mut image,mbwidth * 8 - 8,mbheight * 8 - 8,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); },
-					_ => { },
+				if options.apply_orientation && (orientation != 1) {
+					return Ok(Decoded::Image(image.oriented(orientation)));
+				}
+				return Ok(Decoded::Image(image));
+			},
+			0xFFDA if lossless => {  // scan start, lossless
+				let mut tsp = sp + 4;
+				let count = src[tsp] as usize;
+				tsp += 1;
+				let mut indices = Vec::new();
+				let mut tables = Vec::new();
+				for _i in 0..count {
+					let index = match ids.iter().take(planes.len()).position(|id| *id == src[tsp]) {
+						Some(index) => index,
+						None => {
+							return Err("Invalid JPEG".to_string());
+						},
+					};
+					indices.push(index);
+					tables.push(dcht[(src[tsp + 1] >> 4) as usize & 3]);
+					tsp += 2;
+				}
+				let predictor = src[tsp];  // stored in the spectral selection start
+				let pt = src[tsp + 2] & 15;  // point transform
+				tsp += 3;
+				if !(1..=7).contains(&predictor) || (pt >= precision) {
+					return Err("Invalid JPEG".to_string());
+				}
+				let mut reader = Reader::new(&src[tsp..]);
+				unpack_lossless(&mut reader,&mut planes,&indices,&tables,width,height,precision - pt,predictor,resint);
+				for index in indices.iter() {
+					for sample in planes[*index].iter_mut() {
+						*sample = (*sample << pt) & 0xFFFF;
+					}
 				}
-				return Ok(image);
+				sp = (tsp + reader.leave()) - length - 2;
 			},
 			0xFFDA => {  // scan start
 				//println!("scan start");
@@ -995,7 +2462,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 				let count = src[tsp];
 				tsp += 1;
 				// acht[4], dcht[4]
-				let mut mask = 0;
+				let mut mask = 0u8;
 				for _i in 0..count {
 					let index = src[tsp] - 1;
 					tsp += 1;
@@ -1015,15 +2482,47 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 				let refine = (d & 0xF0) != 0;
 				let shift = d & 15;
 				//println!("start = {}, end = {}, refine = {}, shift = {}",start,end,refine,shift);
-				let mut reader = Reader::new(&src[tsp..]);
 				let mut rescnt = resint;
-				let mut eobrun = 0;
 				let mut dc = [0i32; 3];
-				for i in 0..mbtotal {
-					//println!("macroblock {}:",i);
-					unpack_macroblock(&mut reader,&mut coeffs[i * cpmb..(i + 1) * cpmb],&dcht,&acht,&dt,&at,&mut dc,start,end,shift,refine,&mut eobrun,itype,&mut rescnt,resint,mask);
+				let bsize = if reduced { bs * bs } else { 64 };
+				let (blocks,per_mcu) = scan_blocks(itype,mask,width,height,mbwidth,mbheight,cpmb,bsize);
+				let mut block = [0i32; 64];
+				if arithmetic {
+					let mut reader = ArithReader::new(&src[tsp..]);
+					let mut stats = ArithStats::new(dc_l,dc_u,ac_k);
+					for (n,(index,offset)) in blocks.iter().enumerate() {
+						if reduced {
+							block.fill(0);
+							arith_block(&mut reader,&mut stats,&mut block,*index,dt[*index],at[*index],&mut dc[*index],start,end,shift,refine);
+							reduce_block(&block,&mut coeffs[*offset..*offset + bsize],bs);
+						}
+						else {
+							arith_block(&mut reader,&mut stats,&mut coeffs[*offset..*offset + 64],*index,dt[*index],at[*index],&mut dc[*index],start,end,shift,refine);
+						}
+						if (n + 1) % per_mcu == 0 {
+							handle_arith_restart(&mut reader,&mut stats,&mut dc,&mut rescnt,resint);
+						}
+					}
+					sp = (tsp + reader.leave()) - length - 2;
+				}
+				else {
+					let mut reader = Reader::new(&src[tsp..]);
+					let mut eobrun = 0;
+					for (n,(index,offset)) in blocks.iter().enumerate() {
+						if reduced {
+							block.fill(0);
+							unpack_block(&mut reader,&mut block,&dcht[dt[*index]],&acht[at[*index]],&mut dc[*index],start,end,shift,refine,&mut eobrun);
+							reduce_block(&block,&mut coeffs[*offset..*offset + bsize],bs);
+						}
+						else {
+							unpack_block(&mut reader,&mut coeffs[*offset..*offset + 64],&dcht[dt[*index]],&acht[at[*index]],&mut dc[*index],start,end,shift,refine,&mut eobrun);
+						}
+						if (n + 1) % per_mcu == 0 {
+							handle_restart(&mut reader,&mut dc,&mut eobrun,&mut rescnt,resint);
+						}
+					}
+					sp = (tsp + reader.leave()) - length - 2;
 				}
-				sp = (tsp + reader.leave()) - length - 2;
 				//println!("sp = {}, ({:02X} {:02X})",sp,src[sp + length + 2],src[sp + length + 2 + 1]);
 			},
 			0xFFDB => {  // quantization tables
@@ -1047,49 +2546,45 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 					}
 				}
 			},
+			0xFFCC => {  // arithmetic coding conditioning
+				let mut tsp = sp + 4;
+				while tsp + 1 < sp + length + 2 {
+					let n = (src[tsp] & 3) as usize;
+					if (src[tsp] >> 4) == 0 {
+						dc_l[n] = src[tsp + 1] & 15;
+						dc_u[n] = src[tsp + 1] >> 4;
+					}
+					else {
+						ac_k[n] = src[tsp + 1];
+					}
+					tsp += 2;
+				}
+			},
 			0xFFDD => {  // restart interval
 				resint = from_be16(&src[sp + 4..sp + 6]) as usize;
 			},
 			0xFFE1 => {  // EXIF
 				let header = from_be32(&src[sp + 4..sp + 8]);
 				if header == 0x45786966 {  // Exif
-					let start = sp + 10;
-					let mut tsp = start;
-					let le = from_be16(&src[tsp..tsp + 2]) == 0x4949;  // figure out endianness
-					tsp += 4;  // skip 0x2A
-					tsp += (if le { from_le32(&src[tsp..tsp + 4]) } else { from_be32(&src[tsp..tsp + 4]) } - 8) as usize;  // go to IFD0
-					let entries = if le { from_le16(&src[tsp..tsp + 2]) } else { from_be16(&src[tsp..tsp + 2]) };  // number of entries
-					tsp += 2;
-					for _i in 0..entries {
-						let tag = if le { from_le16(&src[tsp..tsp + 2]) } else { from_be16(&src[tsp..tsp + 2]) };
-						tsp += 2;
-						let format = if le { from_le16(&src[tsp..tsp + 2]) } else { from_be16(&src[tsp..tsp + 2]) };
-						tsp += 2;
-						if format > 12 {
-							return Err("Invalid JPEG".to_string());							
-						}
-						let components = if le { from_le32(&src[tsp..tsp + 4]) } else { from_be32(&src[tsp..tsp + 4]) };
-						tsp += 4;
-						let data = if le { from_le32(&src[tsp..tsp + 4]) } else { from_be32(&src[tsp..tsp + 4]) };
-						tsp += 4;
-						let elsize = [0usize,1,1,2,4,8,1,0,2,4,8,4,8];
-						let total = elsize[format as usize] * (components as usize);
-						let mut dsp = start + data as usize;
-						if total <= 4 {
-							dsp = tsp - 4;
-						}
-						//println!("EXIF tag {:04X}, format {}, components {}, data {:08X}",tag,format,components,data);
-						match tag {
-							0x0106 => { // photometric interpretation
-								let pe = if le { from_le16(&src[dsp..dsp + 2]) } else { from_be16(&src[dsp..dsp + 2]) };
-								if (pe != 2) || (itype != TYPE_YUV444) {
-									return Err("Invalid JPEG".to_string());
+					if let Some(exif) = Exif::new(&src[sp + 10..sp + length + 2]) {
+						if let Some(ifd0) = exif.first_ifd() {
+							for entry in exif.entries(ifd0) {
+								//println!("EXIF tag {:04X}, format {}, components {}",entry.tag,entry.format,entry.count);
+								match entry.tag {
+									0x0106 => { // photometric interpretation
+										if (exif.value(&entry) != Some(2)) || (itype != TYPE_YUV444) {
+											return Err("Invalid JPEG".to_string());
+										}
+										itype = TYPE_RGB444;
+									},
+									0x0112 => { // orientation
+										orientation = exif.value(&entry).unwrap_or(1) as u16;
+									},
+									0xA001 => { // colorspace
+									},
+									_ => {
+									}
 								}
-								itype = TYPE_RGB444;
-							},
-							0xA001 => { // colorspace
-							},
-							_ => {
 							}
 						}
 					}
@@ -1106,6 +2601,1108 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
 	Err("Invalid JPEG".to_string())
 }
 
-pub fn encode(_image: &Image) -> Result<Vec<u8>,String> {
-	Err("not implemented yet".to_string())
+// chroma subsampling for the encoder
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Subsampling {
+	S444,
+	S422,
+	S420,
+}
+
+// one scan of a progressive JPEG
+#[derive(Copy,Clone,Debug)]
+pub struct Scan {
+	pub mask: u8,  // components in the scan, bit 0 is Y, bit 1 is Cb, bit 2 is Cr
+	pub start: u8,  // spectral selection, 0 for the DC scans
+	pub end: u8,
+	pub high: u8,  // successive approximation, previous point transform or 0 for the first scan of a band
+	pub low: u8,  // point transform
+}
+
+// the usual progression: DC first, low and high luminance bands, followed by refinement scans
+pub fn progressive_scans() -> Vec<Scan> {
+	let scan = |mask,start,end,high,low| Scan { mask,start,end,high,low, };
+	vec![
+		scan(7,0,0,0,1),
+		scan(1,1,5,0,2),
+		scan(4,1,63,0,1),
+		scan(2,1,63,0,1),
+		scan(1,6,63,0,2),
+		scan(1,1,63,2,1),
+		scan(7,0,0,1,0),
+		scan(4,1,63,1,0),
+		scan(2,1,63,1,0),
+		scan(1,1,63,1,0),
+	]
+}
+
+// encoder settings
+#[derive(Clone)]
+pub struct EncodeOptions {
+	pub quality: u8,  // 1..=100, scales the Annex K quantization tables
+	pub subsampling: Subsampling,  // chroma subsampling, ignored for gray images
+	pub restart_interval: usize,  // MCUs between restart markers, 0 for none
+	pub optimize_huffman: bool,  // gather statistics and write optimal huffman tables instead of the Annex K ones
+	pub scans: Vec<Scan>,  // scan script for a progressive JPEG, empty for baseline; chroma scans are dropped for gray images, and tables are always optimized as the Annex K ones lack the EOB run codes
+	pub icc_profile: Option<Vec<u8>>,  // ICC profile to embed in APP2 segments
+	pub dpi: Option<(f32,f32)>,  // resolution for the JFIF header, None writes a 1:1 pixel aspect ratio
+}
+
+impl Default for EncodeOptions {
+	fn default() -> EncodeOptions {
+		EncodeOptions {
+			quality: 75,
+			subsampling: Subsampling::S420,
+			restart_interval: 0,
+			optimize_huffman: false,
+			scans: Vec::new(),
+			icc_profile: None,
+			dpi: None,
+		}
+	}
+}
+
+trait WriteTypes {
+	fn push16b(&mut self,d: u16);
+}
+
+impl WriteTypes for Vec<u8> {
+	fn push16b(&mut self,d: u16) {
+		self.push((d >> 8) as u8);
+		self.push((d & 255) as u8);
+	}
+}
+
+// huffman code lookup for the encoder, indexed by symbol
+struct EncodeTable {
+	code: [u16; 256],
+	size: [u8; 256],
+}
+
+impl EncodeTable {
+	pub fn new(bits: &[u8; 16],huffval: &[u8]) -> EncodeTable {
+		let mut code = [0u16; 256];
+		let mut size = [0u8; 256];
+		let mut c = 0u16;
+		let mut k = 0;
+		for (i,n) in bits.iter().enumerate() {
+			for _j in 0..*n {
+				code[huffval[k] as usize] = c;
+				size[huffval[k] as usize] = (i + 1) as u8;
+				c += 1;
+				k += 1;
+			}
+			c <<= 1;
+		}
+		EncodeTable {
+			code,
+			size,
+		}
+	}
+}
+
+// huffman code length of each symbol (K.2), including the reserved symbol 256
+fn code_sizes(counts: &[u32; 256]) -> [usize; 257] {
+	let mut freq = [0u64; 257];
+	for i in 0..256 {
+		freq[i] = counts[i] as u64;
+	}
+	freq[256] = 1;  // reserved, so no code consists of all 1-bits
+	let mut codesize = [0usize; 257];
+	let mut others = [usize::MAX; 257];
+	loop {
+		// the two least frequent symbols, c1 with the highest index on ties
+		let mut c1 = usize::MAX;
+		let mut v = u64::MAX;
+		for (i,f) in freq.iter().enumerate() {
+			if (*f != 0) && (*f <= v) {
+				v = *f;
+				c1 = i;
+			}
+		}
+		let mut c2 = usize::MAX;
+		let mut v = u64::MAX;
+		for (i,f) in freq.iter().enumerate() {
+			if (*f != 0) && (*f <= v) && (i != c1) {
+				v = *f;
+				c2 = i;
+			}
+		}
+		if c2 == usize::MAX {
+			break;
+		}
+		freq[c1] += freq[c2];
+		freq[c2] = 0;
+		codesize[c1] += 1;
+		while others[c1] != usize::MAX {
+			c1 = others[c1];
+			codesize[c1] += 1;
+		}
+		others[c1] = c2;
+		codesize[c2] += 1;
+		while others[c2] != usize::MAX {
+			c2 = others[c2];
+			codesize[c2] += 1;
+		}
+	}
+	codesize
+}
+
+// optimal huffman table (bits, values) for the symbol counts, limited to 16-bit codes (Annex K.2)
+fn optimal_table(counts: &[u32; 256]) -> ([u8; 16],Vec<u8>) {
+	let mut counts = *counts;
+	let mut codesize = code_sizes(&counts);
+	// very skewed counts give codes longer than the 32 bits the length adjustment below handles; flatten them until they fit
+	while codesize.iter().any(|size| *size > 32) {
+		for count in counts.iter_mut() {
+			if *count != 0 {
+				*count = (*count >> 1).max(1);
+			}
+		}
+		codesize = code_sizes(&counts);
+	}
+	let mut bits = [0u8; 33];
+	for size in codesize.iter() {
+		if *size > 0 {
+			bits[*size] += 1;
+		}
+	}
+	// move codes longer than 16 bits up the tree
+	for i in (17..33).rev() {
+		while bits[i] > 0 {
+			let mut j = i - 2;
+			while bits[j] == 0 {
+				j -= 1;
+			}
+			bits[i] -= 2;
+			bits[i - 1] += 1;
+			bits[j + 1] += 2;
+			bits[j] -= 1;
+		}
+	}
+	// remove the reserved symbol
+	let mut i = 16;
+	while bits[i] == 0 {
+		i -= 1;
+	}
+	bits[i] -= 1;
+	let mut result = [0u8; 16];
+	result.copy_from_slice(&bits[1..17]);
+	let mut huffval = Vec::new();
+	for size in 1..33 {
+		for (symbol,s) in codesize.iter().enumerate().take(256) {
+			if *s == size {
+				huffval.push(symbol as u8);
+			}
+		}
+	}
+	(result,huffval)
+}
+
+// entropy coded segment writer, takes care of 0xFF stuffing; when counting, only symbol statistics are gathered
+struct Writer {
+	data: Vec<u8>,
+	cache: u64,
+	bit: u32,
+	dcht: [EncodeTable; 2],
+	acht: [EncodeTable; 2],
+	counting: bool,
+	dc_counts: [[u32; 256]; 2],
+	ac_counts: [[u32; 256]; 2],
+}
+
+impl Writer {
+	pub fn new(dcht: [EncodeTable; 2],acht: [EncodeTable; 2],counting: bool) -> Writer {
+		Writer {
+			data: Vec::new(),
+			cache: 0,
+			bit: 0,
+			dcht,
+			acht,
+			counting,
+			dc_counts: [[0; 256]; 2],
+			ac_counts: [[0; 256]; 2],
+		}
+	}
+
+	pub fn put(&mut self,code: u32,size: u8) {
+		if self.counting {
+			return;
+		}
+		self.cache = (self.cache << size) | (code as u64 & ((1 << size) - 1));
+		self.bit += size as u32;
+		while self.bit >= 8 {
+			let b = (self.cache >> (self.bit - 8)) as u8;
+			self.data.push(b);
+			if b == 0xFF {
+				self.data.push(0x00);
+			}
+			self.bit -= 8;
+		}
+		self.cache &= (1 << self.bit) - 1;
+	}
+
+	pub fn put_dc(&mut self,t: usize,symbol: u8) {
+		if self.counting {
+			self.dc_counts[t][symbol as usize] += 1;
+		}
+		else {
+			self.put(self.dcht[t].code[symbol as usize] as u32,self.dcht[t].size[symbol as usize]);
+		}
+	}
+
+	pub fn put_ac(&mut self,t: usize,symbol: u8) {
+		if self.counting {
+			self.ac_counts[t][symbol as usize] += 1;
+		}
+		else {
+			self.put(self.acht[t].code[symbol as usize] as u32,self.acht[t].size[symbol as usize]);
+		}
+	}
+
+	// pad to a byte boundary with 1-bits
+	pub fn flush(&mut self) {
+		if self.bit > 0 {
+			let n = 8 - self.bit as u8;
+			self.put((1 << n) - 1,n);
+		}
+	}
+
+	pub fn marker(&mut self,marker: u16) {
+		if !self.counting {
+			self.data.push16b(marker);
+		}
+	}
+}
+
+// number of bits needed for the magnitude of a coefficient
+fn category(v: i32) -> u8 {
+	(32 - v.unsigned_abs().leading_zeros()) as u8
+}
+
+// additional bits of a coefficient in category cat
+fn extra_bits(v: i32,cat: u8) -> u32 {
+	(if v < 0 { v - 1 } else { v }) as u32 & ((1 << cat) - 1)
+}
+
+fn encode_dc(writer: &mut Writer,t: usize,diff: i32) {
+	let cat = category(diff);
+	writer.put_dc(t,cat);
+	if cat > 0 {
+		writer.put(extra_bits(diff,cat),cat);
+	}
+}
+
+fn encode_block_sequential(writer: &mut Writer,t: usize,block: &[i32; 64],dc: &mut i32) {
+	encode_dc(writer,t,block[0] - *dc);
+	*dc = block[0];
+	let mut run = 0;
+	for k in 1..64 {
+		let coeff = block[ZIGZAG[k] as usize];
+		if coeff == 0 {
+			run += 1;
+			continue;
+		}
+		while run > 15 {
+			writer.put_ac(t,0xF0);  // ZRL
+			run -= 16;
+		}
+		let cat = category(coeff);
+		writer.put_ac(t,(run << 4) | cat);
+		writer.put(extra_bits(coeff,cat),cat);
+		run = 0;
+	}
+	if run > 0 {
+		writer.put_ac(t,0x00);  // EOB
+	}
+}
+
+// pending end-of-band run of a progressive AC scan, with the correction bits that follow it
+struct EobRun {
+	count: usize,
+	bits: Vec<u8>,
+}
+
+impl EobRun {
+	pub fn flush(&mut self,writer: &mut Writer,t: usize) {
+		if self.count > 0 {
+			let n = category(self.count as i32) - 1;
+			writer.put_ac(t,n << 4);
+			if n > 0 {
+				writer.put(self.count as u32,n);
+			}
+			self.count = 0;
+		}
+		for b in self.bits.iter() {
+			writer.put(*b as u32,1);
+		}
+		self.bits.clear();
+	}
+}
+
+fn encode_block_ac_first(writer: &mut Writer,t: usize,block: &[i32; 64],scan: &Scan,eobrun: &mut EobRun) {
+	let mut run = 0;
+	for k in scan.start..=scan.end {
+		let coeff = block[ZIGZAG[k as usize] as usize];
+		// point transform, rounding towards zero
+		let coeff = if coeff < 0 { -(-coeff >> scan.low) } else { coeff >> scan.low };
+		if coeff == 0 {
+			run += 1;
+			continue;
+		}
+		eobrun.flush(writer,t);
+		while run > 15 {
+			writer.put_ac(t,0xF0);  // ZRL
+			run -= 16;
+		}
+		let cat = category(coeff);
+		writer.put_ac(t,(run << 4) | cat);
+		writer.put(extra_bits(coeff,cat),cat);
+		run = 0;
+	}
+	if run > 0 {
+		eobrun.count += 1;
+		if eobrun.count == 0x7FFF {
+			eobrun.flush(writer,t);
+		}
+	}
+}
+
+fn encode_block_ac_refine(writer: &mut Writer,t: usize,block: &[i32; 64],scan: &Scan,eobrun: &mut EobRun) {
+	let mut absolute = [0i32; 64];
+	let mut eob = 0;
+	for k in scan.start..=scan.end {
+		absolute[k as usize] = block[ZIGZAG[k as usize] as usize].abs() >> scan.low;
+		if absolute[k as usize] == 1 {
+			eob = k;  // last coefficient that becomes nonzero in this scan
+		}
+	}
+	let mut run = 0;
+	let mut bits: Vec<u8> = Vec::new();  // correction bits of coefficients that were already nonzero
+	for k in scan.start..=scan.end {
+		let a = absolute[k as usize];
+		if a == 0 {
+			run += 1;
+			continue;
+		}
+		while (run > 15) && (k <= eob) {
+			eobrun.flush(writer,t);
+			writer.put_ac(t,0xF0);  // ZRL
+			run -= 16;
+			for b in bits.iter() {
+				writer.put(*b as u32,1);
+			}
+			bits.clear();
+		}
+		if a > 1 {
+			bits.push((a & 1) as u8);
+			continue;
+		}
+		eobrun.flush(writer,t);
+		writer.put_ac(t,(run << 4) | 1);
+		writer.put(if block[ZIGZAG[k as usize] as usize] < 0 { 0 } else { 1 },1);
+		for b in bits.iter() {
+			writer.put(*b as u32,1);
+		}
+		bits.clear();
+		run = 0;
+	}
+	if (run > 0) || !bits.is_empty() {
+		eobrun.count += 1;
+		eobrun.bits.extend_from_slice(&bits);
+		if (eobrun.count == 0x7FFF) || (eobrun.bits.len() > 937) {
+			eobrun.flush(writer,t);
+		}
+	}
+}
+
+// forward DCT and quantization of one 8x8 block of level shifted samples, result in natural order
+fn fdct_quantize(samples: &[f32; 64],qtable: &[u8; 64]) -> [i32; 64] {
+	let mut temp = [0f32; 64];
+	for y in 0..8 {
+		for u in 0..8 {
+			let mut sum = 0.0;
+			for x in 0..8 {
+				sum += IDCT8[x * 8 + u] * samples[y * 8 + x];
+			}
+			temp[y * 8 + u] = sum;
+		}
+	}
+	let mut result = [0i32; 64];
+	for v in 0..8 {
+		for u in 0..8 {
+			let mut sum = 0.0;
+			for y in 0..8 {
+				sum += IDCT8[y * 8 + v] * temp[y * 8 + u];
+			}
+			result[v * 8 + u] = (sum / (qtable[v * 8 + u] as f32)).round() as i32;
+		}
+	}
+	result
+}
+
+// quantization table for the IJG quality setting
+fn scale_qtable(table: &[u8; 64],quality: u8) -> [u8; 64] {
+	let quality = clamp(quality as i32,1,100);
+	let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+	let mut result = [0u8; 64];
+	for i in 0..64 {
+		result[i] = clamp((table[i] as i32 * scale + 50) / 100,1,255) as u8;
+	}
+	result
+}
+
+// one component of the image to be encoded
+struct EncodeComponent {
+	id: u8,
+	samp: u8,  // horizontal and vertical sampling factors
+	tq: usize,  // quantization table
+	bwidth: usize,  // number of blocks, padded to whole MCUs
+	bheight: usize,
+	cwidth: usize,  // number of blocks covering the component, for non-interleaved scans
+	cheight: usize,
+	blocks: Vec<[i32; 64]>,  // quantized coefficients in natural order
+}
+
+// split a sample plane of bwidth * 8 x bheight * 8 into quantized blocks
+fn make_blocks(plane: &[f32],bwidth: usize,bheight: usize,qtable: &[u8; 64]) -> Vec<[i32; 64]> {
+	let stride = bwidth * 8;
+	let mut blocks = Vec::with_capacity(bwidth * bheight);
+	for by in 0..bheight {
+		for bx in 0..bwidth {
+			let mut samples = [0f32; 64];
+			for y in 0..8 {
+				for x in 0..8 {
+					samples[y * 8 + x] = plane[(by * 8 + y) * stride + bx * 8 + x] - 128.0;
+				}
+			}
+			blocks.push(fdct_quantize(&samples,qtable));
+		}
+	}
+	blocks
+}
+
+// average a plane down by hf x vf
+fn downsample(plane: &[f32],width: usize,height: usize,hf: usize,vf: usize) -> Vec<f32> {
+	let dwidth = width / hf;
+	let dheight = height / vf;
+	let mut result = vec![0f32; dwidth * dheight];
+	let scale = 1.0 / ((hf * vf) as f32);
+	for y in 0..dheight {
+		for x in 0..dwidth {
+			let mut sum = 0.0;
+			for i in 0..vf {
+				for k in 0..hf {
+					sum += plane[(y * vf + i) * width + x * hf + k];
+				}
+			}
+			result[y * dwidth + x] = sum * scale;
+		}
+	}
+	result
+}
+
+fn write_qtable(dst: &mut Vec<u8>,n: u8,qtable: &[u8; 64]) {
+	dst.push16b(0xFFDB);
+	dst.push16b(67);
+	dst.push(n);
+	for k in 0..64 {
+		dst.push(qtable[ZIGZAG[k] as usize]);
+	}
+}
+
+fn write_huffman_table(dst: &mut Vec<u8>,class_id: u8,bits: &[u8; 16],huffval: &[u8]) {
+	dst.push16b(0xFFC4);
+	dst.push16b((19 + huffval.len()) as u16);
+	dst.push(class_id);
+	dst.extend_from_slice(bits);
+	dst.extend_from_slice(huffval);
+}
+
+// entropy code one scan; MCUs are interleaved when the scan has more than one component
+fn encode_scan(writer: &mut Writer,components: &[EncodeComponent],scan: &Scan,restart_interval: usize) {
+	let indices: Vec<usize> = (0..components.len()).filter(|i| (scan.mask & (1 << i)) != 0).collect();
+	let mut mcus: Vec<Vec<(usize,usize)>> = Vec::new();  // (component, block) per MCU
+	if indices.len() == 1 {
+		let c = &components[indices[0]];
+		for by in 0..c.cheight {
+			for bx in 0..c.cwidth {
+				mcus.push(vec![(indices[0],by * c.bwidth + bx)]);
+			}
+		}
+	}
+	else {
+		let hmax = components.iter().map(|c| (c.samp >> 4) as usize).max().unwrap_or(1);
+		let vmax = components.iter().map(|c| (c.samp & 15) as usize).max().unwrap_or(1);
+		let mcuwidth = components[0].bwidth / hmax;
+		let mcuheight = components[0].bheight / vmax;
+		for my in 0..mcuheight {
+			for mx in 0..mcuwidth {
+				let mut mcu = Vec::new();
+				for i in indices.iter() {
+					let c = &components[*i];
+					let h = (c.samp >> 4) as usize;
+					let v = (c.samp & 15) as usize;
+					for by in 0..v {
+						for bx in 0..h {
+							mcu.push((*i,(my * v + by) * c.bwidth + mx * h + bx));
+						}
+					}
+				}
+				mcus.push(mcu);
+			}
+		}
+	}
+	let sequential = (scan.start == 0) && (scan.end == 63);
+	let mut dc = [0i32; 3];
+	let mut eobrun = EobRun {
+		count: 0,
+		bits: Vec::new(),
+	};
+	let t = if indices[0] == 0 { 0 } else { 1 };  // table of a single component scan
+	let mut rst = 0;
+	for (n,mcu) in mcus.iter().enumerate() {
+		if (restart_interval != 0) && (n != 0) && ((n % restart_interval) == 0) {
+			eobrun.flush(writer,t);
+			writer.flush();
+			writer.marker(0xFFD0 + rst);
+			rst = (rst + 1) & 7;
+			dc = [0i32; 3];
+		}
+		for (i,b) in mcu.iter() {
+			let block = &components[*i].blocks[*b];
+			let t = if *i == 0 { 0 } else { 1 };
+			if sequential {
+				encode_block_sequential(writer,t,block,&mut dc[*i]);
+			}
+			else if scan.start == 0 {
+				if scan.high == 0 {
+					let value = block[0] >> scan.low;
+					encode_dc(writer,t,value - dc[*i]);
+					dc[*i] = value;
+				}
+				else {
+					writer.put(((block[0] >> scan.low) & 1) as u32,1);
+				}
+			}
+			else if scan.high == 0 {
+				encode_block_ac_first(writer,t,block,scan,&mut eobrun);
+			}
+			else {
+				encode_block_ac_refine(writer,t,block,scan,&mut eobrun);
+			}
+		}
+	}
+	eobrun.flush(writer,t);
+	writer.flush();
+}
+
+// JFIF APP0 segment
+fn jfif_header(dpi: Option<(f32,f32)>) -> Vec<u8> {
+	let mut dst: Vec<u8> = Vec::new();
+	dst.push16b(0xFFE0);
+	dst.push16b(16);
+	dst.extend_from_slice(b"JFIF\0");
+	dst.push16b(0x0101);  // version 1.01
+	match dpi {
+		Some((x,y)) => {
+			dst.push(1);  // dots per inch
+			dst.push16b(x.round().clamp(1.0,65535.0) as u16);
+			dst.push16b(y.round().clamp(1.0,65535.0) as u16);
+		},
+		None => {
+			dst.push(0);  // no units, aspect ratio only
+			dst.push16b(1);
+			dst.push16b(1);
+		},
+	}
+	dst.push(0);  // no thumbnail
+	dst.push(0);
+	dst
+}
+
+// write a complete JPEG from quantized components, markers are complete segments written after SOI
+fn write_jpeg(width: usize,height: usize,qtables: &[[u8; 64]],components: &[EncodeComponent],markers: &[Vec<u8>],options: &EncodeOptions) -> Result<Vec<u8>,String> {
+	let all = (1 << components.len()) - 1;
+	let progressive = !options.scans.is_empty();
+	let scans: Vec<Scan> = if progressive {
+		options.scans.iter().map(|s| Scan { mask: s.mask & all,..*s }).filter(|s| s.mask != 0).collect()
+	}
+	else {
+		vec![Scan { mask: all,start: 0,end: 63,high: 0,low: 0, }]
+	};
+	for scan in scans.iter() {
+		let dc = scan.start == 0;
+		if (scan.end > 63) || (scan.start > scan.end) || (progressive && (dc != (scan.end == 0))) || ((scan.high != 0) && (scan.high != scan.low + 1)) || (!dc && (scan.mask.count_ones() != 1)) || (scan.low > 13) {
+			return Err("Invalid scan script".to_string());
+		}
+	}
+
+	let mut dst: Vec<u8> = Vec::new();
+	dst.push16b(0xFFD8);  // SOI
+
+	// APPn and COM segments
+	for marker in markers.iter() {
+		dst.extend_from_slice(marker);
+	}
+
+	for (i,qtable) in qtables.iter().enumerate() {
+		write_qtable(&mut dst,i as u8,qtable);
+	}
+
+	// frame header
+	dst.push16b(if progressive { 0xFFC2 } else { 0xFFC0 });
+	dst.push16b((8 + 3 * components.len()) as u16);
+	dst.push(8);
+	dst.push16b(height as u16);
+	dst.push16b(width as u16);
+	dst.push(components.len() as u8);
+	for c in components.iter() {
+		dst.push(c.id);
+		dst.push(c.samp);
+		dst.push(c.tq as u8);
+	}
+
+	let optimize = options.optimize_huffman || progressive;
+	if !optimize {
+		write_huffman_table(&mut dst,0x00,&STD_DC_LUMINANCE_BITS,&STD_DC_LUMINANCE_VALUES);
+		write_huffman_table(&mut dst,0x10,&STD_AC_LUMINANCE_BITS,&STD_AC_LUMINANCE_VALUES);
+		if components.len() > 1 {
+			write_huffman_table(&mut dst,0x01,&STD_DC_CHROMINANCE_BITS,&STD_DC_CHROMINANCE_VALUES);
+			write_huffman_table(&mut dst,0x11,&STD_AC_CHROMINANCE_BITS,&STD_AC_CHROMINANCE_VALUES);
+		}
+	}
+
+	if options.restart_interval != 0 {
+		dst.push16b(0xFFDD);
+		dst.push16b(4);
+		dst.push16b(options.restart_interval as u16);
+	}
+
+	for scan in scans.iter() {
+		let (mut dcht,mut acht) = standard_tables();
+		if optimize {
+			// gather symbol statistics first, then write the optimal tables for this scan
+			let (cdcht,cacht) = standard_tables();
+			let mut counter = Writer::new(cdcht,cacht,true);
+			encode_scan(&mut counter,components,scan,options.restart_interval);
+			for t in 0..2 {
+				if let Some(table) = optimized_table(&mut dst,t as u8,&counter.dc_counts[t]) {
+					dcht[t] = table;
+				}
+				if let Some(table) = optimized_table(&mut dst,0x10 | t as u8,&counter.ac_counts[t]) {
+					acht[t] = table;
+				}
+			}
+		}
+		let mut writer = Writer::new(dcht,acht,false);
+
+		// scan header
+		let count = scan.mask.count_ones() as usize;
+		dst.push16b(0xFFDA);
+		dst.push16b((6 + 2 * count) as u16);
+		dst.push(count as u8);
+		for (i,c) in components.iter().enumerate() {
+			if (scan.mask & (1 << i)) != 0 {
+				dst.push(c.id);
+				dst.push(if i == 0 { 0x00 } else { 0x11 });
+			}
+		}
+		dst.push(scan.start);
+		dst.push(scan.end);
+		dst.push((scan.high << 4) | scan.low);
+
+		encode_scan(&mut writer,components,scan,options.restart_interval);
+		dst.extend_from_slice(&writer.data);
+	}
+	dst.push16b(0xFFD9);  // EOI
+	Ok(dst)
+}
+
+fn standard_tables() -> ([EncodeTable; 2],[EncodeTable; 2]) {
+	(
+		[EncodeTable::new(&STD_DC_LUMINANCE_BITS,&STD_DC_LUMINANCE_VALUES),EncodeTable::new(&STD_DC_CHROMINANCE_BITS,&STD_DC_CHROMINANCE_VALUES)],
+		[EncodeTable::new(&STD_AC_LUMINANCE_BITS,&STD_AC_LUMINANCE_VALUES),EncodeTable::new(&STD_AC_CHROMINANCE_BITS,&STD_AC_CHROMINANCE_VALUES)],
+	)
+}
+
+// optimal table for the symbol counts, written to dst; None when the table is not used
+fn optimized_table(dst: &mut Vec<u8>,class_id: u8,counts: &[u32; 256]) -> Option<EncodeTable> {
+	if counts.iter().all(|c| *c == 0) {
+		return None;
+	}
+	let (bits,huffval) = optimal_table(counts);
+	write_huffman_table(dst,class_id,&bits,&huffval);
+	Some(EncodeTable::new(&bits,&huffval))
+}
+
+pub fn encode(image: &Image) -> Result<Vec<u8>,String> {
+	encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options(image: &Image,options: &EncodeOptions) -> Result<Vec<u8>,String> {
+	if (image.width == 0) || (image.height == 0) || (image.width > 65535) || (image.height > 65535) {
+		return Err("Invalid image size".to_string());
+	}
+	let gray = image.data.iter().all(|p| (((p >> 16) & 255) == ((p >> 8) & 255)) && (((p >> 8) & 255) == (p & 255)));
+	let (hf,vf) = if gray {
+		(1,1)
+	}
+	else {
+		match options.subsampling {
+			Subsampling::S444 => (1,1),
+			Subsampling::S422 => (2,1),
+			Subsampling::S420 => (2,2),
+		}
+	};
+
+	// pad to whole MCUs by repeating the last column and row
+	let mcuwidth = image.width.div_ceil(8 * hf);
+	let mcuheight = image.height.div_ceil(8 * vf);
+	let pwidth = mcuwidth * 8 * hf;
+	let pheight = mcuheight * 8 * vf;
+	let mut yplane = vec![0f32; pwidth * pheight];
+	let mut uplane = vec![0f32; if gray { 0 } else { pwidth * pheight }];
+	let mut vplane = vec![0f32; if gray { 0 } else { pwidth * pheight }];
+	for y in 0..pheight {
+		let sy = if y < image.height { y } else { image.height - 1 };
+		for x in 0..pwidth {
+			let sx = if x < image.width { x } else { image.width - 1 };
+			let p = image.data[sy * image.width + sx];
+			let r = ((p >> 16) & 255) as f32;
+			let g = ((p >> 8) & 255) as f32;
+			let b = (p & 255) as f32;
+			yplane[y * pwidth + x] = 0.299 * r + 0.587 * g + 0.114 * b;
+			if !gray {
+				uplane[y * pwidth + x] = -0.168736 * r - 0.331264 * g + 0.5 * b + 128.0;
+				vplane[y * pwidth + x] = 0.5 * r - 0.418688 * g - 0.081312 * b + 128.0;
+			}
+		}
+	}
+
+	let mut qtables = vec![scale_qtable(&STD_LUMINANCE_QT,options.quality)];
+	let mut components = vec![EncodeComponent {
+		id: 1,
+		samp: ((hf << 4) | vf) as u8,
+		tq: 0,
+		bwidth: pwidth / 8,
+		bheight: pheight / 8,
+		cwidth: image.width.div_ceil(8),
+		cheight: image.height.div_ceil(8),
+		blocks: make_blocks(&yplane,pwidth / 8,pheight / 8,&qtables[0]),
+	}];
+	if !gray {
+		qtables.push(scale_qtable(&STD_CHROMINANCE_QT,options.quality));
+		let cwidth = pwidth / hf;
+		let cheight = pheight / vf;
+		for (id,plane) in [(2,&uplane),(3,&vplane)].iter() {
+			let plane = downsample(plane,pwidth,pheight,hf,vf);
+			components.push(EncodeComponent {
+				id: *id,
+				samp: 0x11,
+				tq: 1,
+				bwidth: cwidth / 8,
+				bheight: cheight / 8,
+				cwidth: image.width.div_ceil(hf).div_ceil(8),
+				cheight: image.height.div_ceil(vf).div_ceil(8),
+				blocks: make_blocks(&plane,cwidth / 8,cheight / 8,&qtables[1]),
+			});
+		}
+	}
+	let mut markers = vec![jfif_header(options.dpi)];
+	if let Some(profile) = &options.icc_profile {
+		if profile.len() > 255 * ICC_CHUNK {
+			return Err("ICC profile too large".to_string());
+		}
+		markers.extend(icc_segments(profile));
+	}
+	write_jpeg(image.width,image.height,&qtables,&components,&markers,options)
+}
+
+// APPn or COM segment with the JFIF density swapped, for transposing transforms
+fn transposed_jfif(segment: &[u8]) -> Vec<u8> {
+	let mut result = segment.to_vec();
+	if (result.len() >= 16) && (from_be16(&result[0..2]) == 0xFFE0) && (&result[4..9] == b"JFIF\0") {
+		result[12..16].rotate_left(2);
+	}
+	result
+}
+
+// lossless transformation
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub enum Transform {
+	#[default]
+	None,
+	FlipHorizontal,
+	FlipVertical,
+	Transpose,  // mirror over the top-left to bottom-right diagonal
+	Transverse,  // mirror over the top-right to bottom-left diagonal
+	Rotate90,  // clockwise
+	Rotate180,
+	Rotate270,
+}
+
+impl Transform {
+	// (transpose, mirror source columns, mirror source rows)
+	fn parts(self) -> (bool,bool,bool) {
+		match self {
+			Transform::None => (false,false,false),
+			Transform::FlipHorizontal => (false,true,false),
+			Transform::FlipVertical => (false,false,true),
+			Transform::Transpose => (true,false,false),
+			Transform::Transverse => (true,true,true),
+			Transform::Rotate90 => (true,false,true),
+			Transform::Rotate180 => (false,true,true),
+			Transform::Rotate270 => (true,true,false),
+		}
+	}
+}
+
+// transform settings
+#[derive(Copy,Clone,Default)]
+pub struct TransformOptions {
+	pub transform: Transform,
+	pub crop: Option<(usize,usize,usize,usize)>,  // x, y, width, height in the transformed image, x and y are rounded down to whole MCUs
+	pub optimize_huffman: bool,
+	pub progressive: bool,
+}
+
+// rotate, flip and/or crop a JPEG without decoding the pixels, by rearranging the quantized coefficient blocks; APPn and COM segments are kept
+// partial MCUs at an edge that would be mirrored are dropped, like jpegtran -trim
+pub fn transform(src: &[u8],options: &TransformOptions) -> Result<Vec<u8>,String> {
+	let source = read_coefficients(src)?;
+	let (transpose,mirror_x,mirror_y) = options.transform.parts();
+	let hmax = source.components.iter().map(|c| c.hsamp).max().unwrap_or(1);
+	let vmax = source.components.iter().map(|c| c.vsamp).max().unwrap_or(1);
+	let twidth = if mirror_x { (source.width / (8 * hmax)) * 8 * hmax } else { source.width };
+	let theight = if mirror_y { (source.height / (8 * vmax)) * 8 * vmax } else { source.height };
+	if (twidth == 0) || (theight == 0) {
+		return Err("Image too small to transform".to_string());
+	}
+	let (width,height,ohmax,ovmax) = if transpose { (theight,twidth,vmax,hmax) } else { (twidth,theight,hmax,vmax) };
+
+	// crop rectangle in the transformed image
+	let (x0,y0,cwidth,cheight) = match options.crop {
+		Some((x,y,w,h)) => {
+			if (x >= width) || (y >= height) || (w == 0) || (h == 0) {
+				return Err("Invalid crop".to_string());
+			}
+			let x0 = (x / (8 * ohmax)) * 8 * ohmax;
+			let y0 = (y / (8 * ovmax)) * 8 * ovmax;
+			(x0,y0,(x + w).min(width) - x0,(y + h).min(height) - y0)
+		},
+		None => (0,0,width,height),
+	};
+
+	let mut components = Vec::new();
+	for c in source.components.iter() {
+		let nw = twidth.div_ceil(8 * hmax) * c.hsamp;  // source blocks that take part
+		let nh = theight.div_ceil(8 * vmax) * c.vsamp;
+		let (oh,ov) = if transpose { (c.vsamp,c.hsamp) } else { (c.hsamp,c.vsamp) };
+		let ox = (x0 / (8 * ohmax)) * oh;
+		let oy = (y0 / (8 * ovmax)) * ov;
+		let bwidth = cwidth.div_ceil(8 * ohmax) * oh;
+		let bheight = cheight.div_ceil(8 * ovmax) * ov;
+		let mut blocks = Vec::with_capacity(bwidth * bheight);
+		for by in 0..bheight {
+			for bx in 0..bwidth {
+				let (a,b) = if transpose { (by + oy,bx + ox) } else { (bx + ox,by + oy) };
+				let sx = if mirror_x { nw - 1 - a } else { a };
+				let sy = if mirror_y { nh - 1 - b } else { b };
+				let src_block = &c.blocks[sy * c.bwidth + sx];
+				let mut block = [0i32; 64];
+				for (k,coeff) in block.iter_mut().enumerate() {
+					let (su,sv) = if transpose { (k >> 3,k & 7) } else { (k & 7,k >> 3) };
+					let mut value = src_block[sv * 8 + su];
+					if (mirror_x && ((su & 1) != 0)) != (mirror_y && ((sv & 1) != 0)) {
+						value = -value;
+					}
+					*coeff = value;
+				}
+				blocks.push(block);
+			}
+		}
+		let mut qtable = c.qtable;
+		if transpose {
+			for (k,q) in qtable.iter_mut().enumerate() {
+				*q = c.qtable[(k & 7) * 8 + (k >> 3)];
+			}
+		}
+		components.push(JpegComponent {
+			id: c.id,
+			hsamp: oh,
+			vsamp: ov,
+			qtable,
+			bwidth,
+			bheight,
+			blocks,
+		});
+	}
+	let result = JpegCoefficients {
+		width: cwidth,
+		height: cheight,
+		components,
+		markers: if transpose { source.markers.iter().map(|m| transposed_jfif(m)).collect() } else { source.markers },
+	};
+	let encode_options = EncodeOptions {
+		optimize_huffman: options.optimize_huffman,
+		scans: if options.progressive { progressive_scans() } else { Vec::new() },
+		..Default::default()
+	};
+	write_coefficients(&result,&encode_options)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// random numbers in -l..=h, as specified by IEEE 1180
+	struct IeeeRandom {
+		x: u32,
+	}
+
+	impl IeeeRandom {
+		fn next(&mut self,l: i32,h: i32) -> i32 {
+			self.x = self.x.wrapping_mul(1103515245).wrapping_add(12345);
+			let i = self.x & 0x7FFFFFFE;
+			let x = ((i as f64) / (0x7FFFFFFF as f64)) * ((l + h + 1) as f64);
+			(x as i32) - l
+		}
+	}
+
+	// double precision separable 2D transform with basis c: forward (c^T x c) or inverse (c x c^T)
+	fn transform(c: &[[f64; 8]; 8],inp: &[f64; 64],inverse: bool) -> [f64; 64] {
+		let m = |a: usize,b: usize| if inverse { c[a][b] } else { c[b][a] };
+		let mut temp = [0f64; 64];
+		for y in 0..8 {
+			for x in 0..8 {
+				temp[y * 8 + x] = (0..8).map(|k| m(x,k) * inp[y * 8 + k]).sum();
+			}
+		}
+		let mut out = [0f64; 64];
+		for y in 0..8 {
+			for x in 0..8 {
+				out[y * 8 + x] = (0..8).map(|k| m(y,k) * temp[k * 8 + x]).sum();
+			}
+		}
+		out
+	}
+
+	// IEEE 1180 statistics
+	struct Errors {
+		peak: i32,  // peak absolute error
+		pmse: f64,  // worst pixel mean square error
+		omse: f64,  // overall mean square error
+		pme: f64,  // worst pixel mean error
+		ome: f64,  // overall mean error
+	}
+
+	// measure an IDCT on a folded block over 10000 blocks of random samples in -l..=h, optionally negated
+	fn ieee1180(idct: &dyn Fn(&mut [i32]),l: i32,h: i32,negate: bool) -> Errors {
+		const BLOCKS: usize = 10000;
+		// C(u) / 2 * cos((2x + 1) * u * pi / 16), indexed [x][u]
+		let mut c = [[0f64; 8]; 8];
+		for (x,row) in c.iter_mut().enumerate() {
+			for (u,value) in row.iter_mut().enumerate() {
+				let cu = if u == 0 { std::f64::consts::FRAC_1_SQRT_2 } else { 1.0 };
+				*value = 0.5 * cu * (((2 * x + 1) * u) as f64 * std::f64::consts::PI / 16.0).cos();
+			}
+		}
+		let mut random = IeeeRandom { x: 1 };
+		let mut peak = 0;
+		let mut sum = [0i64; 64];
+		let mut squares = [0i64; 64];
+		for _ in 0..BLOCKS {
+			let mut samples = [0f64; 64];
+			for sample in samples.iter_mut() {
+				let value = random.next(l,h);
+				*sample = (if negate { -value } else { value }) as f64;
+			}
+			// forward DCT, rounded and clamped to 12 bits
+			let mut coefficients = [0f64; 64];
+			for (coefficient,value) in coefficients.iter_mut().zip(transform(&c,&samples,false).iter()) {
+				*coefficient = clamp(value.round(),-2048.0,2047.0);
+			}
+			let reference = transform(&c,&coefficients,true);
+			let mut block = [0i32; 64];
+			for v in 0..8 {
+				for u in 0..8 {
+					block[FOLDED_ROW[v] * 8 + u] = coefficients[v * 8 + u] as i32;
+				}
+			}
+			idct(&mut block);
+			for i in 0..64 {
+				let error = clamp(block[i],-256,255) - clamp(reference[i].round() as i32,-256,255);
+				peak = peak.max(error.abs());
+				sum[i] += error as i64;
+				squares[i] += (error * error) as i64;
+			}
+		}
+		let blocks = BLOCKS as f64;
+		Errors {
+			peak,
+			pmse: squares.iter().map(|s| (*s as f64) / blocks).fold(0.0,f64::max),
+			omse: (squares.iter().sum::<i64>() as f64) / (blocks * 64.0),
+			pme: sum.iter().map(|s| ((*s as f64) / blocks).abs()).fold(0.0,f64::max),
+			ome: ((sum.iter().sum::<i64>() as f64) / (blocks * 64.0)).abs(),
+		}
+	}
+
+	// run the IEEE 1180 input ranges against the limits
+	fn check(idct: &dyn Fn(&mut [i32]),limits: &Errors) {
+		for (l,h) in [(256,255),(5,5),(300,300)].iter() {
+			for negate in [false,true].iter() {
+				let errors = ieee1180(idct,*l,*h,*negate);
+				let label = format!("-{}..={}{}",l,h,if *negate { " negated" } else { "" });
+				assert!(errors.peak <= limits.peak,"{}: peak error {}",label,errors.peak);
+				assert!(errors.pmse <= limits.pmse,"{}: pixel mean square error {}",label,errors.pmse);
+				assert!(errors.omse <= limits.omse,"{}: overall mean square error {}",label,errors.omse);
+				assert!(errors.pme <= limits.pme,"{}: pixel mean error {}",label,errors.pme);
+				assert!(errors.ome <= limits.ome,"{}: overall mean error {}",label,errors.ome);
+			}
+		}
+		// all zero in, all zero out
+		let mut block = [0i32; 64];
+		idct(&mut block);
+		assert!(block.iter().all(|v| *v == 0));
+	}
+
+	const IEEE1180: Errors = Errors { peak: 1,pmse: 0.06,omse: 0.02,pme: 0.015,ome: 0.0015, };
+
+	#[test]
+	fn idct_accurate() {
+		check(&|block| convert_block_accurate(block,&[1; 64]),&IEEE1180);
+	}
+
+	#[test]
+	fn idct_float() {
+		check(&|block| convert_block_float(block,&[1; 64],8),&IEEE1180);
+	}
+
+	// the fast IDCT truncates its 8-bit fixed point results and does not meet IEEE 1180, so it is held to its current
+	// accuracy instead
+	#[test]
+	fn idct_fast() {
+		check(&|block| convert_block(block,&[1; 64]),&Errors { peak: 5,pmse: 3.0,omse: 1.2,pme: 1.5,ome: 0.4, });
+	}
+
+	// Fibonacci counts give the deepest huffman trees, here with codes of up to 46 bits
+	#[test]
+	fn optimal_table_long_codes() {
+		let mut counts = [0u32; 256];
+		let (mut a,mut b) = (1u32,2u32);
+		for count in counts.iter_mut().take(46) {
+			*count = a;
+			let next = a.saturating_add(b);
+			a = b;
+			b = next;
+		}
+		assert!(code_sizes(&counts).iter().any(|size| *size > 32));
+		let (bits,huffval) = optimal_table(&counts);
+		assert_eq!(bits.iter().map(|b| *b as usize).sum::<usize>(),46);
+		assert_eq!(huffval.len(),46);
+		// the codes fit, leaving room for the all 1-bits code
+		let kraft: u64 = bits.iter().enumerate().map(|(i,b)| (*b as u64) << (15 - i)).sum();
+		assert!(kraft < 1 << 16);
+	}
 }
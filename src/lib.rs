@@ -4,7 +4,10 @@
 mod image;
 pub use image::*;
 
+pub mod icc;
+
 pub mod bmp;
+pub mod ico;
 pub mod png;
 pub mod jpeg;
 pub mod tga;
@@ -187,7 +187,36 @@ impl<'a> ZipReader<'a> {
     }
 }
 
-fn inflate(src: &[u8],inflated_size: usize) -> Result<Vec<u8>,String> {
+// chunk CRC (ISO 3309)
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if (c & 1) != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = make_crc_table();
+
+fn crc32(src: &[u8]) -> u32 {
+    let mut c = 0xFFFFFFFFu32;
+    for b in src.iter() {
+        c = CRC_TABLE[((c ^ (*b as u32)) & 255) as usize] ^ (c >> 8);
+    }
+    c ^ 0xFFFFFFFF
+}
+
+// with partial set, a stored block that runs past inflated_size ends the output there instead of being an error, for reading
+// only the start of a stream like the iCCP profile size
+fn inflate(src: &[u8],inflated_size: usize,partial: bool) -> Result<Vec<u8>,String> {
 
     let mut dst: Vec<u8> = vec![0; inflated_size as usize];
     let mut reader = ZipReader::new(&src);
@@ -221,9 +250,9 @@ fn inflate(src: &[u8],inflated_size: usize) -> Result<Vec<u8>,String> {
     let mut hlitlen_tables: &Vec<[i16; TABLE_SIZE]> = &default_hlitlen_tables;
     let mut hdist_tables: &Vec<[i16; TABLE_SIZE]> = &default_hdist_tables;
 
-    // main loop
+    // main loop, until the final block or until dst is full
     let mut is_final = false;
-    while !is_final {
+    while !is_final && (dp < dst.len()) {
 
         // get final block and type bits
         match reader.read_bits(1) {
@@ -241,8 +270,16 @@ fn inflate(src: &[u8],inflated_size: usize) -> Result<Vec<u8>,String> {
                 let mut sp = reader.align();
                 let length = (((src[sp + 1] as u16) << 8) | (src[sp] as u16)) as usize;
                 sp += 4;
-                if (sp + length > src.len()) || (dp + length > dst.len()) {
-                    return Err(format!("data corrupt (sp ({}) or dp ({}) + length ({}) too big)",sp,dp,length));
+                if sp + length > src.len() {
+                    return Err(format!("data corrupt (sp ({}) + length ({}) too big)",sp,length));
+                }
+                if dp + length > dst.len() {
+                    if !partial {
+                        return Err(format!("data corrupt (dp ({}) + length ({}) too big)",dp,length));
+                    }
+                    let rest = dst.len() - dp;
+                    dst[dp..].copy_from_slice(&src[sp..sp + rest]);
+                    return Ok(dst);
                 }
                 dst[dp..dp + length].copy_from_slice(&src[sp..sp + length]);
                 sp += length;
@@ -450,16 +487,21 @@ fn clampf(v: f32,min: f32,max: f32) -> f32 {
     }
 }
 
+// 0..1 sample to 8 bits, rounded; gamma only applies to color, alpha is always linear
+fn to8(v: f32,gamma: f32) -> u32 {
+    (clampf(v.powf(gamma),0.0,1.0) * 255.0 + 0.5) as u32
+}
+
 fn make_lf(l: f32,gamma: f32) -> u32 {
-    let ul = (clampf(l.powf(gamma),0.0,1.0) * 255.0) as u32;
+    let ul = to8(l,gamma);
     return 0xFF000000 | (ul << 16) | (ul << 8) | ul;
 }
 
 fn make_rgbaf(r: f32,g: f32,b: f32,a: f32,gamma: f32) -> u32 {
-    let ur = (clampf(r.powf(gamma),0.0,1.0) * 255.0) as u32;
-    let ug = (clampf(g.powf(gamma),0.0,1.0) * 255.0) as u32;
-    let ub = (clampf(b.powf(gamma),0.0,1.0) * 255.0) as u32;
-    let ua = (clampf(a.powf(gamma),0.0,1.0) * 255.0) as u32;
+    let ur = to8(r,gamma);
+    let ug = to8(g,gamma);
+    let ub = to8(b,gamma);
+    let ua = to8(a,1.0);
     return (ua << 24) | (ur << 16) | (ug << 8) | ub;	
 }
 
@@ -467,15 +509,24 @@ fn make_c(c: u32,gamma: f32) -> u32 {
     let r = (((c >> 16) & 255) as f32) / 255.0;
     let g = (((c >> 8) & 255) as f32) / 255.0;
     let b = ((c & 255) as f32) / 255.0;
-    let a = ((c >> 24) as f32) / 255.0;
-    let ur = (clampf(r.powf(gamma),0.0,1.0) * 255.0) as u32;
-    let ug = (clampf(g.powf(gamma),0.0,1.0) * 255.0) as u32;
-    let ub = (clampf(b.powf(gamma),0.0,1.0) * 255.0) as u32;
-    let ua = (clampf(a.powf(gamma),0.0,1.0) * 255.0) as u32;
-    return (ua << 24) | (ur << 16) | (ug << 8) | ub;	
+    let ur = to8(r,gamma);
+    let ug = to8(g,gamma);
+    let ub = to8(b,gamma);
+    (c & 0xFF000000) | (ur << 16) | (ug << 8) | ub
+}
+
+// pixels that match the tRNS key are fully transparent
+fn keyed(p: u32,transparent: bool) -> u32 {
+    if transparent {
+        p & 0x00FFFFFF
+    }
+    else {
+        p
+    }
 }
 
-fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: usize,x0: usize,y0: usize,dx: usize,dy: usize,itype: u16,palette: &[u32; 256],gamma: f32) {
+#[allow(clippy::too_many_arguments)]
+fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: usize,x0: usize,y0: usize,dx: usize,dy: usize,itype: u16,palette: &[u32; 256],key: Option<[u16; 3]>,gamma: f32) {
     let mut sp = 0;
     match itype {
         TYPE_L1 => {
@@ -484,16 +535,16 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
                     let d = src[sp];
                     sp += 1;
                     for i in 0..8 {
-                        let l = if(d & (0x80 >> i)) != 0 { 1.0 } else { 0.0 };
-                        dst[(y0 + y * dy) * stride + x0 + (x * 8 + i) * dx] = make_lf(l,gamma);
+                        let v = (d >> (7 - i)) & 1;
+                        dst[(y0 + y * dy) * stride + x0 + (x * 8 + i) * dx] = keyed(make_lf(v as f32,gamma),key == Some([v as u16,0,0]));
                     }
                 }
                 if (width & 7) != 0 {
                     let d = src[sp];
                     sp += 1;
-                    for i in 0..8 {
-                        let l = if(d & (0x80 >> i)) != 0 { 1.0 } else { 0.0 };
-                        dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFF8) + i) * dx] = make_lf(l,gamma);
+                    for i in 0..(width & 7) {
+                        let v = (d >> (7 - i)) & 1;
+                        dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFF8) + i) * dx] = keyed(make_lf(v as f32,gamma),key == Some([v as u16,0,0]));
                     }
                 }
             }
@@ -524,14 +575,16 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
                     let d = src[sp];
                     sp += 1;
                     for i in 0..4 {
-                        dst[(y0 + y * dy) * stride + x0 + (x * 4 + i) * dx] = make_lf(GRAY2[((d >> ((3 - i) * 2)) & 3) as usize],gamma);
+                        let v = (d >> ((3 - i) * 2)) & 3;
+                        dst[(y0 + y * dy) * stride + x0 + (x * 4 + i) * dx] = keyed(make_lf(GRAY2[v as usize],gamma),key == Some([v as u16,0,0]));
                     }
                 }
                 if(width & 3) != 0 {
                     let d = src[sp];
                     sp += 1;
                     for i in 0..(width & 3) {
-                        dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFFC) + i) * dx] = make_lf(GRAY2[((d >> ((3 - i) * 2)) & 3) as usize],gamma);
+                        let v = (d >> ((3 - i) * 2)) & 3;
+                        dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFFC) + i) * dx] = keyed(make_lf(GRAY2[v as usize],gamma),key == Some([v as u16,0,0]));
                     }
                 }
             }
@@ -560,11 +613,13 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
                     let d = src[sp];
                     sp += 1;
                     for i in 0..2 {
-                        dst[(y0 + y * dy) * stride + x0 + (x * 2 + i) * dx] = make_lf(GRAY4[((d >> ((1 >> i) * 4)) & 15) as usize],gamma);
+                        let v = (d >> ((1 >> i) * 4)) & 15;
+                        dst[(y0 + y * dy) * stride + x0 + (x * 2 + i) * dx] = keyed(make_lf(GRAY4[v as usize],gamma),key == Some([v as u16,0,0]));
                     }
                 }
                 if (width & 1) != 0 {
-                    dst[(y0 + y * dy) * stride + x0 + (width & 0xFFFFFFFE) * dx] = make_lf(GRAY4[(src[sp] >> 4) as usize],gamma);
+                    let v = src[sp] >> 4;
+                    dst[(y0 + y * dy) * stride + x0 + (width & 0xFFFFFFFE) * dx] = keyed(make_lf(GRAY4[v as usize],gamma),key == Some([v as u16,0,0]));
                     sp += 1;
                 }
             }
@@ -588,8 +643,9 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
             for y in 0..height {
                 for x in 0..width {
                     let l = (src[sp] as f32) / 255.0;
+                    let transparent = key == Some([src[sp] as u16,0,0]);
                     sp += 1;
-                    dst[(y0 + y * dy) * stride + x0 + x * dx] = make_lf(l,gamma);
+                    dst[(y0 + y * dy) * stride + x0 + x * dx] = keyed(make_lf(l,gamma),transparent);
                 }
             }
         },
@@ -599,8 +655,9 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
                     let r = (src[sp] as f32) / 255.0;
                     let g = (src[sp + 1] as f32) / 255.0;
                     let b = (src[sp + 2] as f32) / 255.0;
+                    let transparent = key == Some([src[sp] as u16,src[sp + 1] as u16,src[sp + 2] as u16]);
                     sp += 3;
-                    dst[(y0 + y * dy) * stride + x0 + x * dx] = make_rgbaf(r,g,b,1.0,gamma);
+                    dst[(y0 + y * dy) * stride + x0 + x * dx] = keyed(make_rgbaf(r,g,b,1.0,gamma),transparent);
                 }
             }
         },
@@ -638,28 +695,30 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
         TYPE_L16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let l = (src[sp] as f32) / 255.0;
+                    let l = (from_be16(&src[sp..sp + 2]) as f32) / 65535.0;
+                    let transparent = key == Some([from_be16(&src[sp..sp + 2]),0,0]);
                     sp += 2;
-                    dst[(y0 + y * dy) * stride + x0 + x * dx] = make_lf(l,gamma);
+                    dst[(y0 + y * dy) * stride + x0 + x * dx] = keyed(make_lf(l,gamma),transparent);
                 }
             }
         },
         TYPE_RGB16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let r = (src[sp] as f32) / 255.0;
-                    let g = (src[sp + 2] as f32) / 255.0;
-                    let b = (src[sp + 4] as f32) / 255.0;
+                    let r = (from_be16(&src[sp..sp + 2]) as f32) / 65535.0;
+                    let g = (from_be16(&src[sp + 2..sp + 4]) as f32) / 65535.0;
+                    let b = (from_be16(&src[sp + 4..sp + 6]) as f32) / 65535.0;
+                    let transparent = key == Some([from_be16(&src[sp..sp + 2]),from_be16(&src[sp + 2..sp + 4]),from_be16(&src[sp + 4..sp + 6])]);
                     sp += 6;
-                    dst[(y0 + y * dy) * stride + x0 + x * dx] = make_rgbaf(r,g,b,1.0,gamma);
+                    dst[(y0 + y * dy) * stride + x0 + x * dx] = keyed(make_rgbaf(r,g,b,1.0,gamma),transparent);
                 }
             }
         },
         TYPE_LA16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let l = (src[sp] as f32) / 255.0;
-                    let a = (src[sp + 2] as f32) / 255.0;
+                    let l = (from_be16(&src[sp..sp + 2]) as f32) / 65535.0;
+                    let a = (from_be16(&src[sp + 2..sp + 4]) as f32) / 65535.0;
                     sp += 4;
                     dst[(y0 + y * dy) * stride + x0 + x * dx] = make_rgbaf(l,l,l,a,gamma);
                 }
@@ -668,10 +727,10 @@ fn decode_pixels(dst: &mut [u32],src: &[u8],width: usize,height: usize,stride: u
         TYPE_RGBA16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let r = (src[sp] as f32) / 255.0;
-                    let g = (src[sp + 2] as f32) / 255.0;
-                    let b = (src[sp + 4] as f32) / 255.0;
-                    let a = (src[sp + 6] as f32) / 255.0;
+                    let r = (from_be16(&src[sp..sp + 2]) as f32) / 65535.0;
+                    let g = (from_be16(&src[sp + 2..sp + 4]) as f32) / 65535.0;
+                    let b = (from_be16(&src[sp + 4..sp + 6]) as f32) / 65535.0;
+                    let a = (from_be16(&src[sp + 6..sp + 8]) as f32) / 65535.0;
                     sp += 8;
                     dst[(y0 + y * dy) * stride + x0 + x * dx] = make_rgbaf(r,g,b,a,gamma);
                 }
@@ -725,8 +784,85 @@ pub fn test(src: &[u8]) -> Option<(usize,usize)> {
     None
 }
 
+// physical resolution in dots per inch from the pHYs chunk; only a unit of meters gives one
+pub fn dpi(src: &[u8]) -> Option<(f32,f32)> {
+    if (src.len() < 8) || (src[0..8] != [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A]) {
+        return None;
+    }
+    let mut sp: usize = 8;
+    while sp + 8 <= src.len() {
+        let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
+        let chunk_type = from_be32(&src[sp + 4..sp + 8]);
+        sp += 8;
+        if sp + chunk_length > src.len() {
+            return None;
+        }
+        match chunk_type {
+            0x70485973 => { // pHYs
+                // pixels per unit x, y, unit
+                if (chunk_length < 9) || (src[sp + 8] != 1) {
+                    return None;
+                }
+                let x = from_be32(&src[sp..sp + 4]);
+                let y = from_be32(&src[sp + 4..sp + 8]);
+                if (x == 0) || (y == 0) {
+                    return None;
+                }
+                return Some(((x as f32) * 0.0254,(y as f32) * 0.0254));
+            },
+            0x49444154 | 0x49454E44 => { // IDAT, IEND
+                return None;
+            },
+            _ => { },
+        }
+        sp += chunk_length + 4;
+    }
+    None
+}
+
+// embedded ICC profile from the iCCP chunk
+pub fn icc_profile(src: &[u8]) -> Option<Vec<u8>> {
+    if (src.len() < 8) || (src[0..8] != [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A]) {
+        return None;
+    }
+    let mut sp: usize = 8;
+    while sp + 8 <= src.len() {
+        let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
+        let chunk_type = from_be32(&src[sp + 4..sp + 8]);
+        sp += 8;
+        if sp + chunk_length > src.len() {
+            return None;
+        }
+        match chunk_type {
+            0x69434350 => { // iCCP
+                // profile name, 0, compression method, zlib stream
+                let chunk = &src[sp..sp + chunk_length];
+                let name_length = chunk.iter().position(|b| *b == 0)?;
+                if (chunk.len() < name_length + 8) || (chunk[name_length + 1] != 0) {
+                    return None;
+                }
+                let zipped = &chunk[name_length + 2..];
+                // the profile header starts with the profile size
+                let header = inflate(zipped,4,true).ok()?;
+                let size = from_be32(&header) as usize;
+                if (size < 128) || (size > zipped.len() * 1032) {  // deflate expands at most 1032 times
+                    return None;
+                }
+                return inflate(zipped,size,false).ok();
+            },
+            0x49444154 | 0x49454E44 => { // IDAT, IEND
+                return None;
+            },
+            _ => { },
+        }
+        sp += chunk_length + 4;
+    }
+    None
+}
+
 pub fn decode(src: &[u8]) -> Result<Image,String> {
-    if (src[0] != 0x89) ||
+    if (src.len() < 8) ||
+        (src[0] != 0x89) ||
         (src[1] != 0x50) ||
         (src[2] != 0x4E) ||
         (src[3] != 0x47) ||
@@ -756,11 +892,26 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
     let mut palette: [u32; 256] = [0; 256];
     let mut _background: u32 = 0xFF000000;
     let mut gamma: f32 = 1.0;
+    let mut key = None;
     while sp < src.len() {
+        if sp + 12 > src.len() {
+            return Err("Invalid PNG".to_string());
+        }
         let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
         sp += 4;
         let chunk_type = from_be32(&src[sp..sp + 4]);
         sp += 4;
+        if chunk_length > src.len() - sp - 4 {
+            return Err("Invalid PNG".to_string());
+        }
+        // a corrupt critical chunk (uppercase first letter) spoils the image, a corrupt ancillary chunk is left out
+        if crc32(&src[sp - 4..sp + chunk_length]) != from_be32(&src[sp + chunk_length..sp + chunk_length + 4]) {
+            if (chunk_type & 0x20000000) == 0 {
+                return Err("Invalid PNG".to_string());
+            }
+            sp += chunk_length + 4;
+            continue;
+        }
         match chunk_type {
             0x49484452 => { // IHDR
                 width = from_be32(&src[sp..]) as usize;
@@ -790,8 +941,8 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
                     TYPE_RGBA8 => { stride = width * 4; bpp = 4; },
                     TYPE_L16 => { stride = width * 2; bpp = 2; },
                     TYPE_RGB16 => { stride = width * 6; bpp = 6; },
-                    TYPE_LA16 => { stride = width * 2; bpp = 4; },
-                    TYPE_RGBA16 => { stride = width * 4; bpp = 8; },
+                    TYPE_LA16 => { stride = width * 4; bpp = 4; },
+                    TYPE_RGBA16 => { stride = width * 8; bpp = 8; },
                     _ => { return Err("Invalid PNG".to_string()); }
                 }
                 sp += chunk_length;
@@ -856,7 +1007,10 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
                 // histogram
                 sp += chunk_length;
             },
-            // iCCP (ICC color profile)
+            0x69434350 => { // iCCP
+                // ICC color profile, see icc_profile
+                sp += chunk_length;
+            },
             0x69545874 => { // iTXt
                 //println!("iTXt {}",chunk_length);
                 // UTF-8 text
@@ -890,8 +1044,21 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
                 sp += chunk_length;
             },
             0x74524E53 => { // tRNS
-                //println!("tRNS {}",chunk_length);
-                // transparency information
+                // alpha for the first palette entries, or the one gray level or RGB color that is transparent
+                match itype {
+                    TYPE_C1 | TYPE_C2 | TYPE_C4 | TYPE_C8 => {
+                        for i in 0..chunk_length.min(256) {
+                            palette[i] = (palette[i] & 0x00FFFFFF) | ((src[sp + i] as u32) << 24);
+                        }
+                    },
+                    TYPE_L1 | TYPE_L2 | TYPE_L4 | TYPE_L8 | TYPE_L16 if chunk_length >= 2 => {
+                        key = Some([from_be16(&src[sp..sp + 2]),0,0]);
+                    },
+                    TYPE_RGB8 | TYPE_RGB16 if chunk_length >= 6 => {
+                        key = Some([from_be16(&src[sp..sp + 2]),from_be16(&src[sp + 2..sp + 4]),from_be16(&src[sp + 4..sp + 6])]);
+                    },
+                    _ => { },
+                }
                 sp += chunk_length;
             }
             0x7A545874 => { // zTXt
@@ -955,7 +1122,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
             total_dsize += adsize[i];
             //println!("{}: size {}x{}, offset {},{}, step {},{}",i,awidth[i],aheight[i],ax0[i],ay0[i],adx[i],ady[i]);
         }
-        let filtered_data = match inflate(&zipped_data,total_dsize) {
+        let filtered_data = match inflate(&zipped_data,total_dsize,false) {
             Ok(data) => { data },
             Err(msg) => { return Err(msg); },
         };
@@ -964,7 +1131,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
         for i in 0..7 {
             if apresent[i] {
                 let raw_data = unfilter(&filtered_data[sp..sp + adsize[i]],aheight[i],astride[i],bpp);
-                decode_pixels(&mut result.data,&raw_data,awidth[i],aheight[i],width,ax0[i],ay0[i],adx[i],ady[i],itype as u16,&palette,gamma);
+                decode_pixels(&mut result.data,&raw_data,awidth[i],aheight[i],width,ax0[i],ay0[i],adx[i],ady[i],itype,&palette,key,gamma);
                 sp += adsize[i];
             }
         }
@@ -973,7 +1140,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
     {
         //let after0 = Instant::now();
         
-        let filtered_data = match inflate(&zipped_data,(stride + 1) * height) {
+        let filtered_data = match inflate(&zipped_data,(stride + 1) * height,false) {
             Ok(data) => { data },
             Err(msg) => { return Err(msg); },
         };
@@ -985,7 +1152,7 @@ pub fn decode(src: &[u8]) -> Result<Image,String> {
         //let after_unfilter = Instant::now();
         
         let mut result = Image::new(width,height);
-        decode_pixels(&mut result.data,&raw_data,width,height,width,0,0,1,1,itype as u16,&palette,gamma);
+        decode_pixels(&mut result.data,&raw_data,width,height,width,0,0,1,1,itype,&palette,key,gamma);
         
         //let after_decode = Instant::now();
 
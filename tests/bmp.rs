@@ -0,0 +1,457 @@
+// image_formats BMP tests
+// by Desmond Germans, 2019
+
+use image_formats::bmp;
+use image_formats::Image;
+
+fn bmpsuite_file(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/bmp/bmpsuite").join(name)).unwrap()
+}
+
+// resolution in pixels per meter, as stored in the header
+fn ppm(dpi: Option<(f32,f32)>) -> Option<(u32,u32)> {
+    dpi.map(|(x,y)| ((x / 0.0254).round() as u32,(y / 0.0254).round() as u32))
+}
+
+#[test]
+fn dpi() {
+    assert_eq!(ppm(bmp::dpi(&bmpsuite_file("g/pal8.bmp"))),Some((2835,2835)));
+    assert_eq!(ppm(bmp::dpi(&bmpsuite_file("g/pal8nonsquare.bmp"))),Some((2835,1417)));
+    assert_eq!(bmp::dpi(&bmpsuite_file("g/pal8os2.bmp")),None);
+    // a garbage resolution is reported as stored, except in strict mode
+    let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+    assert_eq!(ppm(bmp::dpi(&bmpsuite_file("b/baddens1.bmp"))),Some((30000000,3)));
+    assert_eq!(ppm(bmp::dpi(&bmpsuite_file("b/baddens2.bmp"))),Some((3,30000000)));
+    assert_eq!(bmp::dpi_with_options(&bmpsuite_file("b/baddens1.bmp"),&strict),None);
+    assert_eq!(bmp::dpi_with_options(&bmpsuite_file("b/baddens2.bmp"),&strict),None);
+    assert_eq!(ppm(bmp::dpi_with_options(&bmpsuite_file("g/pal8nonsquare.bmp"),&strict)),Some((2835,1417)));
+    let image = Image::new(3,2);
+    assert_eq!(ppm(bmp::dpi(&bmp::encode(&image).unwrap())),Some((2835,2835)));  // 72 DPI when the resolution is unknown
+    let options = bmp::EncodeOptions { dpi: Some((300.0,150.0)),..Default::default() };
+    assert_eq!(ppm(bmp::dpi(&bmp::encode_with_options(&image,&options).unwrap())),Some((11811,5906)));
+}
+
+// test pattern with distinct rows and columns, opaque
+fn pattern(width: usize,height: usize) -> Image {
+    let mut image = Image::new(width,height);
+    for (i,p) in image.data.iter_mut().enumerate() {
+        *p = 0xFF000000 | ((((i % width) * 37) as u32 & 255) << 16) | ((((i / width) * 53) as u32 & 255) << 8) | ((i * 11) as u32 & 255);
+    }
+    image
+}
+
+// core headers store the height unsigned, so they are written bottom-up even when top-down is asked for
+#[test]
+fn core_header_bottom_up() {
+    let image = pattern(5,3);
+    for bottom_up in [false,true].iter() {
+        let options = bmp::EncodeOptions { format: bmp::Format::Rgb24,header: bmp::Header::Core,bottom_up: *bottom_up,..Default::default() };
+        let src = bmp::encode_with_options(&image,&options).unwrap();
+        assert_eq!(u32::from_le_bytes([src[14],src[15],src[16],src[17]]),12);
+        assert_eq!(i16::from_le_bytes([src[20],src[21]]),3,"bottom_up {}",bottom_up);
+        let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+        assert!(bmp::decode_with_options(&src,&strict).unwrap().data == image.data,"bottom_up {}",bottom_up);
+    }
+}
+
+// xorshift, so the generated images are the same on every run
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self,n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 as usize) % n
+    }
+}
+
+// image with runs, alternating pairs, literal pixels and transparent stretches, fully transparent rows and row groups,
+// and transparent rows at the top and bottom; colors come from a 16 entry palette
+fn rle_image(random: &mut Random,width: usize,height: usize) -> Image {
+    let palette: Vec<u32> = (0..16u32).map(|i| 0xFF000000 | (i * 0x0F0B07)).collect();
+    let mut image = Image::new(width,height);
+    for y in 0..height {
+        let row = &mut image.data[y * width..(y + 1) * width];
+        if (y == 0) || (y == height - 1) || (random.next(4) == 0) {
+            continue;  // transparent row
+        }
+        let mut x = 0;
+        while x < width {
+            let count = (1 + random.next(9)).min(width - x);
+            let (a,b) = (palette[random.next(16)],palette[random.next(16)]);
+            for (i,p) in row[x..x + count].iter_mut().enumerate() {
+                *p = match random.next(4) {
+                    0 => 0,  // transparent
+                    _ if count < 3 => palette[random.next(16)],
+                    1 => if (i & 1) == 0 { a } else { b },
+                    2 => palette[random.next(16)],
+                    _ => a,
+                };
+            }
+            x += count;
+        }
+    }
+    image
+}
+
+// counts of (runs, odd length absolute runs, deltas, ends of line, ends of bitmap) in RLE data
+fn rle_codes(data: &[u8],bits: u16) -> [usize; 5] {
+    let mut counts = [0; 5];
+    let mut sp = 0;
+    while sp + 2 <= data.len() {
+        let (count,value) = (data[sp] as usize,data[sp + 1] as usize);
+        sp += 2;
+        match (count,value) {
+            (0,0) => { counts[3] += 1; },
+            (0,1) => { counts[4] += 1; break; },
+            (0,2) => { counts[2] += 1; sp += 2; },
+            (0,n) => {
+                counts[1] += n & 1;
+                let bytes = if bits == 4 { n.div_ceil(2) } else { n };
+                sp += (bytes + 1) & !1;
+            },
+            _ => { counts[0] += 1; },
+        }
+    }
+    counts
+}
+
+// RLE4 and RLE8 encode and decode exactly, in strict mode too, for odd and even widths; skipped transparent pixels come
+// back as transparent black, the palette has no alpha so other pixels come back opaque
+#[test]
+fn rle_round_trip() {
+    let mut random = Random(0x12345678);
+    let mut total = [0; 5];
+    for width in 1..=21 {
+        for height in [1,2,5,9].iter() {
+            let image = rle_image(&mut random,width,*height);
+            for (format,bits) in [(bmp::Format::Palette4,4),(bmp::Format::Palette8,8)].iter() {
+                for skip_transparent in [false,true].iter() {
+                    let options = bmp::EncodeOptions { format: *format,rle: true,skip_transparent: *skip_transparent,..Default::default() };
+                    let src = bmp::encode_with_options(&image,&options).unwrap();
+                    let what = format!("{}x{} RLE{}{}",width,height,bits,if *skip_transparent { " skipping" } else { "" });
+                    let offset = u32::from_le_bytes([src[10],src[11],src[12],src[13]]) as usize;
+                    let codes = rle_codes(&src[offset..],*bits);
+                    for (t,c) in total.iter_mut().zip(codes.iter()) {
+                        *t += c;
+                    }
+                    let expected: Vec<u32> = image.data.iter().map(|p| if *skip_transparent && ((p >> 24) == 0) { 0 } else { p | 0xFF000000 }).collect();
+                    for strict in [false,true].iter() {
+                        let decoded = bmp::decode_with_options(&src,&bmp::DecodeOptions { strict: *strict,..Default::default() }).unwrap();
+                        assert!(decoded.data == expected,"{}{}",what,if *strict { " strict" } else { "" });
+                    }
+                }
+            }
+        }
+    }
+    for (name,count) in ["runs","odd absolute runs","deltas","ends of line","ends of bitmap"].iter().zip(total.iter()) {
+        assert!(*count > 0,"no {}",name);
+    }
+}
+
+fn bmpsuite_files(dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/bmp/bmpsuite").join(dir)).unwrap()
+        .map(|entry| format!("{}/{}",dir,entry.unwrap().file_name().to_string_lossy()))
+        .filter(|name| name.ends_with(".bmp"))
+        .collect();
+    names.sort();
+    names
+}
+
+// every bmpsuite image with few enough colors survives an RLE round trip, apart from its alpha
+#[test]
+fn rle_bmpsuite() {
+    let mut encoded = 0;
+    for name in bmpsuite_files("g").iter().chain(bmpsuite_files("q").iter()) {
+        let image = match bmp::decode(&bmpsuite_file(name)) {
+            Ok(image) => image,
+            Err(_) => { continue; },
+        };
+        for format in [bmp::Format::Palette4,bmp::Format::Palette8].iter() {
+            let options = bmp::EncodeOptions { format: *format,rle: true,skip_transparent: true,..Default::default() };
+            let src = match bmp::encode_with_options(&image,&options) {
+                Ok(src) => src,
+                Err(_) => { continue; },  // too many colors
+            };
+            let decoded = bmp::decode(&src).unwrap();
+            let expected: Vec<u32> = image.data.iter().map(|p| if (p >> 24) == 0 { 0 } else { p | 0xFF000000 }).collect();
+            assert!(decoded.data == expected,"{} {:?}",name,format);
+            encoded += 1;
+        }
+    }
+    assert!(encoded >= 20,"only {} encodes",encoded);
+}
+
+fn bmpsuite_reference(name: &str) -> Image {
+    image_formats::png::decode(&bmpsuite_file(&format!("html/{}",name))).unwrap()
+}
+
+// the delta and cut samples against the bmpsuite reference for each way of filling skipped pixels; the -b references use
+// black, another background color has to show up in exactly the skipped pixels
+#[test]
+fn skipped_pixels() {
+    for name in ["pal4rletrns","pal8rletrns","pal4rlecut","pal8rlecut"].iter() {
+        let src = bmpsuite_file(&format!("q/{}.bmp",name));
+        let transparent = bmpsuite_reference(&format!("{}.png",name));
+        let skipped: Vec<bool> = transparent.data.iter().map(|p| (p >> 24) == 0).collect();
+        assert!(skipped.iter().any(|s| *s),"{}",name);
+        let black = bmpsuite_reference(&format!("{}-b.png",name));
+        let background: Vec<u32> = black.data.iter().zip(skipped.iter()).map(|(p,s)| if *s { 0xFF123456 } else { *p }).collect();
+        for (mode,expected) in [
+            (bmp::SkippedPixels::Transparent,transparent.data.iter().map(|p| if (p >> 24) == 0 { 0 } else { *p }).collect()),
+            (bmp::SkippedPixels::Index0,bmpsuite_reference(&format!("{}-0.png",name)).data),
+            (bmp::SkippedPixels::Background(0xFF000000),black.data.clone()),
+            (bmp::SkippedPixels::Background(0xFF123456),background),
+        ].iter() {
+            let image = bmp::decode_with_options(&src,&bmp::DecodeOptions { skipped_pixels: *mode,..Default::default() }).unwrap();
+            assert_eq!((image.width,image.height),(transparent.width,transparent.height));
+            let wrong = image.data.iter().zip(expected.iter()).filter(|(a,b)| a != b).count();
+            assert!(wrong == 0,"{} {:?}: {} pixels differ",name,mode,wrong);
+        }
+    }
+}
+
+// rgb24jpeg.bmp and rgb24png.bmp as stored (positive height) and as top-down (negative height)
+#[test]
+fn embedded_height_sign() {
+    for name in ["q/rgb24jpeg.bmp","q/rgb24png.bmp"].iter() {
+        let src = bmpsuite_file(name);
+        let offset = u32::from_le_bytes([src[10],src[11],src[12],src[13]]) as usize;
+        let stored = if name.ends_with("jpeg.bmp") { image_formats::jpeg::decode(&src[offset..]) } else { image_formats::png::decode(&src[offset..]) }.unwrap();
+        let flipped = stored.oriented(4);
+        assert!(flipped.data != stored.data,"{}",name);
+        let height = i32::from_le_bytes([src[22],src[23],src[24],src[25]]);
+        assert!(height > 0,"{}",name);
+        let mut top_down = src.clone();
+        top_down[22..26].copy_from_slice(&(-height).to_le_bytes());
+        let lenient = bmp::DecodeOptions::default();
+        let flipping = bmp::DecodeOptions { embedded_bottom_up: true,..Default::default() };
+        let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+        assert!(bmp::decode_with_options(&src,&lenient).unwrap().data == stored.data,"{} positive",name);
+        assert!(bmp::decode_with_options(&src,&flipping).unwrap().data == flipped.data,"{} positive, bottom-up",name);
+        assert!(bmp::decode_with_options(&src,&strict).unwrap().data == stored.data,"{} positive, strict",name);
+        assert!(bmp::decode_with_options(&top_down,&lenient).unwrap().data == stored.data,"{} negative",name);
+        assert!(bmp::decode_with_options(&top_down,&flipping).unwrap().data == stored.data,"{} negative, bottom-up",name);
+        assert!(bmp::decode_with_options(&top_down,&strict).is_err(),"{} negative, strict",name);
+    }
+}
+
+// channel of a bitfield value scaled to 16 bits, rounded
+fn scaled16(v: u32,mask: u32) -> u64 {
+    let max = (mask >> mask.trailing_zeros()) as u64;
+    ((((v & mask) >> mask.trailing_zeros()) as u64) * 65535 + max / 2) / max
+}
+
+// 16-bit channel from linear s2.13, with the sRGB curve for color
+fn linear16(v: i16,color: bool) -> u64 {
+    let f = (v as f64 / 8192.0).clamp(0.0,1.0);
+    let f = if !color { f } else if f <= 0.0031308 { f * 12.92 } else { 1.055 * f.powf(1.0 / 2.4) - 0.055 };
+    (f * 65535.0).round() as u64
+}
+
+// largest difference of the four channels
+fn channel_error(a: u64,b: u64) -> u64 {
+    (0..4).map(|i| ((a >> (i * 16)) & 0xFFFF).abs_diff((b >> (i * 16)) & 0xFFFF)).max().unwrap()
+}
+
+// bitfields with more than 8 bits per channel keep all of them
+#[test]
+fn decode16_bitfields() {
+    for name in ["q/rgba32-1010102.bmp","q/rgb32-111110.bmp"].iter() {
+        let src = bmpsuite_file(name);
+        let offset = u32::from_le_bytes([src[10],src[11],src[12],src[13]]) as usize;
+        let headersize = u32::from_le_bytes([src[14],src[15],src[16],src[17]]);
+        let width = u32::from_le_bytes([src[18],src[19],src[20],src[21]]) as usize;
+        let height = i32::from_le_bytes([src[22],src[23],src[24],src[25]]);
+        assert!(height > 0,"{}",name);
+        let height = height as usize;
+        let mask = |i: usize| u32::from_le_bytes([src[i],src[i + 1],src[i + 2],src[i + 3]]);
+        let (red,green,blue) = (mask(54),mask(58),mask(62));
+        let alpha = if headersize >= 56 { mask(66) } else { 0 };
+        let image = bmp::decode16(&src).unwrap();
+        assert_eq!((image.width,image.height),(width,height));
+        let mut fractions = 0;
+        for y in 0..height {
+            for x in 0..width {
+                let sp = offset + (height - 1 - y) * width * 4 + x * 4;
+                let v = u32::from_le_bytes([src[sp],src[sp + 1],src[sp + 2],src[sp + 3]]);
+                let a = if alpha == 0 { 0xFFFF } else { scaled16(v,alpha) };
+                let expected = (a << 48) | (scaled16(v,red) << 32) | (scaled16(v,green) << 16) | scaled16(v,blue);
+                let p = image.data[y * width + x];
+                assert!(channel_error(p,expected) <= 1,"{} ({},{}): {:016X} instead of {:016X}",name,x,y,p,expected);
+                fractions += (0..3).filter(|i| ((p >> (i * 16)) & 0xFFFF) % 257 != 0).count();
+            }
+        }
+        // more than 8 bits made it through
+        assert!(fractions > width * height,"{}: only {} channels with more than 8 bits",name,fractions);
+        assert!(image.to_image().data == bmp::decode(&src).unwrap().data,"{}",name);
+    }
+}
+
+// 64-bit BMPs: B,G,R,A linear s2.13 fixed point, with the color channels converted to sRGB
+#[test]
+fn decode16_64bit() {
+    let (width,height) = (7usize,3usize);
+    let values: Vec<i16> = (0..width * height * 4).map(|i| ((i as i32 * 2731) % 9000 - 400) as i16).collect();
+    let mut src = Vec::new();
+    src.extend_from_slice(b"BM");
+    src.extend_from_slice(&((54 + values.len() * 2) as u32).to_le_bytes());
+    src.extend_from_slice(&0u32.to_le_bytes());
+    src.extend_from_slice(&54u32.to_le_bytes());
+    src.extend_from_slice(&40u32.to_le_bytes());
+    src.extend_from_slice(&(width as i32).to_le_bytes());
+    src.extend_from_slice(&(-(height as i32)).to_le_bytes());
+    src.extend_from_slice(&1u16.to_le_bytes());
+    src.extend_from_slice(&64u16.to_le_bytes());
+    src.extend_from_slice(&[0u8; 24]);  // BI_RGB, size, resolution and colors
+    for v in values.iter() {
+        src.extend_from_slice(&v.to_le_bytes());
+    }
+    let image = bmp::decode16(&src).unwrap();
+    assert_eq!((image.width,image.height),(width,height));
+    let mut fractions = 0;
+    for (i,p) in image.data.iter().enumerate() {
+        let v = &values[i * 4..i * 4 + 4];
+        let expected = (linear16(v[3],false) << 48) | (linear16(v[2],true) << 32) | (linear16(v[1],true) << 16) | linear16(v[0],true);
+        assert!(channel_error(*p,expected) <= 1,"pixel {}: {:016X} instead of {:016X}",i,p,expected);
+        fractions += (0..4).filter(|c| ((p >> (c * 16)) & 0xFFFF) % 257 != 0).count();
+    }
+    assert!(fractions > width * height * 2,"only {} channels with more than 8 bits",fractions);
+    assert!(image.to_image().data == bmp::decode(&src).unwrap().data);
+}
+
+// every bmpsuite file cut off at every length gives an image or an error, never a panic
+#[test]
+fn truncated() {
+    let mut names = Vec::new();
+    for dir in ["g","q","b","x"].iter() {
+        names.extend(bmpsuite_files(dir));
+    }
+    // one thread per file, this is a lot of decoding
+    std::thread::scope(|scope| {
+        for name in names.iter() {
+            scope.spawn(move || {
+                let lenient = bmp::DecodeOptions::default();
+                let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+                let src = bmpsuite_file(name);
+                // decode16 has its own pixel decoding only for 16, 32 and 64 bits per pixel
+                let wide = (src.len() >= 30) && [16,32,64].contains(&u16::from_le_bytes([src[28],src[29]]));
+                for length in 0..src.len() {
+                    let cut = &src[..length];
+                    let result = std::panic::catch_unwind(|| {
+                        let _ = bmp::test(cut);
+                        let _ = bmp::dpi(cut);
+                        let _ = bmp::decode_with_options(cut,&lenient);
+                        let _ = bmp::decode_with_options(cut,&strict);
+                        if wide {
+                            let _ = bmp::decode16_with_options(cut,&lenient);
+                        }
+                    });
+                    assert!(result.is_ok(),"{} cut to {} bytes",name,length);
+                }
+            });
+        }
+    });
+}
+
+// lenient mode repairs palettes that are too large, cut short by the pixel offset or too small for the pixels, strict mode
+// rejects them
+#[test]
+fn palette_edge_cases() {
+    let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+    for (name,strict_ok) in [("q/pal8oversizepal.bmp",false),("q/rgb24largepal.bmp",true),("q/pal8offs.bmp",true),("b/badpalettesize.bmp",false),("b/pal8badindex.bmp",false)].iter() {
+        let src = bmpsuite_file(name);
+        let image = bmp::decode(&src).unwrap();
+        assert_eq!((image.width,image.height),(127,64),"{}",name);
+        assert_eq!(bmp::decode_with_options(&src,&strict).is_ok(),*strict_ok,"{} strict",name);
+    }
+    // indices past the 101 colors come out black, the others as their palette color
+    let src = bmpsuite_file("b/pal8badindex.bmp");
+    let image = bmp::decode(&src).unwrap();
+    let offset = u32::from_le_bytes([src[10],src[11],src[12],src[13]]) as usize;
+    let mut past = 0;
+    for y in 0..64 {
+        for x in 0..127 {
+            let index = src[offset + (63 - y) * 128 + x] as usize;
+            let expected = if index < 101 {
+                let p = 54 + index * 4;
+                0xFF000000 | ((src[p + 2] as u32) << 16) | ((src[p + 1] as u32) << 8) | (src[p] as u32)
+            }
+            else {
+                past += 1;
+                0xFF000000
+            };
+            assert_eq!(image.data[y * 127 + x],expected,"({},{}) index {}",x,y,index);
+        }
+    }
+    assert!(past > 0);
+}
+
+// 2x1 32-bit bitmap with a BITMAPINFOHEADER, this compression and these masks after the header
+fn bitfields_bmp(compression: u32,masks: &[u32],pixels: &[u32]) -> Vec<u8> {
+    let offset = 54 + masks.len() * 4;
+    let mut src = Vec::new();
+    src.extend_from_slice(b"BM");
+    src.extend_from_slice(&((offset + pixels.len() * 4) as u32).to_le_bytes());
+    src.extend_from_slice(&0u32.to_le_bytes());
+    src.extend_from_slice(&(offset as u32).to_le_bytes());
+    src.extend_from_slice(&40u32.to_le_bytes());
+    src.extend_from_slice(&(pixels.len() as i32).to_le_bytes());
+    src.extend_from_slice(&1i32.to_le_bytes());
+    src.extend_from_slice(&1u16.to_le_bytes());
+    src.extend_from_slice(&32u16.to_le_bytes());
+    src.extend_from_slice(&compression.to_le_bytes());
+    src.extend_from_slice(&((pixels.len() * 4) as u32).to_le_bytes());
+    src.extend_from_slice(&[0u8; 16]);  // resolution and colors
+    for mask in masks.iter() {
+        src.extend_from_slice(&mask.to_le_bytes());
+    }
+    for p in pixels.iter() {
+        src.extend_from_slice(&p.to_le_bytes());
+    }
+    src
+}
+
+// BI_ALPHABITFIELDS always has the alpha mask, BI_BITFIELDS after a BITMAPINFOHEADER only the color masks
+#[test]
+fn alpha_bitfields() {
+    let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+    let pixels = [0x11223380,0xAABBCC00];
+    let src = bitfields_bmp(6,&[0x0000FF00,0x00FF0000,0xFF000000,0x000000FF],&pixels);
+    assert_eq!(bmp::decode_with_options(&src,&strict).unwrap().data,vec![0x80332211,0x00CCBBAA]);
+    let src = bitfields_bmp(3,&[0x0000FF00,0x00FF0000,0xFF000000],&pixels);
+    assert_eq!(bmp::decode_with_options(&src,&strict).unwrap().data,vec![0xFF332211,0xFFCCBBAA]);
+    // the bmpsuite sample shows the same picture as the BI_BITFIELDS one with a V5 header
+    assert!(bmp::decode(&bmpsuite_file("q/rgba32abf.bmp")).unwrap().data == bmp::decode(&bmpsuite_file("q/rgba32.bmp")).unwrap().data);
+}
+
+// a valid file with one field changed; decodes in lenient mode, but not in strict mode
+fn strict_case(name: &str,change: &dyn Fn(&mut Vec<u8>)) {
+    let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+    let mut src = bmpsuite_file(name);
+    assert!(bmp::decode_with_options(&src,&strict).is_ok(),"{} unchanged",name);
+    change(&mut src);
+    assert!(bmp::decode(&src).is_ok(),"{} lenient",name);
+    assert!(bmp::decode_with_options(&src,&strict).is_err(),"{} strict",name);
+}
+
+fn set32(src: &mut [u8],offset: usize,value: u32) {
+    src[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// each of the strict mode checks on its own
+#[test]
+fn strict_rules() {
+    strict_case("g/pal8.bmp",&|src| { let size = src.len() as u32; set32(src,2,size + 1); });  // file size
+    strict_case("g/pal8.bmp",&|src| src[26] = 2);  // planes
+    strict_case("g/pal8.bmp",&|src| { set32(src,38,30000000); set32(src,42,3); });  // resolution
+    strict_case("g/pal8.bmp",&|src| set32(src,46,257));  // more than 2^8 colors
+    strict_case("g/pal8.bmp",&|src| set32(src,46,256));  // 256 colors, but room for only 252
+    strict_case("g/pal8.bmp",&|src| set32(src,46,10));  // pixels past the palette
+    strict_case("g/rgb16-565.bmp",&|src| set32(src,54,0));  // no red bits
+    strict_case("g/rgb16-565.bmp",&|src| set32(src,54,0xF800 | 0x07E0));  // red overlaps green
+    strict_case("g/pal8rle.bmp",&|src| { let height = -i32::from_le_bytes([src[22],src[23],src[24],src[25]]); set32(src,22,height as u32); });  // top-down RLE
+    strict_case("g/pal8rle.bmp",&|src| set32(src,18,100));  // runs past the end of the line
+    strict_case("g/pal4rle.bmp",&|src| set32(src,22,32));  // runs below the last line
+}
@@ -0,0 +1,304 @@
+// image_formats conformance tests against bmpsuite and PngSuite
+// by Desmond Germans, 2019
+
+// cargo test --test conformance -- --nocapture prints the per-file tables
+
+use std::cell::Cell;
+use std::fs;
+use std::panic;
+use std::sync::Once;
+use std::path::{Path,PathBuf};
+use image_formats::{bmp,icc,jpeg,png};
+use image_formats::Image;
+
+// largest allowed difference per channel for bmpsuite; its references were made by a different converter and are sometimes off by
+// one; PngSuite references are exact
+const BMPSUITE_TOLERANCE: i32 = 2;
+
+// bmpsuite q/ files that differ from their reference, and why that is fine
+const BMPSUITE_DEVIATIONS: [(&str,&str); 1] = [
+    ("q/rgb24prof2.bmp","the embedded profile has a gamma 2.2 curve, the reference only swaps red and green"),
+];
+
+fn static_dir(sub: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("static").join(sub)
+}
+
+// names of the files in dir with this extension, sorted
+fn files(dir: &Path,extension: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.to_lowercase().ends_with(extension))
+        .collect();
+    names.sort();
+    names
+}
+
+thread_local! {
+    static DECODING: Cell<bool> = const { Cell::new(false) };
+}
+
+static QUIET_HOOK: Once = Once::new();
+
+// decode without letting a panic take down the whole run; the tests run in parallel, so the panic hook stays in place and
+// only keeps quiet for panics while decoding
+fn decode_file(path: &Path,options: &bmp::DecodeOptions) -> Result<Image,String> {
+    let src = match fs::read(path) {
+        Ok(src) => src,
+        Err(error) => { return Err(error.to_string()); },
+    };
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let options = options.clone();
+    QUIET_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !DECODING.with(|decoding| decoding.get()) {
+                hook(info);
+            }
+        }));
+    });
+    DECODING.with(|decoding| decoding.set(true));
+    let result = panic::catch_unwind(move || {
+        match extension.as_str() {
+            "bmp" => {
+                // render as a color managed viewer would
+                let image = bmp::decode_with_options(&src,&options)?;
+                match bmp::icc_profile(&src) {
+                    Some(profile) => icc::to_srgb(&image,&icc::Profile::parse(&profile)?,icc::Intent::default()),
+                    None => Ok(image),
+                }
+            },
+            "png" => png::decode(&src),
+            "jpg" => jpeg::decode(&src),
+            _ => Err("Unknown extension".to_string()),
+        }
+    });
+    DECODING.with(|decoding| decoding.set(false));
+    match result {
+        Ok(result) => result,
+        Err(_) => Err("panic".to_string()),
+    }
+}
+
+// fully transparent pixels match whatever their color
+fn same(a: &Image,b: &Image,tolerance: i32) -> bool {
+    if (a.width != b.width) || (a.height != b.height) {
+        return false;
+    }
+    a.data.iter().zip(b.data.iter()).all(|(&pa,&pb)| {
+        if ((pa >> 24) == 0) && ((pb >> 24) == 0) {
+            return true;
+        }
+        (0..4).all(|i| ((((pa >> (i * 8)) & 255) as i32) - (((pb >> (i * 8)) & 255) as i32)).abs() <= tolerance)
+    })
+}
+
+// per-file results
+struct Report {
+    lines: Vec<(String,&'static str,String)>,  // file, pass/FAIL/known/- (unchecked), note
+}
+
+impl Report {
+    fn new() -> Report {
+        Report { lines: Vec::new() }
+    }
+
+    fn add(&mut self,name: &str,pass: bool,note: &str) {
+        self.lines.push((name.to_string(),if pass { "pass" } else { "FAIL" },note.to_string()));
+    }
+
+    fn known(&mut self,name: &str,note: &str) {
+        self.lines.push((name.to_string(),"known",note.to_string()));
+    }
+
+    // decoded, but nothing to compare the pixels with
+    fn unchecked(&mut self,name: &str,note: &str) {
+        self.lines.push((name.to_string(),"-",note.to_string()));
+    }
+
+    // print the table, returns the number of failures
+    fn print(&self,title: &str) -> usize {
+        println!("{}:",title);
+        for (name,status,note) in self.lines.iter() {
+            println!("    {:24} {:5}  {}",name,status,note);
+        }
+        let failed = self.lines.iter().filter(|(_,status,_)| *status == "FAIL").count();
+        let unchecked = self.lines.iter().filter(|(_,status,_)| *status == "-").count();
+        println!("    {} files, {} failed, {} unchecked",self.lines.len(),failed,unchecked);
+        failed
+    }
+}
+
+// bmpsuite.html has one table row per test file, with the reference renderings in the cells after it; where a row lists
+// several, any of them is fine
+fn bmpsuite_references(html: &str) -> Vec<(String,Vec<String>)> {
+    let mut rows = Vec::new();
+    for row in html.split("<tr>").skip(1) {
+        let row = &row[..row.find("</tr>").unwrap_or(row.len())];
+        let cell = match row.find("<td") {
+            Some(start) => &row[start..],
+            None => { continue; },
+        };
+        let name = match (cell.find('>'),cell.find("</td>")) {
+            (Some(start),Some(end)) if start < end => cell[start + 1..end].trim(),
+            _ => { continue; },
+        };
+        if !name.contains('/') {
+            continue;
+        }
+        let mut references = Vec::new();
+        for part in row.split("src=\"").skip(1) {
+            let reference = &part[..part.find('"').unwrap_or(0)];
+            // "../" links show the test file itself
+            if !reference.is_empty() && !reference.starts_with("../") {
+                references.push(reference.to_string());
+            }
+        }
+        rows.push((name.to_string(),references));
+    }
+    rows
+}
+
+// g/ files decode to their reference, also in strict mode; q/ files decode to their reference; b/ files are rejected in
+// strict mode, and don't panic in lenient mode
+#[test]
+fn bmpsuite() {
+    let dir = static_dir("bmp/bmpsuite");
+    let rows = bmpsuite_references(&fs::read_to_string(dir.join("html/bmpsuite.html")).unwrap());
+    let lenient = bmp::DecodeOptions::default();
+    let strict = bmp::DecodeOptions { strict: true,..Default::default() };
+    let mut report = Report::new();
+    for sub in ["g","q","b"].iter() {
+        for file in files(&dir.join(sub),".bmp").iter() {
+            let name = format!("{}/{}",sub,file);
+            let path = dir.join(&name);
+            if *sub == "b" {
+                let lenient_panic = matches!(decode_file(&path,&lenient),Err(msg) if msg == "panic");
+                match decode_file(&path,&strict) {
+                    Ok(_) => report.add(&name,false,"accepted in strict mode"),
+                    Err(msg) if msg == "panic" => report.add(&name,false,"panic in strict mode"),
+                    Err(_) if lenient_panic => report.add(&name,false,"panic in lenient mode"),
+                    Err(_) => report.add(&name,true,"rejected"),
+                }
+                continue;
+            }
+            let image = match decode_file(&path,&lenient) {
+                Ok(image) => image,
+                Err(msg) => {
+                    report.add(&name,false,&msg);
+                    continue;
+                },
+            };
+            if *sub == "g" {
+                if let Err(msg) = decode_file(&path,&strict) {
+                    report.add(&name,false,&format!("strict mode: {}",msg));
+                    continue;
+                }
+            }
+            let references = match rows.iter().find(|(row,_)| *row == name) {
+                Some((_,references)) if !references.is_empty() => references,
+                _ => {
+                    report.unchecked(&name,"no reference");
+                    continue;
+                },
+            };
+            let mut matched = None;
+            let mut note = "differs".to_string();
+            for reference in references.iter() {
+                match decode_file(&dir.join("html").join(reference),&lenient) {
+                    Ok(expected) => {
+                        if same(&image,&expected,BMPSUITE_TOLERANCE) {
+                            matched = Some(reference);
+                            break;
+                        }
+                    },
+                    Err(msg) => { note = format!("{}: {}",reference,msg); },
+                }
+            }
+            match (matched,BMPSUITE_DEVIATIONS.iter().find(|(file,_)| *file == name)) {
+                (Some(reference),_) => report.add(&name,true,reference),
+                (None,Some((_,reason))) => report.known(&name,reason),
+                (None,None) => report.add(&name,false,&note),
+            }
+        }
+    }
+    assert_eq!(report.print("bmpsuite"),0);
+}
+
+// PngSuite comes without reference renderings, but many of its files store the same pixels as another one in a different
+// way; those must decode to the same image
+fn pngsuite_sibling(name: &str) -> Option<String> {
+    let base = &name[..name.len() - 4];
+    if base.len() != 8 {
+        return None;
+    }
+    match &base[0..2] {
+        "bg" => { return Some(format!("bas{}.png",&base[3..])); },  // background color
+        "oi" | "ps" | "ch" => { return Some(format!("basn{}.png",&base[4..])); },  // split IDAT, suggested palette, histogram
+        "z0" => { return if base == "z00n2c08" { None } else { Some("z00n2c08.png".to_string()) }; },  // zlib compression level
+        _ => { },
+    }
+    // interlaced
+    if &base[3..4] == "i" {
+        return Some(format!("{}n{}.png",&base[0..3],&base[4..]));
+    }
+    None
+}
+
+// reference pixels of a PngSuite file, if there are any: static/png/reference has the non-interlaced files as raw 32-bit
+// little endian ARGB, written by a separate decoder that follows the PNG specification: samples scaled to 8 bits and
+// rounded, gAMA g taking color samples s (0..1) to s^g like png::decode does, alpha linear, tRNS applied
+fn pngsuite_reference(name: &str,width: usize,height: usize) -> Option<Image> {
+    let raw = fs::read(static_dir("png/reference").join(format!("{}.argb",&name[..name.len() - 4]))).ok()?;
+    let mut image = Image::new(width,height);
+    if raw.len() != width * height * 4 {
+        return Some(image);  // the wrong size never matches
+    }
+    for (p,b) in image.data.iter_mut().zip(raw.chunks_exact(4)) {
+        *p = u32::from_le_bytes([b[0],b[1],b[2],b[3]]);
+    }
+    Some(image)
+}
+
+// x* files are corrupt and must be rejected, everything else decodes, to its reference pixels or to the same image as its
+// sibling if it has one
+#[test]
+fn pngsuite() {
+    let dir = static_dir("png/pngsuite");
+    let options = bmp::DecodeOptions::default();
+    let mut report = Report::new();
+    let mut references = 0;
+    for name in files(&dir,".png").iter() {
+        let result = decode_file(&dir.join(name),&options);
+        if name.starts_with('x') {
+            match result {
+                Ok(_) => report.add(name,false,"accepted"),
+                Err(msg) => report.add(name,msg != "panic",&msg),
+            }
+            continue;
+        }
+        let image = match result {
+            Ok(image) => image,
+            Err(msg) => {
+                report.add(name,false,&msg);
+                continue;
+            },
+        };
+        if let Some(expected) = pngsuite_reference(name,image.width,image.height) {
+            report.add(name,same(&image,&expected,0),"reference pixels");
+            references += 1;
+            continue;
+        }
+        match pngsuite_sibling(name) {
+            Some(sibling) => match decode_file(&dir.join(&sibling),&options) {
+                Ok(expected) => report.add(name,same(&image,&expected,0),&sibling),
+                Err(msg) => report.add(name,false,&format!("{}: {}",sibling,msg)),
+            },
+            None => report.unchecked(name,"no reference or sibling"),
+        }
+    }
+    assert_eq!(report.print("PngSuite"),0);
+    // all non-interlaced files, except the 256x256 PngSuite logo
+    assert_eq!(references,126,"files with reference pixels");
+}
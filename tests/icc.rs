@@ -0,0 +1,178 @@
+// image_formats ICC tests
+// by Desmond Germans, 2019
+
+use image_formats::{bmp,icc};
+use image_formats::Image;
+
+// sRGB matrix, RGB to D50 XYZ, as in icc::Profile::srgb
+const SRGB_MATRIX: [[f64; 3]; 3] = [
+    [0.4360747,0.3850649,0.1430804],
+    [0.2225045,0.7168786,0.0606169],
+    [0.0139322,0.0971045,0.7141733],
+];
+
+fn srgb_decode(v: f64) -> f64 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn srgb_encode(v: f64) -> f64 {
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+// v2 display profile with the given color space, PCS and tags; tag data is padded to 4 bytes
+fn profile(space: &[u8; 4],pcs: &[u8; 4],tags: &[(&[u8; 4],Vec<u8>)]) -> Vec<u8> {
+    let mut header = vec![0u8; 128];
+    header[8] = 2;
+    header[9] = 0x10;
+    header[12..16].copy_from_slice(b"mntr");
+    header[16..20].copy_from_slice(space);
+    header[20..24].copy_from_slice(pcs);
+    header[36..40].copy_from_slice(b"acsp");
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let start = 128 + 4 + tags.len() * 12;
+    for (signature,tag) in tags.iter() {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((start + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        while (data.len() & 3) != 0 {
+            data.push(0);
+        }
+    }
+    let mut src = header;
+    src.extend_from_slice(&table);
+    src.extend_from_slice(&data);
+    let size = src.len() as u32;
+    src[0..4].copy_from_slice(&size.to_be_bytes());
+    src
+}
+
+fn u16_table(values: &[f64]) -> Vec<u8> {
+    values.iter().flat_map(|v| ((v * 65535.0).round() as u16).to_be_bytes()).collect()
+}
+
+fn row(pixels: Vec<u32>) -> Image {
+    let mut image = Image::new(pixels.len(),1);
+    image.data = pixels;
+    image
+}
+
+// (maximum channel error, pixel) of image against the reference
+fn max_error(image: &Image,reference: &Image) -> (u32,u32) {
+    let mut worst = (0,0);
+    for (a,b) in image.data.iter().zip(reference.data.iter()) {
+        for shift in [0,8,16,24].iter() {
+            let d = ((((a >> shift) & 255) as i32) - (((b >> shift) & 255) as i32)).unsigned_abs();
+            if d > worst.0 {
+                worst = (d,*b);
+            }
+        }
+    }
+    worst
+}
+
+// grid over the RGB cube, every gray and the primary ramps, with some alpha values
+fn test_pixels() -> Vec<u32> {
+    let mut pixels = Vec::new();
+    for r in (0..256).step_by(5) {
+        for g in (0..256).step_by(5) {
+            for b in (0..256).step_by(5) {
+                pixels.push(((((r + g + b) & 255) as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32));
+            }
+        }
+    }
+    for i in 0..256u32 {
+        pixels.extend_from_slice(&[0xFF000000 | (i * 0x010101),0xFF000000 | (i << 16),0xFF000000 | (i << 8),0xFF000000 | i]);
+    }
+    pixels
+}
+
+#[test]
+fn srgb_identity() {
+    let image = row(test_pixels());
+    let result = icc::to_srgb(&image,&icc::Profile::srgb(),icc::Intent::Perceptual).unwrap();
+    let (error,pixel) = max_error(&result,&image);
+    assert!(error <= 1,"error {} at {:08X}",error,pixel);
+}
+
+// gray profile with a gamma 2.2 kTRC; grays map to gray with the same luminance
+#[test]
+fn gray_trc() {
+    let mut curv = b"curv\0\0\0\0\0\0\0\x01".to_vec();
+    curv.extend_from_slice(&563u16.to_be_bytes());  // 2.2 as u8Fixed8Number
+    let profile = icc::Profile::parse(&profile(b"GRAY",b"XYZ ",&[(b"kTRC",curv)])).unwrap();
+    assert!(profile.gray);
+    let image = row((0..256u32).map(|i| 0xFF000000 | (i * 0x010101)).collect());
+    let result = icc::to_srgb(&image,&profile,icc::Intent::Perceptual).unwrap();
+    let expected = row((0..256).map(|i| {
+        let v = (srgb_encode(((i as f64) / 255.0).powf(563.0 / 256.0)) * 255.0).round() as u32;
+        0xFF000000 | (v * 0x010101)
+    }).collect());
+    let (error,pixel) = max_error(&result,&expected);
+    assert!(error <= 1,"error {} at {:08X}",error,pixel);
+}
+
+// RGB profile with only an A2B0 lut16 that does the sRGB linearization in its input tables and the sRGB matrix in a
+// 2x2x2 CLUT, so it converts to sRGB as the identity
+#[test]
+fn lut() {
+    let mut mft2 = b"mft2\0\0\0\0".to_vec();
+    mft2.extend_from_slice(&[3,3,2,0]);  // inputs, outputs, grid points
+    for i in 0..9 {
+        mft2.extend_from_slice(&(if i % 4 == 0 { 0x10000u32 } else { 0 }).to_be_bytes());  // identity matrix
+    }
+    mft2.extend_from_slice(&256u16.to_be_bytes());
+    mft2.extend_from_slice(&2u16.to_be_bytes());
+    let linear: Vec<f64> = (0..256).map(|i| srgb_decode((i as f64) / 255.0)).collect();
+    for _ in 0..3 {
+        mft2.extend_from_slice(&u16_table(&linear));
+    }
+    for corner in 0..8 {
+        let rgb = [(corner >> 2) & 1,(corner >> 1) & 1,corner & 1].map(|c| c as f64);  // the first input varies slowest
+        for row in SRGB_MATRIX.iter() {
+            let xyz = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+            mft2.extend_from_slice(&((xyz * 32768.0).round() as u16).to_be_bytes());  // u1Fixed15Number
+        }
+    }
+    for _ in 0..3 {
+        mft2.extend_from_slice(&u16_table(&[0.0,1.0]));
+    }
+    let profile = icc::Profile::parse(&profile(b"RGB ",b"XYZ ",&[(b"A2B0",mft2)])).unwrap();
+    let image = row(test_pixels());
+    for intent in [icc::Intent::Perceptual,icc::Intent::RelativeColorimetric].iter() {
+        let result = icc::to_srgb(&image,&profile,*intent).unwrap();
+        let (error,pixel) = max_error(&result,&image);
+        assert!(error <= 1,"{:?}: error {} at {:08X}",intent,error,pixel);
+    }
+}
+
+fn bmpsuite_file(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/bmp/bmpsuite").join(name)).unwrap()
+}
+
+// the embedded profiles describe the same picture as g/rgb24.bmp; rgb24prof2.bmp stores red and green swapped and uses
+// gamma 2.2 instead of the sRGB curve, so its result is the reference through that curve; rgb24lprof.bmp only links its profile
+#[test]
+fn bmpsuite_profiles() {
+    let reference = bmp::decode(&bmpsuite_file("g/rgb24.bmp")).unwrap();
+    let mut gamma22 = Image::new(reference.width,reference.height);
+    for (p,r) in gamma22.data.iter_mut().zip(reference.data.iter()) {
+        *p = *r;
+        for shift in [0,8,16].iter() {
+            let v = (srgb_encode((((r >> shift) & 255) as f64 / 255.0).powf(2.2)) * 255.0).round() as u32;
+            *p = (*p & !(255 << shift)) | (v << shift);
+        }
+    }
+    assert_eq!(bmp::icc_profile(&bmpsuite_file("q/rgb24lprof.bmp")),None);
+    for (name,expected) in [("q/rgb24prof.bmp",&reference),("q/rgb24prof2.bmp",&gamma22)].iter() {
+        let src = bmpsuite_file(name);
+        let image = bmp::decode(&src).unwrap();
+        let profile = icc::Profile::parse(&bmp::icc_profile(&src).unwrap()).unwrap();
+        for intent in [icc::Intent::Perceptual,icc::Intent::RelativeColorimetric].iter() {
+            let result = icc::to_srgb(&image,&profile,*intent).unwrap();
+            let (error,pixel) = max_error(&result,expected);
+            assert!(error <= 1,"{} {:?}: error {} at {:08X}",name,intent,error,pixel);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// image_formats ICO and CUR tests
+// by Desmond Germans, 2019
+
+use image_formats::{bmp,ico};
+use image_formats::Image;
+
+fn bmpsuite_file(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/bmp/bmpsuite").join(name)).unwrap()
+}
+
+// the bmpsuite RGBA samples, with every kind of alpha, and a small image with a fully transparent corner
+fn images() -> Vec<Image> {
+    let mut images: Vec<Image> = ["q/rgba32.bmp","q/rgba32-61754.bmp","q/rgba16-4444.bmp","g/rgb24.bmp"].iter().map(|name| bmp::decode(&bmpsuite_file(name)).unwrap()).collect();
+    let mut small = Image::new(5,3);
+    for (i,p) in small.data.iter_mut().enumerate() {
+        *p = if i == 0 { 0 } else { 0xFF000000 | ((i as u32) * 0x0A0B0C) };
+    }
+    images.push(small);
+    images
+}
+
+fn check(src: &[u8],images: &[Image],hotspots: Option<&[(u16,u16)]>) {
+    let entries = ico::entries(src).unwrap();
+    assert_eq!(entries.len(),images.len());
+    for (i,(entry,image)) in entries.iter().zip(images.iter()).enumerate() {
+        assert_eq!((entry.width,entry.height,entry.bpp,entry.png),(image.width,image.height,32,false),"image {}",i);
+        assert_eq!(entry.hotspot,hotspots.map(|h| h[i]),"image {}",i);
+        assert!(ico::decode_entry(src,entry).unwrap().data == image.data,"image {}",i);
+    }
+}
+
+#[test]
+fn icon_round_trip() {
+    let images = images();
+    check(&ico::encode(&images).unwrap(),&images,None);
+}
+
+#[test]
+fn cursor_round_trip() {
+    let images = images();
+    let hotspots: Vec<(u16,u16)> = images.iter().map(|image| ((image.width / 2) as u16,(image.height - 1) as u16)).collect();
+    let src = ico::encode_with_options(&images,&ico::EncodeOptions { hotspots: Some(hotspots.clone()) }).unwrap();
+    assert_eq!(u16::from_le_bytes([src[2],src[3]]),2);
+    check(&src,&images,Some(&hotspots));
+}
+
+// with the alpha channel cleared, as older writers leave it, the AND mask alone decides what is transparent
+#[test]
+fn and_mask() {
+    let images = images();
+    let mut src = ico::encode(&images).unwrap();
+    let offset = u32::from_le_bytes([src[18],src[19],src[20],src[21]]) as usize;
+    for p in 0..images[0].width * images[0].height {
+        src[offset + 40 + p * 4 + 3] = 0;
+    }
+    let decoded = ico::decode_entry(&src,&ico::entries(&src).unwrap()[0]).unwrap();
+    let expected: Vec<u32> = images[0].data.iter().map(|p| if (p >> 24) == 0 { 0 } else { p | 0xFF000000 }).collect();
+    assert!(expected.iter().any(|p| *p == 0));
+    assert!(decoded.data == expected);
+}
+
+// entries that point outside the file or at garbage are skipped, an icon without usable entries is an error
+#[test]
+fn bad_entries() {
+    let images = images();
+    let src = ico::encode(&images).unwrap();
+    let record = |i: usize| 6 + i * 16;
+    let mut outside = src.clone();
+    outside[record(1) + 12..record(1) + 16].copy_from_slice(&(src.len() as u32).to_le_bytes());  // offset at the end, size too large
+    let mut garbage = src.clone();
+    let offset = u32::from_le_bytes([src[record(2) + 12],src[record(2) + 13],src[record(2) + 14],src[record(2) + 15]]) as usize;
+    garbage[offset..offset + 4].copy_from_slice(&[0,0,0,0]);  // header size
+    for (bad,src) in [(1,outside),(2,garbage)].iter() {
+        let entries = ico::entries(src).unwrap();
+        assert_eq!(entries.len(),images.len() - 1,"bad entry {}",bad);
+        let remaining: Vec<&Image> = images.iter().enumerate().filter(|(i,_)| i != bad).map(|(_,image)| image).collect();
+        for (entry,image) in entries.iter().zip(remaining.iter()) {
+            assert!(ico::decode_entry(src,entry).unwrap().data == image.data,"bad entry {}",bad);
+        }
+        assert!(ico::decode(src).is_ok());
+    }
+    let mut none = src.clone();
+    for i in 0..images.len() {
+        none[record(i) + 12..record(i) + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+    }
+    assert!(ico::entries(&none).is_err());
+    assert!(ico::decode(&none).is_err());
+    // a directory that claims more records than the file holds
+    let mut short = src[..6 + 16].to_vec();
+    short.extend_from_slice(&src[6 + images.len() * 16..]);
+    short[4..6].copy_from_slice(&(images.len() as u16).to_le_bytes());
+    let offset = u32::from_le_bytes([src[18],src[19],src[20],src[21]]) - ((images.len() - 1) * 16) as u32;
+    short[18..22].copy_from_slice(&offset.to_le_bytes());
+    assert_eq!(ico::entries(&short).unwrap().len(),1);
+}
+
+fn pngsuite_file(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/png/pngsuite").join(name)).unwrap()
+}
+
+// icon with the given directory width and height bytes for each stored image
+fn icon(images: &[(u8,u8,&[u8])]) -> Vec<u8> {
+    let mut src = vec![0,0,1,0];
+    src.extend_from_slice(&(images.len() as u16).to_le_bytes());
+    let mut offset = 6 + images.len() * 16;
+    for (width,height,data) in images.iter() {
+        src.extend_from_slice(&[*width,*height,0,0,1,0,32,0]);
+        src.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        src.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += data.len();
+    }
+    for (_,_,data) in images.iter() {
+        src.extend_from_slice(data);
+    }
+    src
+}
+
+// a PNG entry next to a DIB entry is decoded as PNG, and as the largest image it is the one decode picks
+#[test]
+fn png_entry() {
+    let small = images().pop().unwrap();
+    let dib = ico::encode(&[small]).unwrap()[22..].to_vec();
+    let png = pngsuite_file("basn6a08.png");
+    let src = icon(&[(5,3,&dib),(32,32,&png)]);
+    let entries = ico::entries(&src).unwrap();
+    assert_eq!((entries[0].width,entries[0].height,entries[0].png),(5,3,false));
+    assert_eq!((entries[1].width,entries[1].height,entries[1].bpp,entries[1].png),(32,32,32,true));
+    let expected = image_formats::png::decode(&png).unwrap();
+    assert!(ico::decode_entry(&src,&entries[1]).unwrap().data == expected.data);
+    assert_eq!(ico::test(&src),Some((32,32)));
+    assert!(ico::decode(&src).unwrap().data == expected.data);
+}
+
+// 256 is written as 0 in the directory, and the stored image decides the size when the directory disagrees with it
+#[test]
+fn directory_size() {
+    let mut large = Image::new(256,256);
+    for (i,p) in large.data.iter_mut().enumerate() {
+        *p = 0xFF000000 | ((i as u32).wrapping_mul(0x010305) & 0xFFFFFF);
+    }
+    let src = ico::encode(std::slice::from_ref(&large)).unwrap();
+    assert_eq!((src[6],src[7]),(0,0));
+    let dib = src[22..].to_vec();
+    let png = pngsuite_file("basn6a08.png");
+    for (width,height) in [(0,0),(16,16),(32,0)].iter() {
+        let src = icon(&[(*width,*height,&dib),(*width,*height,&png)]);
+        let entries = ico::entries(&src).unwrap();
+        assert_eq!((entries[0].width,entries[0].height),(256,256),"directory {}x{}",width,height);
+        assert_eq!((entries[1].width,entries[1].height),(32,32),"directory {}x{}",width,height);
+        assert!(ico::decode_entry(&src,&entries[0]).unwrap().data == large.data,"directory {}x{}",width,height);
+        assert_eq!(ico::test(&src),Some((256,256)),"directory {}x{}",width,height);
+    }
+}
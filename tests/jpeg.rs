@@ -0,0 +1,537 @@
+// image_formats JPEG tests
+// by Desmond Germans, 2019
+
+use image_formats::jpeg;
+use image_formats::Image;
+use image_formats::Image16;
+
+// JPEG with an EXIF block whose IFD1 points at a JPEG thumbnail of the given bytes
+fn with_thumbnail(thumbnail: &[u8]) -> Vec<u8> {
+    let mut tiff: Vec<u8> = vec![0x49,0x49,0x2A,0x00,0x08,0x00,0x00,0x00];  // little endian, IFD0 at 8
+    tiff.extend_from_slice(&[0x00,0x00,0x0E,0x00,0x00,0x00]);  // empty IFD0, IFD1 at 14
+    tiff.extend_from_slice(&[0x02,0x00]);
+    tiff.extend_from_slice(&[0x01,0x02,0x04,0x00,0x01,0x00,0x00,0x00,0x2C,0x00,0x00,0x00]);  // JPEG offset 44
+    tiff.extend_from_slice(&[0x02,0x02,0x04,0x00,0x01,0x00,0x00,0x00]);  // JPEG length
+    tiff.extend_from_slice(&(thumbnail.len() as u32).to_le_bytes());
+    tiff.extend_from_slice(&[0x00,0x00,0x00,0x00]);
+    tiff.extend_from_slice(thumbnail);
+    let mut src = vec![0xFF,0xD8,0xFF,0xE1];
+    src.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    src.extend_from_slice(b"Exif\0\0");
+    src.extend_from_slice(&tiff);
+    src.extend_from_slice(&[0xFF,0xD9]);
+    src
+}
+
+#[test]
+fn short_input() {
+    for length in 0..4 {
+        assert!(jpeg::decode(&[0xFF,0xD8,0xFF,0xD9][..length]).is_err());
+    }
+}
+
+#[test]
+fn short_thumbnail() {
+    for length in 1..4 {
+        assert!(jpeg::thumbnail(&with_thumbnail(&[0xFF,0xD8,0xFF][..length])).is_none());
+    }
+}
+
+fn static_file(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/jpg").join(name)).unwrap()
+}
+
+// camera files whose IFD1 holds a JPEG thumbnail
+const THUMBNAILS: [(&str,usize,usize); 3] = [("kodak-dc210.jpg",96,72),("fujifilm-finepix40i.jpg",160,120),("sony-cybershot.jpg",160,120)];
+
+#[test]
+fn thumbnail() {
+    for (name,width,height) in THUMBNAILS.iter() {
+        let image = jpeg::thumbnail(&static_file(name)).unwrap_or_else(|| panic!("{} has no thumbnail",name));
+        assert_eq!((image.width,image.height),(*width,*height),"{}",name);
+        assert!(image.data.iter().any(|p| *p != image.data[0]),"{} thumbnail is flat",name);
+    }
+}
+
+// 12 byte IFD entry, little endian
+fn ifd_entry(tiff: &mut Vec<u8>,tag: u16,format: u16,count: u32,value: u32) {
+    tiff.extend_from_slice(&tag.to_le_bytes());
+    tiff.extend_from_slice(&format.to_le_bytes());
+    tiff.extend_from_slice(&count.to_le_bytes());
+    tiff.extend_from_slice(&value.to_le_bytes());
+}
+
+// JPEG with an EXIF block whose IFD1 is an uncompressed 2x2 RGB thumbnail in two strips of one row
+fn with_strip_thumbnail(pixels: &[u8; 12],second_count: u32) -> Vec<u8> {
+    let mut tiff: Vec<u8> = vec![0x49,0x49,0x2A,0x00,0x08,0x00,0x00,0x00];  // little endian, IFD0 at 8
+    tiff.extend_from_slice(&[0x00,0x00,0x0E,0x00,0x00,0x00]);  // empty IFD0, IFD1 at 14
+    tiff.extend_from_slice(&[0x08,0x00]);
+    ifd_entry(&mut tiff,0x0100,3,1,2);  // width
+    ifd_entry(&mut tiff,0x0101,3,1,2);  // height
+    ifd_entry(&mut tiff,0x0102,3,3,116);  // bits per sample
+    ifd_entry(&mut tiff,0x0103,3,1,1);  // uncompressed
+    ifd_entry(&mut tiff,0x0106,3,1,2);  // RGB
+    ifd_entry(&mut tiff,0x0111,4,2,122);  // strip offsets
+    ifd_entry(&mut tiff,0x0115,3,1,3);  // samples per pixel
+    ifd_entry(&mut tiff,0x0117,4,2,130);  // strip byte counts
+    tiff.extend_from_slice(&[0x00,0x00,0x00,0x00]);
+    tiff.extend_from_slice(&[0x08,0x00,0x08,0x00,0x08,0x00]);
+    tiff.extend_from_slice(&[138,0,0,0,144,0,0,0]);
+    tiff.extend_from_slice(&[6,0,0,0]);
+    tiff.extend_from_slice(&second_count.to_le_bytes());
+    tiff.extend_from_slice(pixels);
+    let mut src = vec![0xFF,0xD8,0xFF,0xE1];
+    src.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    src.extend_from_slice(b"Exif\0\0");
+    src.extend_from_slice(&tiff);
+    src.extend_from_slice(&[0xFF,0xD9]);
+    src
+}
+
+#[test]
+fn strip_thumbnail() {
+    let pixels = [0x10,0x20,0x30,0x40,0x50,0x60,0x70,0x80,0x90,0xA0,0xB0,0xC0];
+    let image = jpeg::thumbnail(&with_strip_thumbnail(&pixels,6)).unwrap();
+    assert_eq!((image.width,image.height),(2,2));
+    assert_eq!(image.data,vec![0xFF102030,0xFF405060,0xFF708090,0xFFA0B0C0]);
+    assert!(jpeg::thumbnail(&with_strip_thumbnail(&pixels,5)).is_none());  // short second strip
+    assert!(jpeg::thumbnail(&with_strip_thumbnail(&pixels,7)).is_none());  // second strip past the block
+}
+
+// average absolute difference per channel between a scaled decode and the box filtered full size decode
+fn scaled_error(src: &[u8],scale: jpeg::Scale) -> f64 {
+    let full = jpeg::decode(src).unwrap();
+    let scaled = jpeg::decode_with_options(src,&jpeg::DecodeOptions { scale,..Default::default() }).unwrap();
+    let factor = 1 << scale as usize;
+    assert_eq!((scaled.width,scaled.height),(full.width.div_ceil(factor),full.height.div_ceil(factor)));
+    let mut total = 0u64;
+    for y in 0..scaled.height {
+        for x in 0..scaled.width {
+            let mut sum = [0u64; 3];
+            let mut count = 0;
+            for fy in y * factor..((y + 1) * factor).min(full.height) {
+                for fx in x * factor..((x + 1) * factor).min(full.width) {
+                    let p = full.data[fy * full.width + fx];
+                    for (c,s) in sum.iter_mut().enumerate() {
+                        *s += ((p >> (c * 8)) & 255) as u64;
+                    }
+                    count += 1;
+                }
+            }
+            let p = scaled.data[y * scaled.width + x];
+            for (c,s) in sum.iter().enumerate() {
+                total += ((((p >> (c * 8)) & 255) as i64) - ((s / count) as i64)).unsigned_abs();
+            }
+        }
+    }
+    total as f64 / (scaled.width * scaled.height * 3) as f64
+}
+
+// scaled decoding of sequential, progressive and arithmetic files stays close to downscaling the full image
+#[test]
+fn scaled() {
+    for name in ["money.jpg","earth2.jpg","money-arithmetic-progressive.jpg"].iter() {
+        let src = static_file(name);
+        for scale in [jpeg::Scale::Half,jpeg::Scale::Quarter,jpeg::Scale::Eighth].iter() {
+            let error = scaled_error(&src,*scale);
+            assert!(error < 6.0,"{} {:?}: {:.2}",name,scale,error);
+        }
+    }
+}
+
+fn static_files() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/jpg")).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".jpg"))
+        .collect();
+    names.sort();
+    names
+}
+
+// (maximum, mean) absolute channel error of image against the reference
+fn error(image: &Image,reference: &Image) -> (u32,f64) {
+    assert_eq!((image.width,image.height),(reference.width,reference.height));
+    let mut max = 0u32;
+    let mut total = 0u64;
+    for (a,b) in image.data.iter().zip(reference.data.iter()) {
+        for shift in [0,8,16].iter() {
+            let d = ((((a >> shift) & 255) as i32) - (((b >> shift) & 255) as i32)).unsigned_abs();
+            max = max.max(d);
+            total += d as u64;
+        }
+    }
+    (max,(total as f64) / ((image.data.len() * 3) as f64))
+}
+
+// the fixed point IDCTs on real files stay close to the float IDCT; the IEEE 1180 tests on the IDCTs themselves are in src/jpeg.rs
+#[test]
+fn idct_files() {
+    for name in static_files().iter() {
+        let src = static_file(name);
+        let decode = |idct: jpeg::Idct| jpeg::decode_with_options(&src,&jpeg::DecodeOptions { idct,..Default::default() });
+        let reference = match decode(jpeg::Idct::Float) {
+            Ok(image) => image,
+            Err(_) => { continue; },  // CMYK is not supported
+        };
+        for (idct,max_limit,mean_limit) in [(jpeg::Idct::Accurate,3,0.3),(jpeg::Idct::Fast,7,1.1)].iter() {
+            let (max,mean) = error(&decode(*idct).unwrap(),&reference);
+            assert!((max <= *max_limit) && (mean <= *mean_limit),"{} {:?}: max error {}, mean error {:.4}",name,idct,max,mean);
+        }
+    }
+}
+
+// FNV-1a over the pixels
+fn checksum(image: &Image) -> u64 {
+    let mut hash = 0xCBF29CE484222325u64;
+    for p in image.data.iter() {
+        for b in p.to_le_bytes().iter() {
+            hash = (hash ^ (*b as u64)).wrapping_mul(0x100000001B3);
+        }
+    }
+    hash
+}
+
+// jpeg::decode output with the default fancy upsampling and with nearest upsampling; a change here changes what every
+// caller gets, so it has to be deliberate
+const PINNED: [(&str,u64,u64); 5] = [
+    ("money.jpg",0xD6A3897951F4E3A4,0x92D90DE751B93E54),
+    ("earth2.jpg",0xE060EC0CDF10A50B,0x27B9F07E1E4D6EC2),
+    ("kodak-dc210.jpg",0xA43F6096C415024A,0x4ACCBD822E0B73BA),
+    ("dither.jpg",0x66D215197F63AC86,0xF77ADE45D7E7261C),
+    ("test.jpg",0xA7756547E1621AA5,0x5E0AE36F62A43AB8),
+];
+
+#[test]
+fn pinned_output() {
+    for (name,fancy,nearest) in PINNED.iter() {
+        let src = static_file(name);
+        assert_eq!(checksum(&jpeg::decode(&src).unwrap()),*fancy,"{} fancy",name);
+        let options = jpeg::DecodeOptions { upsampling: jpeg::Upsampling::Nearest,..Default::default() };
+        assert_eq!(checksum(&jpeg::decode_with_options(&src,&options).unwrap()),*nearest,"{} nearest",name);
+    }
+}
+
+fn luma(p: u32) -> f64 {
+    0.299 * (((p >> 16) & 255) as f64) + 0.587 * (((p >> 8) & 255) as f64) + 0.114 * ((p & 255) as f64)
+}
+
+// luma PSNR of image against the reference, so chroma subsampling of colorful images doesn't count against the encoder
+fn psnr(image: &Image,reference: &Image) -> f64 {
+    assert_eq!((image.width,image.height),(reference.width,reference.height));
+    let mut total = 0.0;
+    for (a,b) in image.data.iter().zip(reference.data.iter()) {
+        let d = luma(*a) - luma(*b);
+        total += d * d;
+    }
+    if total == 0.0 {
+        return f64::INFINITY;
+    }
+    let mse = total / (image.data.len() as f64);
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+// minimum luma PSNR of an encode and decode round trip, per quality
+const PSNR_THRESHOLDS: [(u8,f64); 5] = [(25,22.0),(50,29.0),(75,34.0),(90,42.0),(100,48.0)];
+
+#[test]
+fn encode_psnr() {
+    for name in ["money.jpg","earth2.jpg","appligent.jpg","test.jpg"].iter() {
+        let reference = jpeg::decode(&static_file(name)).unwrap();
+        for (quality,threshold) in PSNR_THRESHOLDS.iter() {
+            for subsampling in [jpeg::Subsampling::S444,jpeg::Subsampling::S422,jpeg::Subsampling::S420].iter() {
+                for (optimize_huffman,scans) in [(false,Vec::new()),(true,Vec::new()),(false,jpeg::progressive_scans())].iter() {
+                    let options = jpeg::EncodeOptions {
+                        quality: *quality,
+                        subsampling: *subsampling,
+                        restart_interval: 4,
+                        optimize_huffman: *optimize_huffman,
+                        scans: scans.clone(),
+                        ..Default::default()
+                    };
+                    let p = psnr(&jpeg::decode(&jpeg::encode_with_options(&reference,&options).unwrap()).unwrap(),&reference);
+                    assert!(p >= *threshold,"{} quality {} {:?}{}{}: PSNR {:.2} dB",name,quality,subsampling,
+                        if *optimize_huffman { " optimized" } else { "" },if scans.is_empty() { "" } else { " progressive" },p);
+                }
+            }
+        }
+    }
+}
+
+// money-huffman.jpg holds the same quantized coefficients as the arithmetic coded samples, Huffman coded; all three are
+// a 130x97 crop of money.jpg, encoded with 4:2:0 subsampling, all-2 quantization and restart interval 3
+#[test]
+fn arithmetic() {
+    let huffman = jpeg::decode(&static_file("money-huffman.jpg")).unwrap();
+    let full = jpeg::decode(&static_file("money.jpg")).unwrap();
+    let mut crop = Image::new(huffman.width,huffman.height);
+    for y in 0..crop.height {
+        for x in 0..crop.width {
+            crop.data[y * crop.width + x] = full.data[y * full.width + x];
+        }
+    }
+    for name in ["money-arithmetic.jpg","money-arithmetic-progressive.jpg"].iter() {
+        let image = jpeg::decode(&static_file(name)).unwrap();
+        let (max,mean) = error(&image,&huffman);
+        assert!((max <= 3) && (mean <= 0.3),"{} against money-huffman.jpg: max error {}, mean error {:.4}",name,max,mean);
+        // the crop went through another round of chroma subsampling, so only the average stays within the IDCT tolerance
+        let (max,mean) = error(&image,&crop);
+        assert!(mean <= 1.1,"{} against money.jpg: max error {}, mean error {:.4}",name,max,mean);
+    }
+}
+
+// money-12bit.jpg is the crop in money-huffman.jpg at 12 bits per sample; its samples are not limited to multiples of 257
+// and the IDCT choice doesn't apply to it
+#[test]
+fn twelve_bit() {
+    let src = static_file("money-12bit.jpg");
+    let image = jpeg::decode16(&src).unwrap();
+    assert_eq!((image.width,image.height),(130,97));
+    assert!(image.data.iter().any(|p| (0..3).any(|c| ((p >> (c * 16)) & 0xFFFF) % 257 != 0)));
+    for idct in [jpeg::Idct::Fast,jpeg::Idct::Accurate].iter() {
+        let options = jpeg::DecodeOptions { idct: *idct,..Default::default() };
+        assert_eq!(jpeg::decode16_with_options(&src,&options).unwrap().data,image.data,"{:?}",idct);
+    }
+    // the 8-bit file rounds its coefficients 16 times coarser, so the tolerance covers that rounding on top of the IDCT
+    let (max,mean) = error(&jpeg::decode(&src).unwrap(),&jpeg::decode(&static_file("money-huffman.jpg")).unwrap());
+    assert!((max <= 8) && (mean <= 1.0),"against money-huffman.jpg: max error {}, mean error {:.4}",max,mean);
+}
+
+// FNV-1a of the samples money-lossless.jpg was encoded from
+const LOSSLESS_CHECKSUM: u64 = 0x0CFB263E41751438;
+
+#[test]
+fn lossless() {
+    let image = jpeg::decode(&static_file("money-lossless.jpg")).unwrap();
+    assert_eq!((image.width,image.height),(130,97));
+    assert_eq!(checksum(&image),LOSSLESS_CHECKSUM);
+}
+
+// 2x1 8-bit lossless gray image whose first difference of +200 takes the sample past 255
+#[test]
+fn lossless_out_of_range() {
+    let mut src = vec![0xFF,0xD8];
+    src.extend_from_slice(&[0xFF,0xC3,0x00,0x0B,0x08,0x00,0x01,0x00,0x02,0x01,0x01,0x11,0x00]);
+    src.extend_from_slice(&[0xFF,0xC4,0x00,0x15,0x00,0x02,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x08,0x00]);  // 0 = category 8, 1 = category 0
+    src.extend_from_slice(&[0xFF,0xDA,0x00,0x08,0x01,0x01,0x00,0x01,0x00,0x00]);  // predictor 1, no point transform
+    src.extend_from_slice(&[0x64,0x7F]);  // 0 11001000, 1, padding
+    src.extend_from_slice(&[0xFF,0xD9]);
+    let image = jpeg::decode(&src).unwrap();
+    assert_eq!(image.data,vec![0xFF484848,0xFF484848]);  // (128 + 200) modulo 256 = 72
+}
+
+// compares the blocks inside each component's own size; the padding blocks that complete the last MCUs are not coded by
+// non-interleaved scans, so a progressive file doesn't keep their AC coefficients
+fn assert_same_coefficients(a: &jpeg::JpegCoefficients,b: &jpeg::JpegCoefficients,what: &str) {
+    assert_eq!((a.width,a.height,a.components.len()),(b.width,b.height,b.components.len()),"{}",what);
+    let hmax = a.components.iter().map(|c| c.hsamp).max().unwrap();
+    let vmax = a.components.iter().map(|c| c.vsamp).max().unwrap();
+    for (ca,cb) in a.components.iter().zip(b.components.iter()) {
+        assert_eq!((ca.id,ca.hsamp,ca.vsamp,ca.bwidth,ca.bheight),(cb.id,cb.hsamp,cb.vsamp,cb.bwidth,cb.bheight),"{}",what);
+        assert_eq!(ca.qtable,cb.qtable,"{}",what);
+        let bwidth = (a.width * ca.hsamp).div_ceil(8 * hmax);
+        let bheight = (a.height * ca.vsamp).div_ceil(8 * vmax);
+        for y in 0..bheight {
+            for x in 0..bwidth {
+                let i = y * ca.bwidth + x;
+                assert!(ca.blocks[i] == cb.blocks[i],"{}: component {} block ({},{}) differs",what,ca.id,x,y);
+            }
+        }
+    }
+}
+
+// chain of transforms on the coefficients of src
+fn transformed(src: &[u8],transforms: &[jpeg::Transform]) -> jpeg::JpegCoefficients {
+    let mut result = src.to_vec();
+    for transform in transforms.iter() {
+        result = jpeg::transform(&result,&jpeg::TransformOptions { transform: *transform,..Default::default() }).unwrap();
+    }
+    jpeg::read_coefficients(&result).unwrap()
+}
+
+// whole MCU images, so no transform trims an edge and every transform can be undone exactly; the 4:2:2 copy checks that
+// transposing swaps the sampling factors
+#[test]
+fn transform_exact() {
+    use jpeg::Transform::*;
+    let money = jpeg::transform(&static_file("money.jpg"),&jpeg::TransformOptions { crop: Some((0,0,448,336)),..Default::default() }).unwrap();
+    let options = jpeg::EncodeOptions { subsampling: jpeg::Subsampling::S422,..Default::default() };
+    let money422 = jpeg::encode_with_options(&jpeg::decode(&money).unwrap(),&options).unwrap();
+    for src in [money,money422].iter() {
+        let original = jpeg::read_coefficients(src).unwrap();
+        assert_same_coefficients(&transformed(src,&[None]),&original,"None");
+        assert_same_coefficients(&transformed(src,&[Rotate90,Rotate90,Rotate90,Rotate90]),&original,"4 x Rotate90");
+        assert_same_coefficients(&transformed(src,&[Rotate180,Rotate180]),&original,"2 x Rotate180");
+        assert_same_coefficients(&transformed(src,&[Rotate90,Rotate270]),&original,"Rotate90, Rotate270");
+        for transform in [FlipHorizontal,FlipVertical,Transpose,Transverse].iter() {
+            assert_same_coefficients(&transformed(src,&[*transform,*transform]),&original,&format!("2 x {:?}",transform));
+        }
+        assert_same_coefficients(&transformed(src,&[Rotate180]),&transformed(src,&[FlipHorizontal,FlipVertical]),"Rotate180");
+        assert_same_coefficients(&transformed(src,&[Rotate90]),&transformed(src,&[Transpose,FlipHorizontal]),"Rotate90");
+        assert_same_coefficients(&transformed(src,&[Rotate270]),&transformed(src,&[Transpose,FlipVertical]),"Rotate270");
+        assert_same_coefficients(&transformed(src,&[Transverse]),&transformed(src,&[Transpose,Rotate180]),"Transverse");
+    }
+}
+
+// the part of the image at (x0,y0) of width x height
+fn part(image: &Image,x0: usize,y0: usize,width: usize,height: usize) -> Image {
+    let mut result = Image::new(width,height);
+    for y in 0..height {
+        for x in 0..width {
+            result.data[y * width + x] = image.data[(y0 + y) * image.width + x0 + x];
+        }
+    }
+    result
+}
+
+// a transform gives the pixels of the matching EXIF orientation, with the partial MCUs at mirrored edges trimmed and up to 2
+// off from IDCT rounding of transposed blocks; a crop on MCU boundaries gives exactly the cropped pixels
+#[test]
+fn transform_pixels() {
+    use jpeg::Transform::*;
+    let options = jpeg::DecodeOptions { idct: jpeg::Idct::Float,upsampling: jpeg::Upsampling::Nearest,..Default::default() };
+    for name in ["money.jpg","forsaken.jpg","dither.jpg","earth2.jpg","money-arithmetic-progressive.jpg"].iter() {
+        let src = static_file(name);
+        let reference = jpeg::decode_with_options(&src,&options).unwrap();
+        for (transform,orientation) in [(FlipHorizontal,2),(Rotate180,3),(FlipVertical,4),(Transpose,5),(Rotate90,6),(Transverse,7),(Rotate270,8)].iter() {
+            let transformed = jpeg::transform(&src,&jpeg::TransformOptions { transform: *transform,..Default::default() }).unwrap();
+            let image = jpeg::decode_with_options(&transformed,&options).unwrap();
+            let (width,height) = if *orientation >= 5 { (image.height,image.width) } else { (image.width,image.height) };
+            assert!((width <= reference.width) && (reference.width - width < 16),"{} {:?}",name,transform);
+            assert!((height <= reference.height) && (reference.height - height < 16),"{} {:?}",name,transform);
+            let (max,_) = error(&image,&part(&reference,0,0,width,height).oriented(*orientation));
+            assert!(max <= 2,"{} {:?}: max error {}",name,transform,max);
+        }
+        let crop = (16,16,reference.width / 2,reference.height / 2);
+        let cropped = jpeg::transform(&src,&jpeg::TransformOptions { crop: Some(crop),progressive: true,..Default::default() }).unwrap();
+        let image = jpeg::decode_with_options(&cropped,&options).unwrap();
+        assert!(image.data == part(&reference,crop.0,crop.1,crop.2,crop.3).data,"{} crop",name);
+    }
+}
+
+// read_coefficients and write_coefficients keep the coefficients, the markers and so the pixels, for baseline, progressive
+// and arithmetic coded input and for every kind of output
+#[test]
+fn coefficients_round_trip() {
+    let mut covered = Vec::new();
+    for name in static_files().iter() {
+        let src = static_file(name);
+        let original = match jpeg::read_coefficients(&src) {
+            Ok(coefficients) => coefficients,
+            Err(_) => { continue; },  // CMYK, 12-bit and lossless files have no 8-bit coefficients
+        };
+        let pixels = jpeg::decode(&src).unwrap();
+        for (optimize_huffman,scans) in [(false,Vec::new()),(true,Vec::new()),(false,jpeg::progressive_scans())].iter() {
+            let options = jpeg::EncodeOptions { optimize_huffman: *optimize_huffman,scans: scans.clone(),..Default::default() };
+            let written = jpeg::write_coefficients(&original,&options).unwrap();
+            let what = format!("{}{}{}",name,if *optimize_huffman { " optimized" } else { "" },if scans.is_empty() { "" } else { " progressive" });
+            let coefficients = jpeg::read_coefficients(&written).unwrap();
+            assert_same_coefficients(&coefficients,&original,&what);
+            assert!(coefficients.markers == original.markers,"{}: markers differ",what);
+            assert!(jpeg::decode(&written).unwrap().data == pixels.data,"{}: pixels differ",what);
+        }
+        covered.push(name.clone());
+    }
+    for name in ["money.jpg","appligent.jpg","earth2.jpg","money-arithmetic.jpg","money-arithmetic-progressive.jpg"].iter() {
+        assert!(covered.iter().any(|c| c == name),"{} not covered",name);
+    }
+}
+
+// the resolution goes into the JFIF header and turns with transposing transforms
+#[test]
+fn dpi() {
+    let image = jpeg::decode(&static_file("money-huffman.jpg")).unwrap();
+    assert_eq!(jpeg::dpi(&jpeg::encode(&image).unwrap()),None);
+    let options = jpeg::EncodeOptions { dpi: Some((300.0,150.0)),..Default::default() };
+    let src = jpeg::encode_with_options(&image,&options).unwrap();
+    assert_eq!(jpeg::dpi(&src),Some((300.0,150.0)));
+    for (transform,dpi) in [(jpeg::Transform::FlipHorizontal,(300.0,150.0)),(jpeg::Transform::Rotate90,(150.0,300.0))].iter() {
+        let result = jpeg::transform(&src,&jpeg::TransformOptions { transform: *transform,..Default::default() }).unwrap();
+        assert_eq!(jpeg::dpi(&result),Some(*dpi),"{:?}",transform);
+    }
+}
+
+// inserts an EXIF block right after SOI whose IFD0 holds only the orientation tag
+fn with_orientation(src: &[u8],orientation: u16,big_endian: bool) -> Vec<u8> {
+    let mut tiff: Vec<u8> = if big_endian {
+        let mut tiff = vec![0x4D,0x4D,0x00,0x2A,0x00,0x00,0x00,0x08,0x00,0x01];  // big endian, IFD0 at 8, 1 entry
+        tiff.extend_from_slice(&[0x01,0x12,0x00,0x03,0x00,0x00,0x00,0x01]);  // orientation, SHORT, 1 value
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff
+    }
+    else {
+        let mut tiff = vec![0x49,0x49,0x2A,0x00,0x08,0x00,0x00,0x00,0x01,0x00];  // little endian, IFD0 at 8, 1 entry
+        tiff.extend_from_slice(&[0x12,0x01,0x03,0x00,0x01,0x00,0x00,0x00]);  // orientation, SHORT, 1 value
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff
+    };
+    tiff.extend_from_slice(&[0x00,0x00,0x00,0x00,0x00,0x00]);  // value padding, no IFD1
+    let mut result = vec![0xFF,0xD8,0xFF,0xE1];
+    result.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    result.extend_from_slice(b"Exif\0\0");
+    result.extend_from_slice(&tiff);
+    result.extend_from_slice(&src[2..]);
+    result
+}
+
+#[test]
+fn orientation_tag() {
+    let src = static_file("money.jpg");
+    assert_eq!(jpeg::orientation(&src),None);
+    for orientation in 1..=8 {
+        for big_endian in [false,true].iter() {
+            assert_eq!(jpeg::orientation(&with_orientation(&src,orientation,*big_endian)),Some(orientation));
+        }
+    }
+}
+
+// 3x2 image with pixels 0..6, oriented for each tag value
+const ORIENTED: [(u16,usize,usize,[u8; 6]); 10] = [
+    (0,3,2,[0,1,2,3,4,5]),  // not a valid value, left alone
+    (1,3,2,[0,1,2,3,4,5]),
+    (2,3,2,[2,1,0,5,4,3]),  // mirrored horizontally
+    (3,3,2,[5,4,3,2,1,0]),  // rotated 180
+    (4,3,2,[3,4,5,0,1,2]),  // mirrored vertically
+    (5,2,3,[0,3,1,4,2,5]),  // transposed
+    (6,2,3,[3,0,4,1,5,2]),  // rotated 90 clockwise
+    (7,2,3,[5,2,4,1,3,0]),  // transversed
+    (8,2,3,[2,5,1,4,0,3]),  // rotated 90 counterclockwise
+    (9,3,2,[0,1,2,3,4,5]),  // not a valid value, left alone
+];
+
+#[test]
+fn oriented() {
+    let image = Image { width: 3,height: 2,data: (0..6).collect(), };
+    let image16 = Image16 { width: 3,height: 2,data: (0..6).map(|i| i << 48).collect(), };
+    for (orientation,width,height,data) in ORIENTED.iter() {
+        let result = image.oriented(*orientation);
+        assert_eq!((result.width,result.height),(*width,*height),"orientation {}",orientation);
+        assert_eq!(result.data,data.iter().map(|i| *i as u32).collect::<Vec<u32>>(),"orientation {}",orientation);
+        let result = image16.oriented(*orientation);
+        assert_eq!((result.width,result.height),(*width,*height),"orientation {}",orientation);
+        assert_eq!(result.data,data.iter().map(|i| (*i as u64) << 48).collect::<Vec<u64>>(),"orientation {}",orientation);
+    }
+}
+
+// every return path of the decoder turns its result; without apply_orientation the tag is ignored
+#[test]
+fn apply_orientation() {
+    for (name,scale) in [("money.jpg",jpeg::Scale::Full),("money.jpg",jpeg::Scale::Quarter),("money-12bit.jpg",jpeg::Scale::Full),("money-lossless.jpg",jpeg::Scale::Full)].iter() {
+        let src = static_file(name);
+        let options = jpeg::DecodeOptions { scale: *scale,..Default::default() };
+        let image = jpeg::decode_with_options(&src,&options).unwrap();
+        let image16 = jpeg::decode16_with_options(&src,&options).unwrap();
+        for orientation in 1..=8 {
+            let tagged = with_orientation(&src,orientation,false);
+            assert_eq!(jpeg::decode_with_options(&tagged,&options).unwrap().data,image.data,"{} {:?} {}",name,scale,orientation);
+            let oriented = jpeg::DecodeOptions { apply_orientation: true,..options };
+            let result = jpeg::decode_with_options(&tagged,&oriented).unwrap();
+            let expected = image.oriented(orientation);
+            assert_eq!((result.width,result.height),(expected.width,expected.height),"{} {:?} {}",name,scale,orientation);
+            assert!(result.data == expected.data,"{} {:?} {}",name,scale,orientation);
+            let result = jpeg::decode16_with_options(&tagged,&oriented).unwrap();
+            let expected = image16.oriented(orientation);
+            assert_eq!((result.width,result.height),(expected.width,expected.height),"{} {:?} {}",name,scale,orientation);
+            assert!(result.data == expected.data,"{} {:?} {}",name,scale,orientation);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// image_formats PNG tests
+// by Desmond Germans, 2019
+
+use image_formats::png;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for b in data.iter() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn chunk(png: &mut Vec<u8>,chunk_type: &[u8],data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream of uncompressed deflate blocks, split at the given offsets
+fn stored(data: &[u8],splits: &[usize]) -> Vec<u8> {
+    let mut zlib = vec![0x78,0x01];
+    let mut start = 0;
+    for (i,end) in splits.iter().chain([data.len()].iter()).enumerate() {
+        let length = (end - start) as u16;
+        zlib.push(if i == splits.len() { 1 } else { 0 });
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(&data[start..*end]);
+        start = *end;
+    }
+    let (mut a,mut b) = (1u32,0u32);
+    for d in data.iter() {
+        a = (a + *d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    zlib
+}
+
+// 1x1 8-bit gray PNG with an iCCP chunk
+fn with_profile(zlib: &[u8]) -> Vec<u8> {
+    let mut png = vec![0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A];
+    chunk(&mut png,b"IHDR",&[0,0,0,1,0,0,0,1,8,0,0,0,0]);
+    let mut iccp = b"test\0\0".to_vec();
+    iccp.extend_from_slice(zlib);
+    chunk(&mut png,b"iCCP",&iccp);
+    chunk(&mut png,b"IDAT",&stored(&[0,0x80],&[]));
+    chunk(&mut png,b"IEND",&[]);
+    png
+}
+
+// iCCP profiles in uncompressed deflate blocks, also with the size field split over two blocks
+#[test]
+fn stored_icc_profile() {
+    let mut profile: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+    profile[0..4].copy_from_slice(&300u32.to_be_bytes());
+    for splits in [vec![],vec![2],vec![4,100]].iter() {
+        let src = with_profile(&stored(&profile,splits));
+        assert_eq!(png::icc_profile(&src),Some(profile.clone()),"splits {:?}",splits);
+        assert_eq!(png::decode(&src).unwrap().data,vec![0xFF808080]);
+    }
+}
+
+// a stored block that holds more than the image or the profile size is corrupt; only the profile size read may stop early
+#[test]
+fn stored_overflow() {
+    let mut png = vec![0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A];
+    chunk(&mut png,b"IHDR",&[0,0,0,1,0,0,0,1,8,0,0,0,0]);
+    chunk(&mut png,b"IDAT",&stored(&[0,0x80,0x80],&[]));
+    chunk(&mut png,b"IEND",&[]);
+    assert!(png::decode(&png).is_err());
+    let mut profile: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+    profile[0..4].copy_from_slice(&299u32.to_be_bytes());
+    assert_eq!(png::icc_profile(&with_profile(&stored(&profile,&[]))),None);
+}
+
+fn pngsuite_file(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("static/png/pngsuite").join(name)).unwrap()
+}
+
+// cdun2c08 has 1000 pixels per meter, the other cd* files have no unit
+#[test]
+fn dpi() {
+    assert_eq!(png::dpi(&pngsuite_file("cdun2c08.png")),Some((25.4,25.4)));
+    for name in ["cdfn2c08.png","cdhn2c08.png","cdsn2c08.png","basn2c08.png"].iter() {
+        assert_eq!(png::dpi(&pngsuite_file(name)),None,"{}",name);
+    }
+}
+
+// PNG with filter type 0 on every row and these chunks between IHDR and IDAT
+fn image_png(width: u32,height: u32,depth: u8,color_type: u8,rows: &[&[u8]],chunks: &[(&[u8],&[u8])]) -> Vec<u8> {
+    let mut png = vec![0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A];
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[depth,color_type,0,0,0]);
+    chunk(&mut png,b"IHDR",&ihdr);
+    for (chunk_type,data) in chunks.iter() {
+        chunk(&mut png,chunk_type,data);
+    }
+    let mut raw = Vec::new();
+    for row in rows.iter() {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    chunk(&mut png,b"IDAT",&stored(&raw,&[]));
+    chunk(&mut png,b"IEND",&[]);
+    png
+}
+
+// offset of the CRC of the first chunk of this type
+fn crc_offset(png: &[u8],chunk_type: &[u8]) -> usize {
+    let mut p = 8;
+    loop {
+        let length = u32::from_be_bytes([png[p],png[p + 1],png[p + 2],png[p + 3]]) as usize;
+        if &png[p + 4..p + 8] == chunk_type {
+            return p + 8 + length;
+        }
+        p += 12 + length;
+    }
+}
+
+// a bad CRC rejects the image in a critical chunk, and skips an ancillary one
+#[test]
+fn chunk_crc() {
+    let src = image_png(2,1,8,0,&[&[0x10,0x20]],&[(b"tEXt",b"Comment\0hello")]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0xFF101010,0xFF202020]);
+    for (chunk_type,critical) in [(&b"IHDR"[..],true),(&b"IDAT"[..],true),(&b"IEND"[..],true),(&b"tEXt"[..],false)].iter() {
+        let mut bad = src.clone();
+        bad[crc_offset(&src,chunk_type)] ^= 0x01;
+        assert_eq!(png::decode(&bad).is_err(),*critical,"{}",String::from_utf8_lossy(chunk_type));
+    }
+}
+
+// tRNS: one transparent gray level or color, or alpha for the first palette entries
+#[test]
+fn trns() {
+    let src = image_png(3,1,8,0,&[&[0x80,0x40,0x80]],&[(b"tRNS",&[0x00,0x80])]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0x00808080,0xFF404040,0x00808080]);
+    let src = image_png(2,1,16,0,&[&[0x12,0x34,0x12,0x35]],&[(b"tRNS",&[0x12,0x34])]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0x00121212,0xFF121212]);
+    let src = image_png(2,1,8,2,&[&[1,2,3,1,2,4]],&[(b"tRNS",&[0,1,0,2,0,3])]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0x00010203,0xFF010204]);
+    // entries past the end of tRNS are opaque
+    let src = image_png(3,1,8,3,&[&[0,1,2]],&[(b"PLTE",&[255,0,0,0,255,0,0,0,255]),(b"tRNS",&[0x00,0x80])]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0x00FF0000,0x8000FF00,0xFF0000FF]);
+}
+
+// 16-bit gray with alpha has 4 bytes per pixel, 16-bit RGBA 8; samples are rounded to 8 bits
+#[test]
+fn sixteen_bit_rows() {
+    let src = image_png(3,2,16,4,&[&[0x00,0x00,0xFF,0xFF,0x80,0x80,0x7F,0x7F,0xFF,0xFF,0x00,0x00],&[0x12,0xF0,0x34,0x7F,0x56,0x78,0x9A,0xBC,0xFE,0xFF,0x01,0x00]],&[]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0xFF000000,0x7F808080,0x00FFFFFF,0x34131313,0x9A565656,0x01FEFEFE]);
+    let src = image_png(2,2,16,6,&[&[0,0,0x80,0x80,0xFF,0xFF,0x40,0x40,0x12,0x80,0x34,0x7F,0x56,0x78,0x9A,0xBC],&[0xFF,0xFF,0xFF,0xFF,0xFF,0xFF,0xFF,0xFF,0,0,0,0,0,0,0,0]],&[]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0x400080FF,0x9A123456,0xFFFFFFFF,0x00000000]);
+}
+
+// 1-bit gray rows stop at the image width
+#[test]
+fn one_bit_rows() {
+    let src = image_png(3,2,1,0,&[&[0b1010_1111],&[0b0100_0000]],&[]);
+    assert_eq!(png::decode(&src).unwrap().data,vec![0xFFFFFFFF,0xFF000000,0xFFFFFFFF,0xFF000000,0xFFFFFFFF,0xFF000000]);
+}